@@ -0,0 +1,244 @@
+use crate::file_system::{FileType, Metadata};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntryType {
+    File,
+    Dir,
+    Symlink,
+}
+
+impl EntryType {
+    pub fn of(file_type: &FileType) -> Option<Self> {
+        match file_type {
+            FileType::Symlink => Some(EntryType::Symlink),
+            FileType::Dir => Some(EntryType::Dir),
+            FileType::File => Some(EntryType::File),
+            FileType::Other => None,
+        }
+    }
+}
+
+impl FromStr for EntryType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "f" => Ok(EntryType::File),
+            "d" => Ok(EntryType::Dir),
+            "l" => Ok(EntryType::Symlink),
+            _ => Err(format!("unknown type '{}', expected one of f, d, l", s)),
+        }
+    }
+}
+
+/// Filters on entry metadata. They select which files and symlinks are copied; directories are
+/// always walked and created since they may hold matching entries.
+#[derive(Clone, Default)]
+pub struct AttrFilter {
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    newer_than: Option<SystemTime>,
+    older_than: Option<SystemTime>,
+    types: Vec<EntryType>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+}
+
+impl AttrFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_min_size(mut self, bytes: u64) -> Self {
+        self.min_size = Some(bytes);
+        self
+    }
+
+    pub fn set_max_size(mut self, bytes: u64) -> Self {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    /// Keep entries modified less than `age` ago.
+    pub fn set_newer_than(mut self, age: Duration) -> Self {
+        self.newer_than = Some(ago(age));
+        self
+    }
+
+    /// Keep entries modified more than `age` ago.
+    pub fn set_older_than(mut self, age: Duration) -> Self {
+        self.older_than = Some(ago(age));
+        self
+    }
+
+    pub fn add_type(mut self, entry_type: EntryType) -> Self {
+        self.types.push(entry_type);
+        self
+    }
+
+    pub fn set_uid(mut self, uid: u32) -> Self {
+        self.uid = Some(uid);
+        self
+    }
+
+    pub fn set_gid(mut self, gid: u32) -> Self {
+        self.gid = Some(gid);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.types.is_empty() && !self.needs_metadata()
+    }
+
+    /// Only size, time and owner filters need metadata beyond what the directory listing gives.
+    pub fn needs_metadata(&self) -> bool {
+        self.min_size.is_some()
+            || self.max_size.is_some()
+            || self.newer_than.is_some()
+            || self.older_than.is_some()
+            || self.uid.is_some()
+            || self.gid.is_some()
+    }
+
+    /// `metadata` is the entry's own (not followed) metadata, and must be given whenever
+    /// `needs_metadata` is true.
+    pub fn is_excluded(&self, file_type: &FileType, metadata: Option<&Metadata>) -> bool {
+        let entry_type = match EntryType::of(file_type) {
+            Some(t) => t,
+            None => return false,
+        };
+        if entry_type == EntryType::Dir {
+            return false;
+        }
+        if !self.types.is_empty() && !self.types.contains(&entry_type) {
+            return true;
+        }
+        let metadata = match metadata {
+            Some(m) => m,
+            None => return false,
+        };
+
+        let len = metadata.len;
+        if self.min_size.is_some_and(|min| len < min) || self.max_size.is_some_and(|max| len > max)
+        {
+            return true;
+        }
+        if self.newer_than.is_some() || self.older_than.is_some() {
+            let modified = match metadata.modified {
+                Some(m) => m,
+                None => return true,
+            };
+            if self.newer_than.is_some_and(|t| modified < t)
+                || self.older_than.is_some_and(|t| modified > t)
+            {
+                return true;
+            }
+        }
+        self.is_owner_excluded(metadata)
+    }
+
+    fn is_owner_excluded(&self, metadata: &Metadata) -> bool {
+        self.uid.is_some_and(|uid| metadata.uid != uid)
+            || self.gid.is_some_and(|gid| metadata.gid != gid)
+    }
+}
+
+/// `1024`, `10K`, `5M`, `2G`, `1T`; units are powers of 1024.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (number, unit) = split_unit(s);
+    let multiplier: u64 = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        "T" | "TB" => 1 << 40,
+        _ => return Err(format!("unknown size unit in '{}'", s)),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid size '{}'", s))?;
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size '{}' is too large", s))
+}
+
+/// `45s`, `15m`, `12h`, `30d`, `2w`; a bare number counts days.
+pub fn parse_age(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let (number, unit) = split_unit(s);
+    let seconds: u64 = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "" | "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(format!("unknown time unit in '{}'", s)),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid age '{}'", s))?;
+    let too_large = || format!("age '{}' is too large", s);
+    let age = Duration::from_secs(number.checked_mul(seconds).ok_or_else(too_large)?);
+    SystemTime::now().checked_sub(age).ok_or_else(too_large)?;
+    Ok(age)
+}
+
+// The time `age` before now. Ages reaching back further than times go, which `parse_age`
+// rejects, count from the epoch.
+fn ago(age: Duration) -> SystemTime {
+    SystemTime::now()
+        .checked_sub(age)
+        .unwrap_or(SystemTime::UNIX_EPOCH)
+}
+
+fn split_unit(s: &str) -> (&str, &str) {
+    let i = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    s.split_at(i)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn parse_test() {
+        assert_eq!(parse_size("100").unwrap(), 100);
+        assert_eq!(parse_size("10K").unwrap(), 10 * 1024);
+        assert_eq!(parse_size("2g").unwrap(), 2 << 30);
+        assert!(parse_size("2X").is_err());
+        assert_eq!(parse_age("30d").unwrap(), Duration::from_secs(30 * 86400));
+        assert_eq!(parse_age("12h").unwrap(), Duration::from_secs(12 * 3600));
+        assert!(parse_age("h").is_err());
+        assert!(parse_age("18446744073709551615w").is_err());
+        assert!(parse_age("18446744073709551615s").is_err());
+    }
+
+    #[test]
+    fn attr_filter_test() {
+        let path = "./test_dir/copy_test_dir/origin_file";
+        let metadata = Metadata::from(fs::symlink_metadata(path).unwrap());
+        let file_type = metadata.file_type;
+        let len = metadata.len;
+
+        let f = AttrFilter::new().set_min_size(len + 1);
+        assert!(f.needs_metadata());
+        assert!(f.is_excluded(&file_type, Some(&metadata)));
+        let f = AttrFilter::new().set_min_size(len).set_max_size(len);
+        assert!(!f.is_excluded(&file_type, Some(&metadata)));
+
+        let f = AttrFilter::new().set_older_than(Duration::from_secs(3600 * 24 * 365 * 100));
+        assert!(f.is_excluded(&file_type, Some(&metadata)));
+        let f = AttrFilter::new().set_newer_than(Duration::MAX);
+        assert!(!f.is_excluded(&file_type, Some(&metadata)));
+
+        let f = AttrFilter::new().add_type(EntryType::Symlink);
+        assert!(!f.needs_metadata());
+        assert!(f.is_excluded(&file_type, None));
+        let dir_type = fs::symlink_metadata("./test_dir").unwrap().file_type().into();
+        assert!(!f.is_excluded(&dir_type, None));
+    }
+}
@@ -0,0 +1,226 @@
+use crate::date::civil_from_days;
+use crate::file_system::FileSystem;
+use std::ffi::OsString;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How backups of overwritten files are named, after GNU cp's `--backup`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackupMode {
+    /// `name~`, replacing an older backup.
+    Simple,
+    /// `name.~1~`, `name.~2~`, ... keeping every version.
+    Numbered,
+    /// Numbered if numbered backups of the file exist already, simple otherwise.
+    Existing,
+    /// `name.~20240131-235959~`, the start time of the run in UTC.
+    Timestamp,
+}
+
+impl FromStr for BackupMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "simple" | "never" => Ok(BackupMode::Simple),
+            "numbered" | "t" => Ok(BackupMode::Numbered),
+            "existing" | "nil" => Ok(BackupMode::Existing),
+            "timestamp" => Ok(BackupMode::Timestamp),
+            _ => Err(format!(
+                "unknown backup mode '{}', expected one of simple, numbered, existing, timestamp",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for BackupMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            BackupMode::Simple => "simple",
+            BackupMode::Numbered => "numbered",
+            BackupMode::Existing => "existing",
+            BackupMode::Timestamp => "timestamp",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// Where the file about to be overwritten is kept. Without a mode, backups in `dir` keep their
+// plain name, so a later run replaces them like rsync's `--backup-dir` does.
+pub struct BackupPolicy {
+    mode: Option<BackupMode>,
+    dir: Option<PathBuf>,
+    stamp: String,
+}
+
+impl BackupPolicy {
+    pub fn new(mode: Option<BackupMode>, dir: Option<PathBuf>) -> Self {
+        Self {
+            mode,
+            dir,
+            stamp: utc_stamp(SystemTime::now()),
+        }
+    }
+
+    // Keep the current `to` aside and return where. `relative` is the path of `to` below the
+    // destination, which the tree in the backup directory mirrors.
+    pub fn back_up(
+        &self,
+        fs: &dyn FileSystem,
+        to: &Path,
+        relative: &Path,
+    ) -> Result<PathBuf, io::Error> {
+        let base = match &self.dir {
+            Some(dir) => {
+                let base = dir.join(relative);
+                if let Some(parent) = base.parent() {
+                    fs.create_dir_all(parent)?;
+                }
+                base
+            }
+            None => to.to_path_buf(),
+        };
+        let mode = match self.mode {
+            Some(BackupMode::Existing) if last_number(fs, &base)? > 0 => Some(BackupMode::Numbered),
+            Some(BackupMode::Existing) => Some(BackupMode::Simple),
+            mode => mode,
+        };
+        let backup = match mode {
+            None => base,
+            Some(BackupMode::Simple) => with_suffix(&base, "~"),
+            Some(BackupMode::Timestamp) => with_suffix(&base, &format!(".~{}~", self.stamp)),
+            Some(BackupMode::Numbered) | Some(BackupMode::Existing) => {
+                let mut n = last_number(fs, &base)? + 1;
+                loop {
+                    let backup = with_suffix(&base, &format!(".~{}~", n));
+                    match preserve(fs, to, &backup, false) {
+                        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => n += 1,
+                        r => return r.map(|_| backup),
+                    }
+                }
+            }
+        };
+        preserve(fs, to, &backup, true)?;
+        Ok(backup)
+    }
+}
+
+// A hard link leaves `to` in place until the new copy is renamed over it, so the file is never
+// missing. Where links aren't possible it is moved, or copied across filesystems.
+fn preserve(
+    fs: &dyn FileSystem,
+    to: &Path,
+    backup: &Path,
+    replace: bool,
+) -> Result<(), io::Error> {
+    if replace {
+        match fs.remove_file(backup) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    match fs.hard_link(to, backup) {
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Err(e),
+        Err(_) if !replace && fs.symlink_metadata(backup).is_ok() => {
+            Err(io::Error::from(io::ErrorKind::AlreadyExists))
+        }
+        Err(_) => fs.rename(to, backup).or_else(|_| {
+            io::copy(&mut fs.open(to)?, &mut fs.create(backup)?).map(|_| ())
+        }),
+        Ok(()) => Ok(()),
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.file_name().unwrap_or_default());
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+// Highest N of the `name.~N~` backups next to `base`, 0 if there are none.
+fn last_number(fs: &dyn FileSystem, base: &Path) -> Result<u64, io::Error> {
+    let (dir, name) = match (base.parent(), base.file_name()) {
+        (Some(dir), Some(name)) => (dir, name.to_string_lossy()),
+        _ => return Ok(0),
+    };
+    let prefix = format!("{}.~", name);
+    let entries = match fs.read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let mut last = 0;
+    for entry in entries {
+        let entry_name = entry?.name;
+        let n = entry_name
+            .to_str()
+            .and_then(|s| s.strip_prefix(&prefix))
+            .and_then(|s| s.strip_suffix('~'))
+            .and_then(|s| s.parse::<u64>().ok());
+        if let Some(n) = n {
+            last = last.max(n);
+        }
+    }
+    Ok(last)
+}
+
+// `YYYYMMDD-HHMMSS` in UTC.
+fn utc_stamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, rest) = (secs / 86400, secs % 86400);
+    let (year, month, day) = civil_from_days(days as i64);
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        rest / 3600,
+        rest % 3600 / 60,
+        rest % 60
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::file_system::LocalFs;
+    use std::fs;
+    use std::time::Duration;
+
+    #[test]
+    fn utc_stamp_test() {
+        assert_eq!(utc_stamp(UNIX_EPOCH), "19700101-000000");
+        let t = UNIX_EPOCH + Duration::from_secs(1_709_251_199);
+        assert_eq!(utc_stamp(t), "20240229-235959");
+    }
+
+    #[test]
+    fn numbered_backup_test() {
+        let dir = Path::new("./test_dir/backup_test");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        let file = dir.join("config");
+        fs::write(&file, "v1").unwrap();
+        let config = Path::new("config");
+
+        let policy = BackupPolicy::new(Some(BackupMode::Existing), None);
+        assert_eq!(policy.back_up(&LocalFs, &file, config).unwrap(), dir.join("config~"));
+
+        let policy = BackupPolicy::new(Some(BackupMode::Numbered), None);
+        assert_eq!(policy.back_up(&LocalFs, &file, config).unwrap(), dir.join("config.~1~"));
+        assert_eq!(policy.back_up(&LocalFs, &file, config).unwrap(), dir.join("config.~2~"));
+        // Numbered backups exist now, so `existing` keeps numbering.
+        let policy = BackupPolicy::new(Some(BackupMode::Existing), None);
+        assert_eq!(policy.back_up(&LocalFs, &file, config).unwrap(), dir.join("config.~3~"));
+        assert_eq!(fs::read_to_string(dir.join("config.~3~")).unwrap(), "v1");
+
+        let policy = BackupPolicy::new(None, Some(dir.join("old")));
+        let backup = policy.back_up(&LocalFs, &file, Path::new("etc/config")).unwrap();
+        assert_eq!(backup, dir.join("old/etc/config"));
+        assert!(file.exists());
+    }
+}
@@ -0,0 +1,138 @@
+use lz4_flex::frame::{FrameDecoder, FrameEncoder, FrameInfo};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// How files are compressed as they are copied. Each file is written in the tool's own format,
+/// `zstd -d` or `lz4 -d` restores it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// Zstandard, written as `name.zst`.
+    Zstd,
+    /// LZ4, faster but bigger, written as `name.lz4`.
+    Lz4,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zstd" => Ok(Compression::Zstd),
+            "lz4" => Ok(Compression::Lz4),
+            _ => Err(format!(
+                "unknown compression '{}', expected one of zstd, lz4",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Compression::Zstd => "zstd",
+            Compression::Lz4 => "lz4",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl Compression {
+    /// The extension of compressed files, without the dot.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::Zstd => "zst",
+            Compression::Lz4 => "lz4",
+        }
+    }
+
+    // The compression a file's extension names.
+    pub(crate) fn of(path: &Path) -> Option<Self> {
+        [Compression::Zstd, Compression::Lz4]
+            .into_iter()
+            .find(|c| path.extension().is_some_and(|e| e == c.extension()))
+    }
+
+    // `path` with the extension added.
+    pub(crate) fn compressed_path(&self, path: &Path) -> PathBuf {
+        let mut name = path.as_os_str().to_owned();
+        name.push(".");
+        name.push(self.extension());
+        PathBuf::from(name)
+    }
+
+    pub(crate) fn encoder<W: Write>(&self, out: W) -> io::Result<Encoder<W>> {
+        Ok(match self {
+            Compression::Zstd => Encoder::Zstd(zstd::Encoder::new(out, 0)?),
+            Compression::Lz4 => {
+                let info = FrameInfo::new().content_checksum(true);
+                Encoder::Lz4(FrameEncoder::with_frame_info(info, out))
+            }
+        })
+    }
+
+    pub(crate) fn decoder<'a, R: Read + 'a>(&self, data: R) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Compression::Zstd => Box::new(zstd::Decoder::new(data)?),
+            Compression::Lz4 => Box::new(FrameDecoder::new(data)),
+        })
+    }
+}
+
+pub(crate) enum Encoder<W: Write> {
+    Zstd(zstd::Encoder<'static, W>),
+    Lz4(FrameEncoder<W>),
+}
+
+impl<W: Write> Encoder<W> {
+    // Write what is still buffered and the end of the frame, and return the output.
+    pub(crate) fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Zstd(encoder) => encoder.finish(),
+            Encoder::Lz4(encoder) => Ok(encoder.finish()?),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Zstd(encoder) => encoder.write(buf),
+            Encoder::Lz4(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Zstd(encoder) => encoder.flush(),
+            Encoder::Lz4(encoder) => encoder.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn compression_test() {
+        let data = b"compressed, compressed, compressed".repeat(1000);
+        for compression in [Compression::Zstd, Compression::Lz4] {
+            let mut encoder = compression.encoder(vec![]).unwrap();
+            encoder.write_all(&data).unwrap();
+            let packed = encoder.finish().unwrap();
+            assert!(packed.len() < data.len() / 10);
+            let mut unpacked = vec![];
+            compression.decoder(&packed[..]).unwrap().read_to_end(&mut unpacked).unwrap();
+            assert_eq!(unpacked, data);
+
+            let path = compression.compressed_path(Path::new("dir/a.txt"));
+            assert_eq!(Compression::of(&path), Some(compression));
+            assert_eq!(path.with_extension(""), Path::new("dir/a.txt"));
+        }
+        assert_eq!(Compression::of(Path::new("a.txt")), None);
+        assert_eq!("lz4".parse(), Ok(Compression::Lz4));
+    }
+}
@@ -0,0 +1,221 @@
+use std::ffi::OsString;
+use std::fmt;
+use crate::file_system::Metadata;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// What to do with a file whose destination already exists.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Replace the destination.
+    Overwrite,
+    /// Keep the destination, don't copy.
+    Skip,
+    /// Replace the destination only if the source was modified later.
+    Newer,
+    /// Keep both, the copy gets a free `name (n).ext`.
+    Rename,
+    /// Stop the whole copy.
+    Fail,
+    /// Ask on the terminal for each conflict.
+    Ask,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "overwrite" => Ok(ConflictPolicy::Overwrite),
+            "skip" => Ok(ConflictPolicy::Skip),
+            "newer" => Ok(ConflictPolicy::Newer),
+            "rename" => Ok(ConflictPolicy::Rename),
+            "fail" => Ok(ConflictPolicy::Fail),
+            "ask" => Ok(ConflictPolicy::Ask),
+            _ => Err(format!(
+                "unknown conflict policy '{}', expected one of overwrite, skip, newer, rename, \
+                 fail, ask",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ConflictPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ConflictPolicy::Overwrite => "overwrite",
+            ConflictPolicy::Skip => "skip",
+            ConflictPolicy::Newer => "newer",
+            ConflictPolicy::Rename => "rename",
+            ConflictPolicy::Fail => "fail",
+            ConflictPolicy::Ask => "ask",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// The outcome for one conflicting file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Decision {
+    Overwrite,
+    Skip,
+    Rename,
+    Fail,
+}
+
+impl fmt::Display for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Decision::Overwrite => "overwrite",
+            Decision::Skip => "skip",
+            Decision::Rename => "rename",
+            Decision::Fail => "fail",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// Turns the policy into a decision per file. Shared by all workers: with `ask` only one prompt
+// is shown at a time, and an answer for all remaining conflicts applies to every worker.
+pub struct ConflictResolver {
+    policy: ConflictPolicy,
+    answer_for_all: Mutex<Option<Decision>>,
+}
+
+impl ConflictResolver {
+    pub fn new(policy: ConflictPolicy) -> Self {
+        Self {
+            policy,
+            answer_for_all: Mutex::new(None),
+        }
+    }
+
+    // Whether decisions are asked for on the terminal.
+    pub fn asks(&self) -> bool {
+        self.policy == ConflictPolicy::Ask
+    }
+
+    // `to` exists, decide whether the source is copied over it. `source_is_newer` is only
+    // called for `Newer`, so the others don't stat the source.
+    pub fn decide<F>(&self, to: &Path, source_is_newer: F) -> Decision
+    where
+        F: FnOnce() -> bool,
+    {
+        match self.policy {
+            ConflictPolicy::Overwrite => Decision::Overwrite,
+            ConflictPolicy::Skip => Decision::Skip,
+            ConflictPolicy::Rename => Decision::Rename,
+            ConflictPolicy::Fail => Decision::Fail,
+            ConflictPolicy::Newer => {
+                if source_is_newer() {
+                    Decision::Overwrite
+                } else {
+                    Decision::Skip
+                }
+            }
+            ConflictPolicy::Ask => {
+                let mut answer_for_all = self.answer_for_all.lock().unwrap();
+                if let Some(decision) = *answer_for_all {
+                    return decision;
+                }
+                match ask(to) {
+                    Ok((decision, for_all)) => {
+                        if for_all {
+                            *answer_for_all = Some(decision);
+                        }
+                        decision
+                    }
+                    // Without a terminal nothing gets replaced.
+                    Err(e) => {
+                        log::warn!("can't ask about {:?}: {}", to, e);
+                        Decision::Skip
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Without modification times the source isn't newer.
+pub fn is_newer(from: &Metadata, to: &Metadata) -> bool {
+    match (from.modified, to.modified) {
+        (Some(from), Some(to)) => from > to,
+        _ => false,
+    }
+}
+
+// The prompt goes to the terminal itself, so it works while stdin feeds `--files-from -` and
+// stdout carries JSON events.
+fn ask(to: &Path) -> Result<(Decision, bool), io::Error> {
+    let mut tty_out = OpenOptions::new().write(true).open("/dev/tty")?;
+    let mut tty_in = BufReader::new(File::open("/dev/tty")?);
+    loop {
+        write!(
+            tty_out,
+            "{:?} exists. Overwrite? [y]es, [n]o, [r]ename, [a]ll yes, [s]kip all: ",
+            to
+        )?;
+        tty_out.flush()?;
+        let mut line = String::new();
+        if tty_in.read_line(&mut line)? == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        match line.trim() {
+            "y" => return Ok((Decision::Overwrite, false)),
+            "n" => return Ok((Decision::Skip, false)),
+            "r" => return Ok((Decision::Rename, false)),
+            "a" => return Ok((Decision::Overwrite, true)),
+            "s" => return Ok((Decision::Skip, true)),
+            _ => {}
+        }
+    }
+}
+
+// `dir/name (n).ext`; the extension is the part after the last dot, so `a.tar.gz` becomes
+// `a.tar (1).gz`, and dot files like `.bashrc` get `.bashrc (1)`.
+pub fn renamed_path(to: &Path, n: u32) -> PathBuf {
+    let name = to.file_name().unwrap_or_default().to_string_lossy();
+    let (stem, ext) = match name.rfind('.') {
+        Some(i) if i > 0 => name.split_at(i),
+        _ => (&name[..], ""),
+    };
+    let mut renamed = OsString::from(stem);
+    renamed.push(format!(" ({})", n));
+    renamed.push(ext);
+    to.with_file_name(renamed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn renamed_path_test() {
+        let dir = Path::new("a/b");
+        assert_eq!(renamed_path(&dir.join("x.txt"), 1), dir.join("x (1).txt"));
+        assert_eq!(renamed_path(&dir.join("a.tar.gz"), 2), dir.join("a.tar (2).gz"));
+        assert_eq!(renamed_path(&dir.join(".bashrc"), 1), dir.join(".bashrc (1)"));
+        assert_eq!(renamed_path(&dir.join("Makefile"), 3), dir.join("Makefile (3)"));
+    }
+
+    #[test]
+    fn decide_test() {
+        let origin = Path::new("./test_dir/copy_test_dir/origin_file");
+        let metadata = Metadata::from(std::fs::metadata(origin).unwrap());
+        let resolver = ConflictResolver::new(ConflictPolicy::Skip);
+        assert_eq!(resolver.decide(origin, || unreachable!()), Decision::Skip);
+        // Same file, not newer than itself.
+        let resolver = ConflictResolver::new(ConflictPolicy::Newer);
+        let newer = || is_newer(&metadata, &metadata);
+        assert_eq!(resolver.decide(origin, newer), Decision::Skip);
+        assert_eq!(
+            "rename".parse::<ConflictPolicy>().unwrap(),
+            ConflictPolicy::Rename
+        );
+        assert!("clobber".parse::<ConflictPolicy>().is_err());
+    }
+}
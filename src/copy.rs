@@ -1,342 +1,2441 @@
+use crate::attr_filter::AttrFilter;
+use crate::backup::{BackupMode, BackupPolicy};
+use crate::cancel::CancelToken;
+use crate::compression::Compression;
+use crate::conflict::{is_newer, renamed_path, ConflictPolicy, ConflictResolver, Decision};
 use crate::dir_tree::{DirNode, SharedNodeRef};
+use crate::encryption::{self, Cipher, EncryptionKey, Salt, SALT_FILE};
+use crate::error::Error;
+use crate::file_system::{FileSystem, FileType, LocalFs, Metadata, WriteFile};
+use crate::files_from::normalize_listed;
+use crate::filter::Filter;
+use crate::gitignore::IgnoreStack;
+use crate::observer::CopyObserver;
+use crate::output::{ConflictCounts, DryRunCounts, Event, EventWriter};
 use crate::pool::Message;
-use crate::ThreadPool;
-use std::fs::create_dir_all;
-use std::io::Write;
+use crate::progress::{format_bytes, ExcludeFn, Progress, ScanFn, ScanTotals};
+use crate::report::CopyReport;
+use crate::space::Space;
+use crate::stats::CopyStats;
+use crate::sync::SyncPolicy;
+use crate::task::{CopyTask, EventSender, EventStream};
+use crate::zip_format::ZipCompression;
+use crate::pool::ThreadPool;
+use log::{debug, error, info, trace, warn};
+use std::cell::OnceCell;
+use std::collections::BTreeSet;
+use std::ffi::{OsStr, OsString};
+use std::io::{Read, Write};
+use std::panic::{self, AssertUnwindSafe};
 use std::path::{Path, PathBuf};
 use std::sync::mpsc::Sender;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::time::{Duration, Instant};
-use std::{fs, io, thread};
+use std::{fmt, io, thread};
 
+mod extract;
+mod from_tar;
+mod from_zip;
+mod to_archive;
+mod to_tar;
+mod to_zip;
+
+use extract::ArchiveSource;
+use to_archive::ArchiveTarget;
+
+const COPY_BUFFER_SIZE: usize = 128 * 1024;
+
+// Archives written instead of copying, or extracted instead of sources.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ArchiveFormat {
+    Tar,
+    Zip,
+}
+
+impl fmt::Display for ArchiveFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArchiveFormat::Tar => write!(f, "tar"),
+            ArchiveFormat::Zip => write!(f, "zip"),
+        }
+    }
+}
+
+// A source and where it is copied to.
+#[derive(Debug, PartialEq, Eq)]
+struct ResolvedRoot {
+    from: PathBuf,
+    dest: PathBuf,
+    is_dir: bool,
+    // Only the contents of the directory are copied, as for `dir/`.
+    contents: bool,
+    // `dest` is named after the source, inside `to`.
+    own_name: bool,
+}
+
+impl ResolvedRoot {
+    // The directory that has to exist before copying starts.
+    fn dest_dir(&self) -> &Path {
+        if self.is_dir {
+            &self.dest
+        } else {
+            self.dest.parent().unwrap_or(&self.dest)
+        }
+    }
+}
+
+/// Settings of a copy, made with [`Copyer::builder`]. Only the sources and the destination are
+/// required; by default a copy runs on the calling thread and overwrites existing files.
 #[derive(Clone)]
 pub struct CopyBuilder {
-    verbose: bool,
     multi_threads: bool,
     threads_number: usize,
-    from: Option<String>,
+    sync: SyncPolicy,
+    on_conflict: ConflictPolicy,
+    backup_mode: Option<BackupMode>,
+    backup_dir: Option<String>,
+    moving: bool,
+    verify: bool,
+    dry_run: bool,
+    force: bool,
+    compress: Option<Compression>,
+    decompress: bool,
+    encrypt: Option<EncryptionKey>,
+    decrypt: Option<EncryptionKey>,
+    encrypt_names: bool,
+    cancel: CancelToken,
+    progress: bool,
+    quiet: bool,
+    events: Option<Arc<EventWriter>>,
+    observers: Vec<Arc<dyn CopyObserver>>,
+    filter: Filter,
+    attr_filter: AttrFilter,
+    respect_gitignore: bool,
+    files_from: Option<Arc<Vec<PathBuf>>>,
+    source_fs: Arc<dyn FileSystem>,
+    dest_fs: Arc<dyn FileSystem>,
+    sources: Vec<String>,
     to: Option<String>,
+    // An archive written instead of copying, or extracted instead of sources.
+    to_archive: Option<(ArchiveFormat, String)>,
+    from_archive: Option<(ArchiveFormat, String)>,
+    zip_compression: ZipCompression,
+    keep_special_bits: bool,
 }
 
 impl CopyBuilder {
+    /// Copy on a pool of `num` threads, each directory being a task of its own.
     pub fn set_threads_number(mut self, num: usize) -> Self {
         self.threads_number = num;
         self.multi_threads = true;
         self
     }
 
-    pub fn set_verbose(mut self, verbose: bool) -> Self {
-        self.verbose = verbose;
+    /// When copied data is flushed to disk.
+    pub fn set_sync_policy(mut self, sync: SyncPolicy) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    /// What to do with files that already exist in the destination.
+    pub fn set_conflict_policy(mut self, on_conflict: ConflictPolicy) -> Self {
+        self.on_conflict = on_conflict;
+        self
+    }
+
+    /// Move instead of copy: sources are removed once they are in place.
+    pub fn set_move(mut self, moving: bool) -> Self {
+        self.moving = moving;
+        self
+    }
+
+    /// Compare copied data with the source before it replaces anything.
+    pub fn set_verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Compress each copied file on its own, as `name.zst` or `name.lz4`. The work is done by
+    /// the copying threads, so it scales with them.
+    pub fn set_compress(mut self, compression: Compression) -> Self {
+        self.compress = Some(compression);
+        self
+    }
+
+    /// Restore files compressed by [`set_compress`](Self::set_compress) as they are copied:
+    /// `name.zst` and `name.lz4` are decompressed to `name`, other files are copied as they are.
+    pub fn set_decompress(mut self, decompress: bool) -> Self {
+        self.decompress = decompress;
+        self
+    }
+
+    /// Encrypt each copied file on its own with XChaCha20-Poly1305, as `name.enc`, on the copying
+    /// threads. Sizes and times are left in the clear, and names unless
+    /// [`set_encrypt_names`](Self::set_encrypt_names).
+    pub fn set_encrypt(mut self, key: EncryptionKey) -> Self {
+        self.encrypt = Some(key);
+        self
+    }
+
+    /// Restore files encrypted by [`set_encrypt`](Self::set_encrypt) with the same key:
+    /// `name.enc` is decrypted to `name`, other files are copied as they are. A file that was
+    /// changed, or encrypted with another key, is an error and isn't written.
+    pub fn set_decrypt(mut self, key: EncryptionKey) -> Self {
+        self.decrypt = Some(key);
+        self
+    }
+
+    /// Encrypt, or decrypt, the names of files and directories too, and files get no `.enc`.
+    /// Names are salted by a `.rfc-salt` file the first run leaves in the destination, so a later
+    /// run finds what an earlier one copied; decrypting looks for it above the sources.
+    pub fn set_encrypt_names(mut self, encrypt_names: bool) -> Self {
+        self.encrypt_names = encrypt_names;
+        self
+    }
+
+    /// Walk and decide everything as usual but change nothing; what would be done is printed and
+    /// counted instead.
+    pub fn set_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Start even if the destination looks too small for the copy.
+    pub fn set_force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    /// Keep files about to be overwritten, named as `mode` says.
+    pub fn set_backup_mode(mut self, mode: BackupMode) -> Self {
+        self.backup_mode = Some(mode);
+        self
+    }
+
+    /// Keep files about to be overwritten in `dir`, in a tree mirroring the destination.
+    pub fn set_backup_dir(mut self, dir: &str) -> Self {
+        self.backup_dir = Some(String::from(dir));
+        self
+    }
+
+    /// Cancelling the token stops the copy: directories not yet read are skipped and files in
+    /// flight are rolled back, then `run` returns normally.
+    pub fn set_cancel_token(mut self, cancel: CancelToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Show a live status line on stderr while copying.
+    pub fn set_progress(mut self, progress: bool) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Don't print human readable messages to stdout, e.g. when stdout carries JSON events.
+    pub fn set_quiet(mut self, quiet: bool) -> Self {
+        self.quiet = quiet;
+        self
+    }
+
+    /// Publish an [`Event`] for every entry, and at the start and end of the run.
+    pub fn set_event_writer(mut self, events: EventWriter) -> Self {
+        self.events = Some(Arc::new(events));
+        self
+    }
+
+    /// Call `observer`'s hooks as the copy goes on. Observers are called in the order they
+    /// were added.
+    pub fn add_observer(mut self, observer: Arc<dyn CopyObserver>) -> Self {
+        self.observers.push(observer);
+        self
+    }
+
+    /// Include/exclude rules checked against each entry's path relative to `from`. Excluded
+    /// directories are not read at all.
+    pub fn set_filter(mut self, filter: Filter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Copy only the files and symlinks whose size, age, type and owner match.
+    pub fn set_attr_filter(mut self, attr_filter: AttrFilter) -> Self {
+        self.attr_filter = attr_filter;
+        self
+    }
+
+    /// Skip what `.gitignore`, `.ignore` and the global git excludes ignore. A source inside a
+    /// repository also follows the repository's `.git/info/exclude` and the ignore files above it.
+    pub fn set_respect_gitignore(mut self, respect_gitignore: bool) -> Self {
+        self.respect_gitignore = respect_gitignore;
+        self
+    }
+
+    /// Copy only these paths, relative to `from`, instead of walking the whole tree.
+    pub fn set_files_from(mut self, list: Vec<PathBuf>) -> Self {
+        self.files_from = Some(Arc::new(list));
         self
     }
 
+    /// Read the sources and write the destination through `fs` instead of the local
+    /// filesystem, e.g. a [`MemoryFs`](crate::MemoryFs) in tests.
+    pub fn set_file_system(mut self, fs: Arc<dyn FileSystem>) -> Self {
+        self.source_fs = fs.clone();
+        self.dest_fs = fs;
+        self
+    }
+
+    /// Read the sources through `fs`, the destination stays where
+    /// [`set_file_system`](Self::set_file_system) put it. Moves between two filesystems copy
+    /// every file.
+    pub fn set_source_file_system(mut self, fs: Arc<dyn FileSystem>) -> Self {
+        self.source_fs = fs;
+        self
+    }
+
+    /// Replace the sources with a single `from`.
     pub fn set_from(mut self, from: &str) -> Self {
-        self.from = Some(String::from(from));
+        self.sources = vec![String::from(from)];
+        self
+    }
+
+    /// Sources are copied like `cp -r` and rsync do: a directory given with a trailing `/` has
+    /// its contents copied into the destination, otherwise the directory itself is.
+    pub fn add_from(mut self, from: &str) -> Self {
+        self.sources.push(String::from(from));
         self
     }
 
+    /// Where the sources go, see [`add_from`](Self::add_from).
     pub fn set_to(mut self, to: &str) -> Self {
         self.to = Some(String::from(to));
         self
     }
 
-    fn parse_from(from: &str) -> Result<String, &'static str> {
-        let abs_from = fs::canonicalize(Path::new(from));
-        if abs_from.is_err() {
-            return Err("Preprocess from param failed.");
-        }
-        return Ok(String::from(abs_from.unwrap().to_str().unwrap()));
+    /// Instead of copying to a destination, write the sources into a tar archive at `path`,
+    /// or to stdout for `-`. Sources are named in the archive as they would be in a
+    /// destination directory; symlinks, hard links and extended attributes are kept. Files are
+    /// read on the pool but written in the same order every time.
+    pub fn set_to_tar(mut self, path: &str) -> Self {
+        self.to_archive = Some((ArchiveFormat::Tar, String::from(path)));
+        self
     }
 
-    fn parse_to(to: &str) -> Result<String, &'static str> {
-        let mut abs_to_pathbuff = PathBuf::from(to);
-        let abs_to = fs::canonicalize(Path::new(to));
+    /// Like [`set_to_tar`](Self::set_to_tar), with a zip archive. Files are compressed on the
+    /// pool as set by [`set_zip_compression`](Self::set_zip_compression); zip has no hard links
+    /// or extended attributes, hard links are archived as copies.
+    pub fn set_to_zip(mut self, path: &str) -> Self {
+        self.to_archive = Some((ArchiveFormat::Zip, String::from(path)));
+        self
+    }
 
-        if abs_to.is_err() {
-            let mut count = 0;
-            while !abs_to_pathbuff.exists() {
-                let p = abs_to_pathbuff.pop();
-                if !p {
-                    return Err("Preprocess to param failed");
-                }
-                count += 1;
-            }
-            let abs_to_pathbuff_children = PathBuf::from(to);
-            let mut new_buff = PathBuf::new();
-            for (i, path) in abs_to_pathbuff_children.iter().rev().enumerate() {
-                new_buff.push(path);
-                if i >= count - 1 {
-                    break;
-                }
-            }
+    /// How `set_to_zip` compresses files, deflate by default.
+    pub fn set_zip_compression(mut self, compression: ZipCompression) -> Self {
+        self.zip_compression = compression;
+        self
+    }
 
-            let children = new_buff.iter().rev().collect::<PathBuf>();
-            abs_to_pathbuff = fs::canonicalize(abs_to_pathbuff).unwrap();
-            abs_to_pathbuff = abs_to_pathbuff.join(children);
-        }
+    /// Instead of copying sources, extract the tar archive at `path`, or stdin for `-`, into
+    /// the destination. Entries are read in order while files are written on the pool; entries
+    /// that would land outside the destination, or be written through a symlink, are skipped.
+    pub fn set_from_tar(mut self, path: &str) -> Self {
+        self.from_archive = Some((ArchiveFormat::Tar, String::from(path)));
+        self
+    }
 
-        if create_dir_all(abs_to_pathbuff.clone()).is_err() {
-            return Err("Create to directory failed");
-        }
+    /// Like [`set_from_tar`](Self::set_from_tar), with a zip archive at `path`. Entries are
+    /// read from the archive on the pool, in any order.
+    pub fn set_from_zip(mut self, path: &str) -> Self {
+        self.from_archive = Some((ArchiveFormat::Zip, String::from(path)));
+        self
+    }
 
-        return Ok(String::from(abs_to_pathbuff.to_str().unwrap()));
+    /// Keep the setuid, setgid and sticky bits of extracted entries. Left out by default, so an
+    /// archive from elsewhere can't plant a setuid program.
+    pub fn set_keep_special_bits(mut self, keep: bool) -> Self {
+        self.keep_special_bits = keep;
+        self
     }
 
-    fn path_preprocess(from: &str, to: &str) -> Result<(String, String), &'static str> {
-        let from = Self::parse_from(from);
-        if let Err(e) = from {
-            return Err(e);
+    // Absolute form of a path that may not exist yet: the longest existing ancestor is
+    // canonicalized and the rest appended as given.
+    fn absolute(fs: &dyn FileSystem, path: &Path) -> Result<PathBuf, io::Error> {
+        let mut existing = if path.is_absolute() {
+            path.to_path_buf()
+        } else {
+            std::env::current_dir()?.join(path)
+        };
+        let mut missing = vec![];
+        while !fs.exists(&existing) {
+            match existing.file_name() {
+                Some(name) => missing.push(name.to_os_string()),
+                None => return Err(io::Error::from(io::ErrorKind::NotFound)),
+            }
+            existing.pop();
         }
-        let to = Self::parse_to(to);
-        if let Err(e) = to {
-            return Err(e);
+        let mut absolute = fs.canonicalize(&existing)?;
+        absolute.extend(missing.iter().rev());
+        Ok(absolute)
+    }
+
+    // Where each source goes. With several sources, or a `to` that is a directory or ends in a
+    // `/`, sources land inside `to` under their own names; a single source otherwise becomes
+    // `to` itself. Unless `follow_links`, a symlink named as a source is taken as the link, not
+    // what it points to.
+    fn resolve_roots(
+        source_fs: &dyn FileSystem,
+        dest_fs: &dyn FileSystem,
+        sources: &[String],
+        to: &str,
+        follow_links: bool,
+    ) -> Result<(PathBuf, Vec<ResolvedRoot>), &'static str> {
+        if sources.is_empty() {
+            return Err("No source to copy.");
         }
+        let abs_to =
+            Self::absolute(dest_fs, Path::new(to)).map_err(|_| "Preprocess to param failed")?;
+        let into_to =
+            sources.len() > 1 || to.ends_with(std::path::is_separator) || dest_fs.is_dir(&abs_to);
 
-        return Ok((from.unwrap(), to.unwrap()));
+        let mut roots = vec![];
+        for source in sources {
+            let (abs_from, is_dir, contents, name) =
+                Self::resolve_source(source_fs, source, follow_links)?;
+            let dest = match name {
+                Some(name) if into_to && !contents => abs_to.join(name),
+                _ => abs_to.clone(),
+            };
+            if is_dir && (dest == abs_from || dest.starts_with(&abs_from)) {
+                return Err("Cannot copy a directory into itself.");
+            }
+            if dest == abs_from {
+                return Err("Source and destination are the same file.");
+            }
+            roots.push(ResolvedRoot {
+                from: abs_from,
+                dest,
+                is_dir,
+                contents,
+                own_name: into_to && !contents && name.is_some(),
+            });
+        }
+        // A single file copied to a new name: the run's destination is the directory it is in.
+        if !into_to && !roots[0].is_dir {
+            let parent = abs_to.parent().map(Path::to_path_buf).unwrap_or(abs_to);
+            return Ok((parent, roots));
+        }
+        Ok((abs_to, roots))
     }
 
-    pub fn build(self) -> Result<Copyer, &'static str> {
-        let r = Self::path_preprocess(&self.from.unwrap(), &self.to.unwrap());
-        if r.is_err() {
-            return Err("Wrong.");
+    // A source's absolute path, whether it is a directory, whether only its contents are copied,
+    // and its name. Unless `follow_links`, a symlink is taken as the link.
+    fn resolve_source<'a>(
+        fs: &dyn FileSystem,
+        source: &'a str,
+        follow_links: bool,
+    ) -> Result<(PathBuf, bool, bool, Option<&'a OsStr>), &'static str> {
+        // `.`, `..` and `/` have no name of their own and behave like `dir/`.
+        let name = Path::new(source).file_name();
+        let link = !follow_links
+            && !source.ends_with(std::path::is_separator)
+            && fs
+                .symlink_metadata(Path::new(source))
+                .is_ok_and(|m| m.file_type.is_symlink());
+        let from = match (link, name) {
+            (true, Some(name)) => Self::absolute(fs, Path::new(source).parent().unwrap())
+                .map(|parent| parent.join(name)),
+            _ => fs.canonicalize(Path::new(source)),
         }
+        .map_err(|_| "Preprocess from param failed.")?;
+        let is_dir = !link && fs.is_dir(&from);
+        let contents = is_dir && (source.ends_with(std::path::is_separator) || name.is_none());
+        Ok((from, is_dir, contents, name))
+    }
 
-        let (abs_from, abs_to) = r.unwrap();
-        let mut pool = None;
-        let mut root = None;
-        if self.threads_number > 0 {
-            pool = Some(ThreadPool::new(self.threads_number));
-            root = Some(SharedNodeRef::new(DirNode::new(PathBuf::from(abs_to.clone()), self.verbose)));
+    /// Check the settings and resolve the sources. Destination directories are created here,
+    /// unless for a dry run.
+    pub fn build(self) -> Result<Copyer, Error> {
+        if self.to_archive.is_some() {
+            return self.build_to_archive();
+        }
+        if self.from_archive.is_some() {
+            return self.build_from_archive();
+        }
+        if self.compress.is_some() && self.decompress {
+            return Err("--compress can't be combined with --decompress.".into());
+        }
+        let ciphers = self.encrypt.is_some() || self.decrypt.is_some();
+        if self.encrypt.is_some() && self.decrypt.is_some() {
+            return Err("--encrypt can't be combined with --decrypt.".into());
+        }
+        if ciphers && (self.compress.is_some() || self.decompress) {
+            return Err("--encrypt and --decrypt can't be combined with compression.".into());
+        }
+        if self.encrypt_names && !ciphers {
+            return Err("--encrypt-names needs --encrypt or --decrypt.".into());
+        }
+        // Moved directories are removed by their destination names.
+        if self.encrypt_names && self.moving {
+            return Err("--encrypt-names can't be combined with --move.".into());
+        }
+        let to = self.to.clone().ok_or("Not set target path.")?;
+        let (abs_to, sources) = Self::resolve_roots(
+            &*self.source_fs,
+            &*self.dest_fs,
+            &self.sources,
+            &to,
+            !self.moving,
+        )?;
+        if self.files_from.is_some() && !(sources.len() == 1 && sources[0].is_dir) {
+            return Err("--files-from needs a single directory source.".into());
+        }
+        let mut base = self.context(abs_to, self.backup_policy()?);
+        if self.encrypt_names {
+            let salt = self.names_salt(&base.to, &sources)?;
+            let cipher = |key: &EncryptionKey| Arc::new(Cipher::new(key).with_names(&salt));
+            base.encrypt = self.encrypt.as_ref().map(cipher);
+            base.decrypt = self.decrypt.as_ref().map(cipher);
+        }
+        let mut sources = sources;
+        for root in sources.iter_mut().filter(|root| root.own_name) {
+            let name = root.dest.file_name().unwrap();
+            let name = base
+                .dest_name(name)
+                .map_err(|e| Error::Config(format!("{}: {}", root.from.display(), e)))?;
+            root.dest.set_file_name(name);
+        }
+        if !self.dry_run {
+            for root in &sources {
+                if self.dest_fs.create_dir_all(root.dest_dir()).is_err() {
+                    return Err("Create to directory failed".into());
+                }
+            }
         }
+        let pool = self.pool();
+        let roots: Vec<CopyRoot> = sources
+            .into_iter()
+            .map(|root| {
+                let mut node = None;
+                if root.is_dir && pool.is_some() {
+                    let mut n = DirNode::new(root.dest.clone());
+                    n.set_listing();
+                    node = Some(SharedNodeRef::new(n));
+                }
+                CopyRoot {
+                    context: Arc::new(CopyContext {
+                        from: root.from,
+                        dest: root.dest,
+                        // `mv dir/ to` moves the contents, `dir` itself stays.
+                        keep_from: root.contents,
+                        ..base.clone()
+                    }),
+                    is_dir: root.is_dir,
+                    node,
+                }
+            })
+            .collect();
 
         Ok(Copyer {
-            verbose: self.verbose,
             multi_threads: self.multi_threads,
             pool,
-            from: PathBuf::from(abs_from),
-            to: PathBuf::from(abs_to),
-            _root: root,
+            context: roots[0].context.clone(),
+            roots,
+            files_from: self.files_from,
+            force: self.force,
+            scanned: None,
+            to_archive: None,
+            from_archive: None,
         })
     }
+
+    // Names are encrypted with a salt kept in a file at the top of the encrypted tree: in `to`
+    // when encrypting, made by the first run; above the sources when decrypting.
+    fn names_salt(&self, to: &Path, sources: &[ResolvedRoot]) -> Result<Salt, Error> {
+        let salt_error = |dir: &Path, e: io::Error| {
+            Error::Config(format!("{}: {}", dir.join(SALT_FILE).display(), e))
+        };
+        if self.encrypt.is_some() {
+            let dest_fs = &*self.dest_fs;
+            let salt = encryption::read_salt(dest_fs, to).map_err(|e| salt_error(to, e))?;
+            if let Some(salt) = salt {
+                return Ok(salt);
+            }
+            let salt = encryption::random_salt();
+            if !self.dry_run {
+                dest_fs
+                    .create_dir_all(to)
+                    .and_then(|()| encryption::write_salt(dest_fs, to, &salt))
+                    .map_err(|e| salt_error(to, e))?;
+            }
+            return Ok(salt);
+        }
+        let source_fs = &*self.source_fs;
+        for dir in sources[0].from.ancestors().filter(|dir| source_fs.is_dir(dir)) {
+            let salt = encryption::read_salt(source_fs, dir).map_err(|e| salt_error(dir, e))?;
+            if let Some(salt) = salt {
+                return Ok(salt);
+            }
+        }
+        Err(Error::Config(format!(
+            "No {} above the sources, names can't be decrypted.",
+            SALT_FILE
+        )))
+    }
+
+    fn backup_policy(&self) -> Result<Option<Arc<BackupPolicy>>, Error> {
+        if self.backup_mode.is_none() && self.backup_dir.is_none() {
+            return Ok(None);
+        }
+        let dir = match &self.backup_dir {
+            Some(dir) => Some(
+                Self::absolute(&*self.dest_fs, Path::new(dir))
+                    .map_err(|_| "Preprocess backup dir failed")?,
+            ),
+            None => None,
+        };
+        Ok(Some(Arc::new(BackupPolicy::new(self.backup_mode, dir))))
+    }
+
+    // The settings shared by every root, copying to `to`.
+    fn context(&self, to: PathBuf, backup: Option<Arc<BackupPolicy>>) -> CopyContext {
+        CopyContext {
+            from: PathBuf::new(),
+            dest: PathBuf::new(),
+            to,
+            sync: self.sync,
+            conflicts: Arc::new(ConflictResolver::new(self.on_conflict)),
+            backup,
+            moving: self.moving,
+            verify: self.verify,
+            dry_run: self.dry_run,
+            compress: self.compress,
+            decompress: self.decompress,
+            encrypt: self.encrypt.as_ref().map(|key| Arc::new(Cipher::new(key))),
+            decrypt: self.decrypt.as_ref().map(|key| Arc::new(Cipher::new(key))),
+            encrypt_names: self.encrypt_names,
+            keep_special_bits: self.keep_special_bits,
+            keep_from: false,
+            failure: Arc::new(Mutex::new(None)),
+            cancel: self.cancel.clone(),
+            progress: self.progress,
+            quiet: self.quiet,
+            events: self.events.clone(),
+            stream: Arc::new(OnceLock::new()),
+            observers: Arc::new(self.observers.clone()),
+            filter: Arc::new(self.filter.clone()),
+            attr_filter: self.attr_filter.clone(),
+            respect_gitignore: self.respect_gitignore,
+            source_fs: self.source_fs.clone(),
+            dest_fs: self.dest_fs.clone(),
+            stats: Arc::new(CopyStats::new()),
+        }
+    }
+
+    fn pool(&self) -> Option<ThreadPool> {
+        (self.threads_number > 0).then(|| ThreadPool::new(self.threads_number))
+    }
 }
 
-pub struct Copyer {
-    verbose: bool,
-    multi_threads: bool,
-    pool: Option<ThreadPool>,
+// Per-run settings and counters, shared by every copy task. Each source has a context of its
+// own, differing only in `from` and `dest`.
+#[derive(Clone)]
+struct CopyContext {
     from: PathBuf,
+    dest: PathBuf,
+    // Destination of the whole run, `dest` or its parent.
     to: PathBuf,
-    _root: Option<SharedNodeRef>,
+    sync: SyncPolicy,
+    conflicts: Arc<ConflictResolver>,
+    backup: Option<Arc<BackupPolicy>>,
+    moving: bool,
+    verify: bool,
+    dry_run: bool,
+    compress: Option<Compression>,
+    decompress: bool,
+    encrypt: Option<Arc<Cipher>>,
+    decrypt: Option<Arc<Cipher>>,
+    // Names are encrypted, or decrypted, too.
+    encrypt_names: bool,
+    // Extracted entries keep setuid, setgid and sticky bits.
+    keep_special_bits: bool,
+    // A moved `from` is left in place, only emptied.
+    keep_from: bool,
+    // Set when the run is stopped by an error, e.g. a conflict with `--on-conflict fail`.
+    failure: Arc<Mutex<Option<io::Error>>>,
+    cancel: CancelToken,
+    progress: bool,
+    quiet: bool,
+    events: Option<Arc<EventWriter>>,
+    // Set by `Copyer::event_stream`, after the contexts are made.
+    stream: Arc<OnceLock<EventSender>>,
+    observers: Arc<Vec<Arc<dyn CopyObserver>>>,
+    filter: Arc<Filter>,
+    attr_filter: AttrFilter,
+    respect_gitignore: bool,
+    source_fs: Arc<dyn FileSystem>,
+    dest_fs: Arc<dyn FileSystem>,
+    stats: Arc<CopyStats>,
 }
 
-impl Copyer {
-    pub fn builder() -> CopyBuilder {
-        CopyBuilder {
-            verbose: false,
-            multi_threads: false,
-            threads_number: 0,
-            from: None,
-            to: None,
+impl CopyContext {
+    fn root_ignores(&self) -> Option<Arc<IgnoreStack>> {
+        self.respect_gitignore
+            .then(|| IgnoreStack::root(&*self.source_fs, &self.from))
+    }
+
+    // What `exclude_reason` needs to know of the entry at `path` besides its type: nothing
+    // unless an attribute filter looks at files.
+    fn filter_metadata(
+        &self,
+        path: &Path,
+        file_type: FileType,
+    ) -> Result<Option<Metadata>, io::Error> {
+        if self.attr_filter.needs_metadata() && !file_type.is_dir() {
+            self.source_fs.symlink_metadata(path).map(Some)
+        } else {
+            Ok(None)
         }
     }
 
-    pub fn run_multi_threads(self) {
-        let pool_ref = self.pool.as_ref().unwrap();
-        Self::copy_dir_recursive(
-            PathBuf::from(self.from),
-            PathBuf::from(self.to),
-            PathBuf::new(),
-            pool_ref.sender.clone(),
-            self._root.as_ref().unwrap().clone(),
-            self.verbose
-        )
-        .expect("Copy failed");
+    // Why the entry at `path`, `depth_path` below the copy root, is left out, if it is. Without
+    // the caller's `metadata`, from `filter_metadata`, only the rules that need none are applied.
+    fn exclude_reason(
+        &self,
+        depth_path: &Path,
+        path: &Path,
+        file_type: FileType,
+        metadata: Option<&Metadata>,
+        ignores: Option<&Arc<IgnoreStack>>,
+    ) -> Option<&'static str> {
+        let is_dir = file_type.is_dir();
+        let salt_file = path.file_name() == Some(OsStr::new(SALT_FILE));
+        if salt_file && self.decrypt.is_some() && self.encrypt_names {
+            return Some("encryption salt");
+        }
+        if self.filter.is_excluded(depth_path, is_dir) {
+            return Some("excluded");
+        }
+        if ignores.is_some_and(|i| i.is_ignored(path, is_dir)) {
+            return Some("gitignored");
+        }
+        if self.attr_filter.is_excluded(&file_type, metadata) {
+            return Some("filtered");
+        }
+        None
+    }
 
-        let now = Instant::now();
-        println!("Waiting copy stop...");
-        // This loop check if root directory node is copied, which only possible when all children (and
-        // children of children, and so on...) of root is copied.
-        loop {
-            match self._root.as_ref().unwrap().inner().try_read() {
-                Ok(reader) => {
-                    if reader.is_copied() {
-                        println!("Copy complete.");
+    // `exclude_reason` of an entry of the walk, which comes with its type only. It is stat'ed
+    // once nothing else rules it out, and the metadata goes on to the copy of a kept entry.
+    fn walked_exclude_reason(
+        &self,
+        depth_path: &Path,
+        path: &Path,
+        file_type: FileType,
+        ignores: Option<&Arc<IgnoreStack>>,
+    ) -> Result<Walked, io::Error> {
+        if let Some(reason) = self.exclude_reason(depth_path, path, file_type, None, ignores) {
+            return Ok(Walked::Excluded(reason));
+        }
+        let metadata = self.filter_metadata(path, file_type)?;
+        if metadata
+            .as_ref()
+            .is_some_and(|m| self.attr_filter.is_excluded(&file_type, Some(m)))
+        {
+            return Ok(Walked::Excluded("filtered"));
+        }
+        Ok(Walked::Kept(metadata))
+    }
+
+    // Read the directory at `depth_path` below the root and hand `f` each entry that isn't left
+    // out, in name order if `sorted`, along with the ignore rules below the directory. Errors are
+    // reported where they happen and only cost the entry they happen on.
+    fn walk_dir<F>(
+        &self,
+        depth_path: &Path,
+        ignores: Option<&Arc<IgnoreStack>>,
+        sorted: bool,
+        mut f: F,
+    ) where
+        F: FnMut(WalkedEntry, Option<&Arc<IgnoreStack>>),
+    {
+        let read_dir = self.from.join(depth_path);
+        debug!("read dir {:?} (depth path {:?})", read_dir, depth_path);
+        self.notify(|o| o.on_dir_start(&self.dest_path(depth_path)));
+        let ignores = ignores.map(|i| i.child(&*self.source_fs, &read_dir));
+        let mut entries = match self.source_fs.read_dir(&read_dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                self.report_error(&read_dir, e);
+                return;
+            }
+        };
+        if sorted {
+            let mut listed = vec![];
+            for entry in entries {
+                match entry {
+                    Ok(entry) => listed.push(entry),
+                    Err(e) => {
+                        self.report_error(&read_dir, e);
                         break;
                     }
                 }
+            }
+            listed.sort_by(|a, b| a.name.cmp(&b.name));
+            entries = Box::new(listed.into_iter().map(Ok));
+        }
+        for entry in entries {
+            if self.cancel.is_cancelled() {
+                break;
+            }
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    self.report_error(&read_dir, e);
+                    break;
+                }
+            };
+            let path = read_dir.join(&entry.name);
+            let depth_path = depth_path.join(&entry.name);
+            let file_type = entry.file_type;
+            let metadata = match self.walked_exclude_reason(
+                &depth_path,
+                &path,
+                file_type,
+                ignores.as_ref(),
+            ) {
+                Ok(Walked::Kept(metadata)) => metadata,
+                Ok(Walked::Excluded(reason)) => {
+                    self.skip(&path, reason);
+                    continue;
+                }
                 Err(e) => {
-                    println!("{:?}", e);
+                    self.report_error(&path, e);
+                    continue;
                 }
+            };
+            if !(file_type.is_dir() || file_type.is_file() || file_type.is_symlink()) {
+                warn!("{:?} is not a file or directory", path);
+                self.skip(&path, "unsupported file type");
+                continue;
             }
-            thread::sleep(Duration::from_millis(50));
+            let entry = WalkedEntry {
+                path,
+                depth_path,
+                file_type,
+                metadata,
+            };
+            f(entry, ignores.as_ref());
         }
-        let elapsed_time = now.elapsed();
-        println!(
-            "Copy action took {} milliseconds.",
-            elapsed_time.as_millis()
-        );
-    }
-
-    pub fn run_single_threads(self) {
-        let now = Instant::now();
-        Self::copy_dir_recursive_single_thread(&self.from, &self.to, &PathBuf::new(), self.verbose)
-            .expect("Copy failed");
-        let elapsed_time = now.elapsed();
-        println!("Copy action took {} milliseconds", elapsed_time.as_millis());
     }
 
-    pub fn run(self) {
-        if self.multi_threads {
-            self.run_multi_threads();
+    // One `--files-from` entry. Its parent directories are created as needed; a listed
+    // directory is created but not walked, only listed paths are copied.
+    fn copy_listed(&self, listed: &Path) -> Result<(), io::Error> {
+        if self.cancel.is_cancelled() {
+            return Ok(());
+        }
+        let depth_path = match normalize_listed(listed) {
+            Some(p) => p,
+            None => {
+                warn!("{:?} is not a path inside the source", listed);
+                self.skip(listed, "outside source");
+                return Ok(());
+            }
+        };
+        let path = self.from.join(&depth_path);
+        let creating_path = self
+            .try_dest_path(&depth_path)
+            .map_err(|e| self.report_error(&path, e))?;
+        let metadata = self
+            .source_fs
+            .symlink_metadata(&path)
+            .map_err(|e| self.report_error(&path, e))?;
+        let file_type = metadata.file_type;
+        if file_type.is_dir() {
+            self.create_dir(&creating_path)?;
+        } else if file_type.is_file() || file_type.is_symlink() {
+            match creating_path.parent() {
+                Some(parent) if !self.dry_run => self
+                    .dest_fs
+                    .create_dir_all(parent)
+                    .map_err(|e| self.report_error(parent, e))?,
+                _ => {}
+            }
+            debug!("create file {:?}", creating_path);
+            self.copy_file(&path, &creating_path, Some(metadata))?;
         } else {
-            self.run_single_threads();
+            warn!("{:?} is not a file or directory", path);
+            self.skip(&path, "unsupported file type");
         }
+        Ok(())
     }
 
-    // TODO May causing problem when handle really big file
-    fn copy_file(from: &Path, to: &Path) -> Result<(), io::Error> {
-        let content = fs::read_to_string(from)?;
-        let mut file = fs::File::create(to)?;
-        file.write_all(content.as_bytes())?;
+    // A directory of the walk, which a dry run only notes if it doesn't exist yet.
+    fn create_dir(&self, path: &Path) -> Result<(), io::Error> {
+        debug!("create dir {:?}", path);
+        if !self.dry_run {
+            self.dest_fs
+                .create_dir_all(path)
+                .map_err(|e| self.report_error(path, e))?;
+        } else if !self.dest_fs.is_dir(path) {
+            self.plan(|| format!("create {}/", self.relative(path)));
+        }
+        self.stats.add_dir();
+        self.emit(|| Event::DirCreated {
+            path: self.relative(path),
+        });
         Ok(())
     }
 
-    fn copy_dir_recursive_single_thread(
+    // Write into a temporary sibling first and rename it into place, so a cancelled or failed
+    // copy never leaves a half-written file under the real name. An existing destination is
+    // dealt with as the conflict policy decides, before anything is read. `metadata` is that of
+    // `from`, not followed, if the caller has it.
+    fn copy_file(
+        &self,
         from: &Path,
-        dest: &Path,
-        depth_path: &PathBuf,
-        verbose: bool
+        to: &Path,
+        metadata: Option<Metadata>,
     ) -> Result<(), io::Error> {
-        let read_dir = from.clone().to_path_buf().join(depth_path);
-        if verbose {
-            println!("-----------");
-            println!("from : {:?}", from);
-            println!("dest : {:?}", dest);
-            println!("depth_path : {:?}", depth_path);
-            println!("read_dir : {:?}", read_dir);
-        }
-        for entry in fs::read_dir(read_dir)? {
-            let entry = entry?;
-            let path = entry.path();
-            let new_depth_path = depth_path.clone().join(Path::new(&entry.file_name()));
-            let creating_path = dest.clone().to_path_buf().join(new_depth_path.clone());
-            if path.is_dir() && !path.is_symlink() {
-                if verbose {
-                    println!("next depth's path: {:?}", new_depth_path);
-                    println!("creating path: {:?}", new_depth_path);
-                }
-                create_dir_all(&creating_path)?;
-                Self::copy_dir_recursive_single_thread(from, dest, &new_depth_path,verbose)?;
-            } else if path.is_file() || path.is_symlink() {
-                let read_file = from.clone().to_path_buf().join(new_depth_path.clone());
-                if verbose {
-                    println!("creating file : {:?}", creating_path);
+        let now = Instant::now();
+        let source = SourceMetadata::new(&*self.source_fs, from, metadata);
+        let to = &self.copied_path(from, to);
+        let decision = match self.dest_fs.symlink_metadata(to) {
+            // A dry run doesn't ask, the answer can't be known.
+            Ok(_) if self.dry_run && self.conflicts.asks() => {
+                self.plan(|| format!("ask about {}, kept for now", self.relative(to)));
+                Some(Decision::Skip)
+            }
+            Ok(existing) => Some(self.conflicts.decide(to, || {
+                let source = source.followed();
+                source.is_ok_and(|source| is_newer(source, &existing))
+            })),
+            Err(_) => None,
+        };
+        match decision {
+            Some(Decision::Skip) => {
+                debug!("{:?} exists, kept", to);
+                self.plan(|| format!("keep {}", self.relative(to)));
+                self.conflict(to, Decision::Skip, None);
+                return Ok(());
+            }
+            Some(Decision::Fail) => {
+                self.plan(|| format!("stop at {}, it exists", self.relative(to)));
+                self.conflict(to, Decision::Fail, None);
+                let e = io::Error::new(io::ErrorKind::AlreadyExists, "destination exists");
+                self.fail(to, e);
+                return Ok(());
+            }
+            _ if self.dry_run => return self.plan_file(&source, to, decision),
+            _ => {}
+        }
+
+        let temp = temp_path(to);
+        match self
+            .write_temp_file(&source, &temp)
+            .and_then(|bytes| self.place(&temp, to, decision).map(|dest| (bytes, dest)))
+        {
+            Ok((bytes, dest)) => {
+                self.file_placed(to, &dest, decision, bytes, now.elapsed());
+                if self.moving {
+                    debug!("remove moved file {:?}", from);
+                    self.source_fs
+                        .remove_file(from)
+                        .map_err(|e| self.report_error(from, e))?;
                 }
-                Self::copy_file(read_file.as_path(), creating_path.as_path())?;
+                Ok(())
             }
+            Err(e) => self.file_failed(to, &temp, e),
         }
+    }
 
-        Ok(())
+    // Count and publish a file now at `dest`, which is `to` unless the copy was renamed.
+    fn file_placed(
+        &self,
+        to: &Path,
+        dest: &Path,
+        decision: Option<Decision>,
+        bytes: u64,
+        elapsed: Duration,
+    ) {
+        if let Some(decision) = decision {
+            let renamed_to = (dest != to).then(|| self.relative(dest));
+            self.conflict(to, decision, renamed_to);
+        }
+        self.stats.add_file(bytes);
+        self.emit(|| Event::FileCopied {
+            path: self.relative(dest),
+            bytes,
+            duration_ms: elapsed.as_secs_f64() * 1000.0,
+        });
+        self.notify(|o| o.on_file_done(dest, bytes, elapsed));
     }
 
-    fn copy_dir_recursive(
-        from: PathBuf,
-        dest: PathBuf,
-        depth_path: PathBuf,
-        sender: Sender<Message>,
-        parent_node: SharedNodeRef,
-        verbose: bool
+    // Writing `to` failed: the temp file goes, and a cancel or a full destination aren't errors
+    // of this file.
+    fn file_failed(&self, to: &Path, temp: &Path, e: io::Error) -> Result<(), io::Error> {
+        let _ = self.dest_fs.remove_file(temp);
+        if self.cancel.is_cancelled() {
+            debug!("cancelled, removed partial file {:?}", temp);
+            self.skip(to, "cancelled");
+            return Ok(());
+        }
+        // Nothing else would fit either.
+        if e.kind() == io::ErrorKind::StorageFull {
+            self.fail(to, e);
+            return Ok(());
+        }
+        Err(self.report_error(to, e))
+    }
+
+    // What `copy_file` would do once the conflict is decided, counted as if it was done.
+    fn plan_file(
+        &self,
+        source: &SourceMetadata<'_>,
+        to: &Path,
+        decision: Option<Decision>,
     ) -> Result<(), io::Error> {
-        let read_dir = from.clone().to_path_buf().join(depth_path.clone());
-        if verbose {
-            println!("-----------");
-            println!("from : {:?}", from);
-            println!("dest : {:?}", dest);
-            println!("depth_path : {:?}", depth_path);
-            println!("read_dir : {:?}", read_dir);
-        }
-        for entry in fs::read_dir(read_dir)? {
-            let entry = entry?;
-            let path = entry.path();
-            let new_depth_path = depth_path.clone().join(Path::new(&entry.file_name()));
-            let creating_path = dest.clone().to_path_buf().join(new_depth_path.clone());
-            if path.is_dir() && !path.is_symlink() {
-                if verbose {
-                    println!("next depth's path: {:?}", new_depth_path);
-                    println!("creating path: {:?}", new_depth_path);
+        let from = source.path;
+        // Copies follow symlinks, moves keep them.
+        let metadata = if self.moving {
+            source.own()
+        } else {
+            source.followed()
+        }
+        .map_err(|e| self.report_error(from, e))?;
+        let bytes = metadata.len;
+        // A move within one filesystem only links the data.
+        let linked = self.moving && self.same_device_as(metadata, to);
+        let mut needed = if linked { 0 } else { bytes as i64 };
+        let mut dest = to.to_path_buf();
+        match decision {
+            Some(Decision::Overwrite) => {
+                if self.backup.is_some() {
+                    self.plan(|| format!("back up {}", self.relative(to)));
+                    self.stats.add_backup();
+                } else {
+                    needed -= self.dest_fs.symlink_metadata(to).map_or(0, |m| m.len) as i64;
                 }
-                create_dir_all(&creating_path)?;
+                self.plan(|| format!("overwrite {}", self.relative(to)));
+            }
+            Some(Decision::Rename) => {
+                dest = (1..)
+                    .map(|n| renamed_path(to, n))
+                    .find(|p| self.dest_fs.symlink_metadata(p).is_err())
+                    .unwrap();
+                self.plan(|| format!("copy {} as {}", self.relative(to), self.relative(&dest)));
+            }
+            _ => self.plan(|| format!("copy {}", self.relative(to))),
+        }
+        if let Some(decision) = decision {
+            let renamed_to = (dest != to).then(|| self.relative(&dest));
+            self.conflict(to, decision, renamed_to);
+        }
+        self.stats.add_file(bytes);
+        self.stats.add_needed(needed);
+        self.emit(|| Event::FileCopied {
+            path: self.relative(&dest),
+            bytes,
+            duration_ms: 0.0,
+        });
+        self.notify(|o| o.on_file_done(&dest, bytes, Duration::ZERO));
+        if self.moving {
+            self.plan(|| format!("remove {}", from.display()));
+            self.stats.add_removed();
+        }
+        Ok(())
+    }
 
-                // Create new node for directory in this loop, and then attach it to directory tree and
-                // set parent for it.
-                if verbose {
-                    println!("creating new node for path {:?}", creating_path);
+    // Move the written temp file to its final name and return that: `to`, or for a renamed copy
+    // the first free `name (n).ext`. Claiming the new name with a hard link fails if another
+    // worker got there first, so two workers never pick the same one.
+    fn place(
+        &self,
+        temp: &Path,
+        to: &Path,
+        decision: Option<Decision>,
+    ) -> Result<PathBuf, io::Error> {
+        if decision != Some(Decision::Rename) {
+            if decision == Some(Decision::Overwrite) {
+                self.back_up(to)?;
+            }
+            self.dest_fs.rename(temp, to)?;
+            return Ok(to.to_path_buf());
+        }
+        let fs = &self.dest_fs;
+        let mut n = 1;
+        loop {
+            let candidate = renamed_path(to, n);
+            match fs.hard_link(temp, &candidate) {
+                Ok(()) => {
+                    fs.remove_file(temp)?;
+                    return Ok(candidate);
                 }
-                let mut node = DirNode::new(creating_path, verbose);
-                node.set_parent(parent_node.clone());
-                let node_r = SharedNodeRef::new(node);
-
-                if verbose {
-                    println!("add new node to parent");
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                // Filesystems without hard links.
+                Err(_) if !fs.exists(&candidate) => {
+                    fs.rename(temp, &candidate)?;
+                    return Ok(candidate);
                 }
-                let mut writer = parent_node.inner().write().unwrap();
-                writer.add_sub_nodes(node_r.clone());
-                drop(writer);
+                Err(_) => {}
+            }
+            n += 1;
+        }
+    }
 
-                if verbose {
-                    println!("attach node to tree done");
-                }
+    fn back_up(&self, to: &Path) -> Result<(), io::Error> {
+        let backup = match &self.backup {
+            Some(backup) => backup,
+            None => return Ok(()),
+        };
+        let relative = to.strip_prefix(&self.to).unwrap_or(to);
+        let path = backup.back_up(&*self.dest_fs, to, relative)?;
+        debug!("backed up {:?} to {:?}", to, path);
+        self.stats.add_backup();
+        self.emit(|| Event::BackedUp {
+            path: self.relative(to),
+            backup: self.relative(&path),
+        });
+        Ok(())
+    }
 
-                let new_from = from.clone();
-                let new_dest = dest.clone();
-                let new_new_depth_path = new_depth_path.clone();
-                let new_sender = sender.clone();
-
-                //For directory under this directory, make it as a new task to pool.
-                sender
-                    .send(Message::NewTask(Box::new(move || {
-                        Self::copy_dir_recursive(
-                            new_from,
-                            new_dest,
-                            new_new_depth_path,
-                            new_sender,
-                            node_r,
-                            verbose
-                        )
-                        .unwrap();
-                    })))
-                    .unwrap();
-            } else if path.is_file() || path.is_symlink() {
-                let read_file = from.clone().to_path_buf().join(new_depth_path.clone());
-                if verbose {
-                    println!("creating file : {:?}", creating_path);
-                }
-                Self::copy_file(read_file.as_path(), creating_path.as_path())?;
-            }
+    fn conflict(&self, to: &Path, decision: Decision, renamed_to: Option<String>) {
+        info!("conflict on {:?}: {}", to, decision);
+        self.stats.add_conflict(decision);
+        self.emit(|| Event::Conflict {
+            path: self.relative(to),
+            decision: decision.to_string(),
+            renamed_to,
+        });
+    }
+
+    // Report an error that stops the whole run: running tasks wind down like on a cancel, and
+    // `run` returns the error.
+    fn fail(&self, path: &Path, e: io::Error) {
+        let e = self.report_error(path, e);
+        self.set_failure(path, &e);
+        // A dry run goes on, to show everything the real one would run into.
+        if !self.dry_run {
+            self.cancel.cancel();
         }
+    }
 
-        let mut writer = parent_node.inner().write().unwrap();
-        if verbose {
-            println!("start lookup {:?}", writer.path());
+    // Run a pool task working on `path`. A panic is reported as an error on it, so what waits
+    // for the task still sees it end.
+    fn guard<F: FnOnce()>(&self, path: &Path, task: F) {
+        if panic::catch_unwind(AssertUnwindSafe(task)).is_err() {
+            self.report_error(path, io::Error::other("copy task panicked"));
         }
-        writer.set_copied(); //当前node的父node检查
-        drop(writer);
+    }
 
-        DirNode::try_lookup_continuously(parent_node);
+    // Have `run` return an error that is reported already, the first one if there are several.
+    fn set_failure(&self, path: &Path, e: &io::Error) {
+        let mut failure = self.failure.lock().unwrap();
+        if failure.is_none() {
+            *failure = Some(io::Error::new(
+                e.kind(),
+                format!("{}: {}", path.display(), e),
+            ));
+        }
+    }
 
-        Ok(())
+    fn take_failure(&self) -> Result<(), io::Error> {
+        match self.failure.lock().unwrap().take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
     }
-}
-#[cfg(test)]
-mod copy_test {
-    use super::*;
 
-    #[test]
-    fn copy_file_test() {
-        let from = Path::new("./test_dir/copy_test_dir/origin_file");
-        let to = Path::new("./test_dir/copy_test_dir/copied_file1");
-        Copyer::copy_file(&from, &to).unwrap();
-        let content_from = fs::read_to_string(&from).unwrap();
-        let content_to = fs::read_to_string(&to).unwrap();
-        assert_eq!(content_to, content_from);
+    // Where a file copied to `to` goes: compressed or encrypted, its name gets the extension;
+    // decompressed or decrypted, it loses it. Encrypted names are mapped already.
+    fn copied_path(&self, from: &Path, to: &Path) -> PathBuf {
+        let named = |compression| Compression::of(to) == Some(compression);
+        let plain_names = !self.encrypt_names;
+        match self.transform(from) {
+            Some(Transform::Compress(compression)) => compression.compressed_path(to),
+            Some(Transform::Decompress(compression)) if named(compression) => {
+                to.with_extension("")
+            }
+            Some(Transform::Encrypt(_)) if plain_names => encryption::encrypted_path(to),
+            Some(Transform::Decrypt(_)) if plain_names && encryption::is_encrypted(to) => {
+                to.with_extension("")
+            }
+            _ => to.to_path_buf(),
+        }
+    }
+
+    fn transform(&self, from: &Path) -> Option<Transform<'_>> {
+        if let Some(cipher) = &self.encrypt {
+            return Some(Transform::Encrypt(cipher));
+        }
+        if let Some(cipher) = &self.decrypt {
+            let encrypted = self.encrypt_names || encryption::is_encrypted(from);
+            return encrypted.then_some(Transform::Decrypt(cipher));
+        }
+        match self.compress {
+            Some(compression) => Some(Transform::Compress(compression)),
+            None if self.decompress => Compression::of(from).map(Transform::Decompress),
+            None => None,
+        }
+    }
+
+    fn write_temp_file(&self, source: &SourceMetadata<'_>, temp: &Path) -> Result<u64, io::Error> {
+        let from = source.path;
+        let transform = self.transform(from);
+        if self.moving && transform.is_none() {
+            if let Some(bytes) = self.link_temp_file(source, temp)? {
+                return Ok(bytes);
+            }
+        }
+        let (source_fs, dest_fs) = (&*self.source_fs, &*self.dest_fs);
+        let (mut file, bytes) = match transform {
+            None => Copyer::copy_file(source_fs, dest_fs, from, temp, &self.cancel, &self.stats)?,
+            Some(Transform::Compress(compression)) => {
+                let mut encoder = compression.encoder(dest_fs.create(temp)?)?;
+                let reader = source_fs.open(from)?;
+                let bytes = Copyer::copy_into(reader, &mut encoder, &self.cancel, &self.stats)?;
+                (encoder.finish()?, bytes)
+            }
+            Some(Transform::Decompress(compression)) => {
+                let reader = compression.decoder(source_fs.open(from)?)?;
+                Copyer::copy_stream(reader, dest_fs, temp, &self.cancel, &self.stats)?
+            }
+            Some(Transform::Encrypt(cipher)) => {
+                let mut encryptor = cipher.encryptor(dest_fs.create(temp)?)?;
+                let reader = source_fs.open(from)?;
+                let bytes = Copyer::copy_into(reader, &mut encryptor, &self.cancel, &self.stats)?;
+                (encryptor.finish()?, bytes)
+            }
+            Some(Transform::Decrypt(cipher)) => {
+                let reader = cipher.decryptor(source_fs.open(from)?)?;
+                Copyer::copy_stream(reader, dest_fs, temp, &self.cancel, &self.stats)?
+            }
+        };
+        // A move leaves only the copy, so it keeps what it can of the source. Set before the
+        // sync, which then flushes them along with the data.
+        if self.moving {
+            self.keep_attributes(source.followed()?, temp)?;
+        }
+        if self.sync.syncs_files() {
+            self.timed_sync(|| file.sync_all())?;
+        }
+        drop(file);
+        if self.verify && !self.copy_equal(from, temp, transform)? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "copy differs from the source",
+            ));
+        }
+        Ok(bytes)
+    }
+
+    // Whether `temp` holds the data of `from`, read as it was transformed.
+    fn copy_equal(
+        &self,
+        from: &Path,
+        temp: &Path,
+        transform: Option<Transform<'_>>,
+    ) -> Result<bool, io::Error> {
+        let (source, copy) = (self.source_fs.open(from)?, self.dest_fs.open(temp)?);
+        match transform {
+            None => files_equal(source, copy),
+            Some(Transform::Compress(compression)) => {
+                files_equal(source, compression.decoder(copy)?)
+            }
+            Some(Transform::Decompress(compression)) => {
+                files_equal(compression.decoder(source)?, copy)
+            }
+            Some(Transform::Encrypt(cipher)) => files_equal(source, cipher.decryptor(copy)?),
+            Some(Transform::Decrypt(cipher)) => files_equal(cipher.decryptor(source)?, copy),
+        }
+    }
+
+    // A move within one filesystem needs no copy: the temp name becomes a second link to the
+    // source, which is unlinked once the move is complete. Symlinks are moved as links, also
+    // between filesystems. Returns
+    // `None` when the data has to be copied instead.
+    fn link_temp_file(
+        &self,
+        source: &SourceMetadata<'_>,
+        temp: &Path,
+    ) -> Result<Option<u64>, io::Error> {
+        let (source_fs, dest_fs, from) = (&self.source_fs, &self.dest_fs, source.path);
+        let _ = dest_fs.remove_file(temp);
+        if !self.same_fs() || dest_fs.hard_link(from, temp).is_err() {
+            if !source.own()?.file_type.is_symlink() {
+                return Ok(None);
+            }
+            match dest_fs.symlink(&source_fs.read_link(from)?, temp) {
+                Err(e) if e.kind() == io::ErrorKind::Unsupported => return Ok(None),
+                r => r?,
+            }
+        }
+        let bytes = dest_fs.symlink_metadata(temp)?.len;
+        self.stats.add_written(bytes);
+        Ok(Some(bytes))
+    }
+
+    // Give the copy at `temp` the modification time and permissions of the source's `metadata`.
+    // Permissions go last, they may take away the right to set the time.
+    fn keep_attributes(&self, metadata: &Metadata, temp: &Path) -> Result<(), io::Error> {
+        if let Some(modified) = metadata.modified {
+            match self.dest_fs.set_modified(temp, modified) {
+                Err(e) if e.kind() != io::ErrorKind::Unsupported => return Err(e),
+                _ => {}
+            }
+        }
+        self.dest_fs.set_permissions(temp, metadata.mode & 0o7777)
+    }
+
+    // A directory of the walk is complete: sync it and, when moving, remove the emptied source
+    // directory. What was excluded or failed keeps it from being empty, so it stays then.
+    fn dir_complete(&self, path: &Path) -> Result<(), io::Error> {
+        // `to` itself not being synced fails the run, as in `Copyer::sync_to`.
+        if let Err(e) = self.sync_directory(path) {
+            if path == self.to {
+                self.set_failure(path, &e);
+            }
+            return Err(e);
+        }
+        self.notify(|o| o.on_dir_complete(path));
+        let rest = match path.strip_prefix(&self.dest) {
+            Ok(rest) if self.moving => rest,
+            _ => return Ok(()),
+        };
+        if rest.as_os_str().is_empty() && self.keep_from {
+            return Ok(());
+        }
+        let source = if rest.as_os_str().is_empty() {
+            self.from.clone()
+        } else {
+            self.from.join(rest)
+        };
+        if self.dry_run {
+            if self.selects_entries() {
+                self.plan(|| format!("remove directory {} if it is empty", source.display()));
+            } else {
+                self.plan(|| format!("remove directory {}", source.display()));
+            }
+            return Ok(());
+        }
+        match self.source_fs.remove_dir(&source) {
+            Ok(()) => debug!("removed moved directory {:?}", source),
+            Err(e) => info!("source directory {:?} kept: {}", source, e),
+        }
+        Ok(())
+    }
+
+    fn sync_directory(&self, path: &Path) -> Result<(), io::Error> {
+        if self.sync.syncs_dirs() && !self.dry_run {
+            debug!("sync directory {:?}", path);
+            self.timed_sync(|| self.dest_fs.sync_dir(path))
+                .map_err(|e| self.report_error(path, e))?;
+        }
+        Ok(())
+    }
+
+    // Whether sources and destination are on one filesystem, so renames and hard links between
+    // them can work.
+    fn same_fs(&self) -> bool {
+        Arc::ptr_eq(&self.source_fs, &self.dest_fs)
+    }
+
+    // Whether `to`, or the part of it that exists, is on the device `from` is on.
+    fn same_device(&self, from: &Path, to: &Path) -> bool {
+        self.same_fs()
+            && self.source_fs.symlink_metadata(from).is_ok_and(|m| self.same_device_as(&m, to))
+    }
+
+    // `same_device` of a source stat'ed already.
+    fn same_device_as(&self, from: &Metadata, to: &Path) -> bool {
+        if !self.same_fs() {
+            return false;
+        }
+        let fs = &self.dest_fs;
+        let to_dev = to.ancestors().find_map(|p| fs.symlink_metadata(p).ok());
+        to_dev.is_some_and(|to| from.dev != 0 && from.dev == to.dev)
+    }
+
+    // Anything that could leave part of a source behind rules out moving it with one rename.
+    fn selects_entries(&self) -> bool {
+        !self.filter.is_empty() || !self.attr_filter.is_empty() || self.respect_gitignore
+    }
+
+    // Without the final sync nothing is known to be on disk, so its failure fails the run.
+    fn finish(&self) {
+        if self.sync == SyncPolicy::End && !self.dry_run {
+            if let Err(e) = self.timed_sync(|| self.dest_fs.sync_fs(&self.to)) {
+                self.fail(&self.to, e);
+            }
+        }
+    }
+
+    fn skip(&self, path: &Path, reason: &str) {
+        info!("skipped {:?}: {}", path, reason);
+        self.plan(|| format!("skip {} ({})", self.relative(path), reason));
+        self.stats.add_skipped();
+        self.emit(|| Event::FileSkipped {
+            path: self.relative(path),
+            reason: String::from(reason),
+        });
+    }
+
+    // Count and publish an error, then hand it back to be propagated.
+    fn report_error(&self, path: &Path, e: io::Error) -> io::Error {
+        error!("{:?}: {}", path, e);
+        self.stats.add_error();
+        self.emit(|| Event::Error {
+            path: self.relative(path),
+            message: e.to_string(),
+        });
+        self.notify(|o| o.on_error(path, &e));
+        e
+    }
+
+    // Where the entry at `depth_path` below the root goes; the root itself for an empty path.
+    // A name that can't be mapped is kept, as the entry is reported where it is copied.
+    fn dest_path(&self, depth_path: &Path) -> PathBuf {
+        self.try_dest_path(depth_path).unwrap_or_else(|_| self.dest.join(depth_path))
+    }
+
+    // `dest_path`, failing when a name can't be encrypted or decrypted.
+    fn try_dest_path(&self, depth_path: &Path) -> Result<PathBuf, io::Error> {
+        if depth_path.as_os_str().is_empty() {
+            return Ok(self.dest.clone());
+        }
+        if !self.encrypt_names {
+            return Ok(self.dest.join(depth_path));
+        }
+        let mut path = self.dest.clone();
+        for name in depth_path.iter() {
+            path.push(self.dest_name(name)?);
+        }
+        Ok(path)
+    }
+
+    // The destination name of an entry named `name` in the source.
+    fn dest_name(&self, name: &OsStr) -> Result<OsString, io::Error> {
+        match (&self.encrypt, &self.decrypt) {
+            (Some(cipher), _) if self.encrypt_names => cipher.encrypt_name(name),
+            (_, Some(cipher)) if self.encrypt_names => cipher.decrypt_name(name),
+            _ => Ok(name.to_owned()),
+        }
+    }
+
+    fn notify<F>(&self, hook: F)
+    where
+        F: Fn(&dyn CopyObserver),
+    {
+        for observer in self.observers.iter() {
+            hook(observer.as_ref());
+        }
+    }
+
+    // Events are built lazily, so runs without `--output json` or an event stream don't format
+    // paths for nothing.
+    fn emit<F>(&self, event: F)
+    where
+        F: FnOnce() -> Event,
+    {
+        let stream = self.stream.get();
+        if self.events.is_none() && stream.is_none() {
+            return;
+        }
+        let event = event();
+        if let Some(events) = &self.events {
+            events.emit(&event);
+        }
+        if let Some(stream) = stream {
+            stream.send(event);
+        }
+    }
+
+    // Path of an entry relative to the run's destination, source paths are mapped to where they
+    // are copied to.
+    fn relative(&self, path: &Path) -> String {
+        let in_dest = match path.strip_prefix(&self.from) {
+            Ok(rest) if !path.starts_with(&self.dest) => self.dest.join(rest),
+            _ => path.to_path_buf(),
+        };
+        let relative = in_dest.strip_prefix(&self.to).unwrap_or(&in_dest);
+        relative.to_string_lossy().into_owned()
+    }
+
+    // One line of a dry run's plan.
+    fn plan<F>(&self, line: F)
+    where
+        F: FnOnce() -> String,
+    {
+        if self.dry_run && !self.quiet {
+            println!("{}", line());
+        }
+    }
+
+    fn say(&self, message: &str) {
+        if !self.quiet {
+            println!("{}", message);
+        }
+    }
+
+    fn timed_sync<F>(&self, f: F) -> Result<(), io::Error>
+    where
+        F: FnOnce() -> Result<(), io::Error>,
+    {
+        let now = Instant::now();
+        let r = f();
+        self.stats.add_sync_time(now.elapsed());
+        r
+    }
+
+    fn start_run(&self, sources: &[&Path], threads: usize) {
+        info!(
+            "copy {:?} to {:?} with {} threads",
+            sources, self.to, threads
+        );
+        self.emit(|| Event::RunStarted {
+            sources: sources
+                .iter()
+                .map(|s| s.to_string_lossy().into_owned())
+                .collect(),
+            to: self.to.to_string_lossy().into_owned(),
+            threads,
+            dry_run: self.dry_run,
+        });
+    }
+
+    // Report the run, or the error that stopped it.
+    fn finish_run(&self, elapsed: Duration) -> Result<CopyReport, io::Error> {
+        let stats = &self.stats;
+        let available = if self.dry_run {
+            self.dest_fs.available(&self.to).ok()
+        } else {
+            None
+        };
+        if self.dry_run {
+            self.say_dry_run(available);
+        } else {
+            self.say_run(elapsed);
+        }
+        self.emit(|| Event::RunFinished {
+            files: stats.files(),
+            bytes: stats.bytes(),
+            dirs: stats.dirs(),
+            skipped: stats.skipped(),
+            errors: stats.errors(),
+            conflicts: ConflictCounts {
+                overwritten: stats.overwritten(),
+                skipped: stats.kept(),
+                renamed: stats.renamed(),
+            },
+            backups: stats.backups(),
+            moved: stats.moved(),
+            dry_run: self.dry_run.then(|| DryRunCounts {
+                removed: stats.removed(),
+                bytes_needed: stats.needed(),
+                bytes_available: available.map(|space| space.bytes),
+            }),
+            cancelled: self.cancel.is_cancelled(),
+            sync_ms: stats.sync_time().as_millis(),
+            elapsed_ms: elapsed.as_millis(),
+        });
+        if let Some(stream) = self.stream.get() {
+            stream.close();
+        }
+        self.take_failure()?;
+        Ok(CopyReport::new(
+            stats,
+            self.dry_run,
+            self.cancel.is_cancelled(),
+            elapsed,
+        ))
+    }
+
+    fn say_dry_run(&self, available: Option<Space>) {
+        let stats = &self.stats;
+        self.say("Dry run, nothing was changed.");
+        if let Some(e) = &*self.failure.lock().unwrap() {
+            self.say(&format!("Copy would stop: {}", e));
+        } else if self.cancel.is_cancelled() {
+            self.say("Dry run cancelled, the plan is incomplete.");
+        }
+        self.say(&format!(
+            "Would copy {} files ({} bytes) and {} directories.",
+            stats.files(),
+            stats.bytes(),
+            stats.dirs()
+        ));
+        if stats.skipped() > 0 || stats.errors() > 0 {
+            self.say(&format!(
+                "Would skip {} entries, {} errors.",
+                stats.skipped(),
+                stats.errors()
+            ));
+        }
+        if stats.overwritten() > 0 || stats.kept() > 0 || stats.renamed() > 0 {
+            self.say(&format!(
+                "Existing files: {} overwritten, {} kept, {} copied under a new name.",
+                stats.overwritten(),
+                stats.kept(),
+                stats.renamed()
+            ));
+        }
+        if stats.moved() > 0 || stats.removed() > 0 {
+            self.say(&format!(
+                "Would move {} sources by renaming them and remove {} moved files.",
+                stats.moved(),
+                stats.removed()
+            ));
+        }
+        if stats.backups() > 0 {
+            self.say(&format!("Would back up {} overwritten files.", stats.backups()));
+        }
+        let free = match available {
+            Some(space) => format!("{} available", format_bytes(space.bytes)),
+            None => String::from("free space unknown"),
+        };
+        self.say(&format!(
+            "Needs {} on the destination, {}.",
+            format_bytes(stats.needed()),
+            free
+        ));
+        let inodes = stats.files() + stats.dirs();
+        if let Some(shortage) = available.and_then(|s| s.shortage(stats.needed(), inodes)) {
+            self.say(&format!("The copy would not fit: {}.", shortage));
+        }
+    }
+
+    fn say_run(&self, elapsed: Duration) {
+        let stats = &self.stats;
+        self.say(&format!(
+            "Copy action took {} milliseconds.",
+            elapsed.as_millis()
+        ));
+        if let Some(e) = &*self.failure.lock().unwrap() {
+            self.say(&format!("Copy stopped: {}", e));
+        } else if self.cancel.is_cancelled() {
+            self.say("Copy cancelled, partial result kept.");
+        }
+        self.say(&format!(
+            "Copied {} files ({} bytes) and {} directories.",
+            stats.files(),
+            stats.bytes(),
+            stats.dirs()
+        ));
+        if stats.skipped() > 0 || stats.errors() > 0 {
+            self.say(&format!(
+                "Skipped {} entries, {} errors.",
+                stats.skipped(),
+                stats.errors()
+            ));
+        }
+        if stats.overwritten() > 0 || stats.kept() > 0 || stats.renamed() > 0 {
+            self.say(&format!(
+                "Existing files: {} overwritten, {} kept, {} copied under a new name.",
+                stats.overwritten(),
+                stats.kept(),
+                stats.renamed()
+            ));
+        }
+        if stats.moved() > 0 {
+            self.say(&format!("Moved {} sources by renaming them.", stats.moved()));
+        }
+        if stats.backups() > 0 {
+            self.say(&format!("Backed up {} overwritten files.", stats.backups()));
+        }
+        if self.sync != SyncPolicy::None {
+            self.say(&format!(
+                "Sync ({}) took {} milliseconds.",
+                self.sync,
+                stats.sync_time().as_millis()
+            ));
+        }
+    }
+}
+
+fn files_equal(mut a: impl Read, mut b: impl Read) -> Result<bool, io::Error> {
+    let mut a_buf = vec![0u8; COPY_BUFFER_SIZE];
+    let mut b_buf = vec![0u8; COPY_BUFFER_SIZE];
+    loop {
+        let n = a.read(&mut a_buf)?;
+        if n == 0 {
+            return Ok(b.read(&mut b_buf[..1])? == 0);
+        }
+        if b.read_exact(&mut b_buf[..n]).is_err() || a_buf[..n] != b_buf[..n] {
+            return Ok(false);
+        }
+    }
+}
+
+// What is done to the data of a file as it is copied.
+#[derive(Clone, Copy)]
+enum Transform<'a> {
+    Compress(Compression),
+    Decompress(Compression),
+    Encrypt(&'a Cipher),
+    Decrypt(&'a Cipher),
+}
+
+// What the walk does with an entry.
+enum Walked {
+    Excluded(&'static str),
+    // With its metadata, not followed, if a filter needed it.
+    Kept(Option<Metadata>),
+}
+
+// An entry of the walk that is copied: a directory, a file or a symlink.
+struct WalkedEntry {
+    path: PathBuf,
+    // Below the root.
+    depth_path: PathBuf,
+    file_type: FileType,
+    // Not followed, if a filter needed it.
+    metadata: Option<Metadata>,
+}
+
+// The metadata of the source of a copied file, stat'ed at most once whichever steps of the copy
+// need it. A symlink followed takes a second stat.
+struct SourceMetadata<'a> {
+    fs: &'a dyn FileSystem,
+    path: &'a Path,
+    own: OnceCell<Metadata>,
+    followed: OnceCell<Metadata>,
+}
+
+impl<'a> SourceMetadata<'a> {
+    fn new(fs: &'a dyn FileSystem, path: &'a Path, own: Option<Metadata>) -> Self {
+        Self {
+            fs,
+            path,
+            own: own.map(OnceCell::from).unwrap_or_default(),
+            followed: OnceCell::new(),
+        }
+    }
+
+    // Of the entry itself, also if it is a symlink.
+    fn own(&self) -> Result<&Metadata, io::Error> {
+        if let Some(metadata) = self.own.get() {
+            return Ok(metadata);
+        }
+        let metadata = self.fs.symlink_metadata(self.path)?;
+        Ok(self.own.get_or_init(|| metadata))
+    }
+
+    // Of what a symlink points to, otherwise of the entry.
+    fn followed(&self) -> Result<&Metadata, io::Error> {
+        let own = self.own()?;
+        if !own.file_type.is_symlink() {
+            return Ok(own);
+        }
+        if let Some(metadata) = self.followed.get() {
+            return Ok(metadata);
+        }
+        let metadata = self.fs.metadata(self.path)?;
+        Ok(self.followed.get_or_init(|| metadata))
+    }
+}
+
+// `.name.rfc-tmp` next to the destination file, so the final rename stays on one filesystem.
+fn temp_path(to: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(to.file_name().unwrap_or_default());
+    name.push(".rfc-tmp");
+    to.with_file_name(name)
+}
+
+/// A copy ready to run, see [`CopyBuilder`].
+pub struct Copyer {
+    multi_threads: bool,
+    pool: Option<ThreadPool>,
+    // Any root's context, for what is shared by the whole run.
+    context: Arc<CopyContext>,
+    roots: Vec<CopyRoot>,
+    files_from: Option<Arc<Vec<PathBuf>>>,
+    force: bool,
+    // Totals from the free space check, reused by the progress line.
+    scanned: Option<Arc<ScanTotals>>,
+    // Set when the sources are written into an archive instead.
+    to_archive: Option<ArchiveTarget>,
+    // Set when an archive is extracted instead of copying sources.
+    from_archive: Option<ArchiveSource>,
+}
+
+// One source of the run. A directory copied on the pool has the node tracking its completion.
+struct CopyRoot {
+    context: Arc<CopyContext>,
+    is_dir: bool,
+    node: Option<SharedNodeRef>,
+}
+
+impl Copyer {
+    /// Events of the run as a stream, for async callers; see
+    /// [`CopyBuilder::set_event_writer`] for a blocking alternative. Only the first stream taken
+    /// gets events, later ones end right away.
+    pub fn event_stream(&self) -> EventStream {
+        let (sender, stream) = EventStream::channel();
+        let _ = self.context.stream.set(sender);
+        stream
+    }
+
+    /// Run the copy on a thread of its own, see [`run`](Self::run). The pool still does the
+    /// copying, but no async runtime thread waits for it.
+    pub fn run_async(self) -> CopyTask {
+        let cancel = self.context.cancel.clone();
+        CopyTask::spawn(move || self.run(), cancel)
+    }
+
+    /// Start setting up a copy.
+    pub fn builder() -> CopyBuilder {
+        let local: Arc<dyn FileSystem> = Arc::new(LocalFs);
+        CopyBuilder {
+            multi_threads: false,
+            threads_number: 0,
+            sync: SyncPolicy::None,
+            on_conflict: ConflictPolicy::Overwrite,
+            backup_mode: None,
+            backup_dir: None,
+            moving: false,
+            verify: false,
+            dry_run: false,
+            force: false,
+            compress: None,
+            decompress: false,
+            encrypt: None,
+            decrypt: None,
+            encrypt_names: false,
+            cancel: CancelToken::new(),
+            progress: false,
+            quiet: false,
+            events: None,
+            observers: vec![],
+            filter: Filter::new(),
+            attr_filter: AttrFilter::new(),
+            respect_gitignore: false,
+            files_from: None,
+            source_fs: local.clone(),
+            dest_fs: local,
+            sources: vec![],
+            to: None,
+            to_archive: None,
+            from_archive: None,
+            zip_compression: ZipCompression::Deflate,
+            keep_special_bits: false,
+        }
+    }
+
+    fn start_progress(&self) -> Option<Progress> {
+        if !self.context.progress {
+            return None;
+        }
+        let active = self
+            .pool
+            .as_ref()
+            .map(|pool| (pool.active_counter(), pool.size()));
+        let scan: Box<ScanFn> = match &self.scanned {
+            Some(scanned) => {
+                let scanned = scanned.clone();
+                Box::new(move |totals| totals.add(&scanned))
+            }
+            None => self.scan_fn(|_| true),
+        };
+        Some(Progress::start(self.context.stats.clone(), active, scan))
+    }
+
+    // Count what the copy would take from the roots `include` selects, walking as the copy does.
+    fn scan_fn<F>(&self, include: F) -> Box<ScanFn>
+    where
+        F: Fn(&CopyRoot) -> bool,
+    {
+        match &self.files_from {
+            Some(list) => {
+                let ctx = self.context.clone();
+                let list = list.clone();
+                Box::new(move |totals| {
+                    totals.scan_list(&*ctx.source_fs, &ctx.from, &list, &ctx.cancel)
+                })
+            }
+            None => {
+                let roots: Vec<(Arc<CopyContext>, bool)> = self
+                    .roots
+                    .iter()
+                    .filter(|root| include(root))
+                    .map(|root| (root.context.clone(), root.is_dir))
+                    .collect();
+                Box::new(move |totals| {
+                    for (ctx, is_dir) in roots {
+                        let fs = &*ctx.source_fs;
+                        if !is_dir {
+                            totals.scan_file(fs, &ctx.from);
+                            continue;
+                        }
+                        let excluded_ctx = ctx.clone();
+                        let excluded: Box<ExcludeFn> =
+                            Box::new(move |depth_path, path, file_type, ignores| {
+                                excluded_ctx
+                                    .walked_exclude_reason(depth_path, path, file_type, ignores)
+                                    .is_ok_and(|walked| matches!(walked, Walked::Excluded(_)))
+                            });
+                        totals.scan(fs, &ctx.from, &ctx.cancel, ctx.root_ignores(), &*excluded);
+                    }
+                })
+            }
+        }
+    }
+
+    // Refuse to start a copy the destination has no room for, instead of running out of space
+    // halfway. Moves within one filesystem take no room and are left out.
+    fn check_space(&mut self) -> Result<(), io::Error> {
+        let ctx = self.context.clone();
+        let takes_room =
+            |root: &CopyRoot| !(ctx.moving && ctx.same_device(&root.context.from, &ctx.to));
+        if !self.roots.iter().any(takes_room) {
+            return Ok(());
+        }
+        let space = match ctx.dest_fs.available(&ctx.to) {
+            Ok(space) => space,
+            Err(e) => {
+                warn!("can't check free space on {:?}: {}", ctx.to, e);
+                return Ok(());
+            }
+        };
+        let totals = Arc::new(ScanTotals::default());
+        self.scan_fn(takes_room)(&totals);
+        let inodes = totals.files() + totals.dirs();
+        debug!("copy needs {} bytes and {} inodes, {:?}", totals.bytes(), inodes, space);
+        if let Some(shortage) = space.shortage(totals.bytes(), inodes) {
+            let e = io::Error::new(
+                io::ErrorKind::StorageFull,
+                format!("not enough room ({}), use --force to copy anyway", shortage),
+            );
+            return Err(ctx.report_error(&ctx.to, e));
+        }
+        if self.roots.iter().all(takes_room) {
+            self.scanned = Some(totals);
+        }
+        Ok(())
+    }
+
+    fn start_run(&self, threads: usize) {
+        let sources: Vec<&Path> = self.roots.iter().map(|r| r.context.from.as_path()).collect();
+        self.context.start_run(&sources, threads);
+    }
+
+    // Directories are synced as they complete, `to` itself only is when it is one of them. Like
+    // the sync at the end of the run, a failure fails the run, wherever `to` is synced.
+    fn sync_to(&self) {
+        let ctx = &self.context;
+        if self.roots.iter().any(|r| r.is_dir && r.context.dest == ctx.to) {
+            return;
+        }
+        if let Err(e) = ctx.sync_directory(&ctx.to) {
+            ctx.set_failure(&ctx.to, &e);
+        }
+    }
+
+    fn run_multi_threads(self) -> Result<CopyReport, io::Error> {
+        let pool_ref = self.pool.as_ref().unwrap();
+        self.start_run(pool_ref.size());
+        let progress = self.start_progress();
+        let now = Instant::now();
+        for root in &self.roots {
+            let ctx = &root.context;
+            match &root.node {
+                Some(node) => Self::copy_dir_recursive(
+                    ctx.clone(),
+                    PathBuf::new(),
+                    pool_ref.sender.clone(),
+                    node.clone(),
+                    ctx.root_ignores(),
+                ),
+                // A failed file is reported, the other sources still get copied.
+                None => {
+                    let _ = ctx.copy_file(&ctx.from, &ctx.dest, None);
+                }
+            }
+        }
+
+        if progress.is_none() {
+            self.context.say("Waiting copy stop...");
+        }
+        // This loop check if root directory nodes are copied, which only possible when all
+        // children (and children of children, and so on...) of roots are copied.
+        loop {
+            let copied = self
+                .roots
+                .iter()
+                .filter_map(|root| root.node.as_ref())
+                .all(|node| match node.inner().try_read() {
+                    Ok(reader) => reader.is_copied(),
+                    Err(e) => {
+                        trace!("root node: {:?}", e);
+                        false
+                    }
+                });
+            if copied {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        if let Some(progress) = progress {
+            progress.finish();
+        }
+        if !self.context.cancel.is_cancelled() && !self.context.dry_run {
+            self.context.say("Copy complete.");
+        }
+        self.sync_to();
+        self.context.finish();
+        self.context.finish_run(now.elapsed())
+    }
+
+    fn run_single_threads(self) -> Result<CopyReport, io::Error> {
+        self.start_run(1);
+        let progress = self.start_progress();
+        let now = Instant::now();
+        for root in &self.roots {
+            let ctx = &root.context;
+            if root.is_dir {
+                let ignores = ctx.root_ignores();
+                Self::copy_dir_recursive_single_thread(ctx, &PathBuf::new(), ignores.as_ref());
+                let _ = ctx.dir_complete(&ctx.dest);
+            } else {
+                let _ = ctx.copy_file(&ctx.from, &ctx.dest, None);
+            }
+        }
+        if let Some(progress) = progress {
+            progress.finish();
+        }
+        self.sync_to();
+        self.context.finish();
+        self.context.finish_run(now.elapsed())
+    }
+
+    // Copy the `--files-from` list, each path is a task of its own on the pool.
+    fn run_file_list(self) -> Result<CopyReport, io::Error> {
+        let ctx = &self.context;
+        let list = self.files_from.clone().unwrap();
+        self.start_run(self.pool.as_ref().map_or(1, |pool| pool.size()));
+        let progress = self.start_progress();
+        let now = Instant::now();
+        match &self.pool {
+            Some(pool) => {
+                let pending = Arc::new(AtomicUsize::new(list.len()));
+                for listed in list.iter() {
+                    let task_ctx = ctx.clone();
+                    let task_pending = pending.clone();
+                    let listed = listed.clone();
+                    pool.sender
+                        .send(Message::NewTask(Box::new(move || {
+                            // Errors are already reported, the other paths still get copied.
+                            task_ctx.guard(&listed, || {
+                                let _ = task_ctx.copy_listed(&listed);
+                            });
+                            task_pending.fetch_sub(1, Ordering::SeqCst);
+                        })))
+                        .unwrap();
+                }
+                while pending.load(Ordering::SeqCst) > 0 {
+                    thread::sleep(Duration::from_millis(50));
+                }
+            }
+            None => {
+                for listed in list.iter() {
+                    let _ = ctx.copy_listed(listed);
+                }
+            }
+        }
+        if let Some(progress) = progress {
+            progress.finish();
+        }
+
+        // There is no tree to complete bottom-up here, every directory something was copied into
+        // is synced, and reported complete, once at the end instead.
+        let mut dirs = BTreeSet::new();
+        for listed in list.iter().filter_map(|l| normalize_listed(l)) {
+            let mut dir = listed.parent();
+            while let Some(d) = dir {
+                dirs.insert(ctx.dest_path(d));
+                dir = d.parent();
+            }
+        }
+        for dir in dirs.iter().rev() {
+            let _ = ctx.sync_directory(dir);
+            ctx.notify(|o| o.on_dir_complete(dir));
+        }
+        if !ctx.cancel.is_cancelled() && !ctx.dry_run {
+            ctx.say("Copy complete.");
+        }
+        ctx.finish();
+        ctx.finish_run(now.elapsed())
+    }
+
+    // Move whole sources with a single rename where possible. Across filesystems, onto a
+    // directory that isn't empty, or with filters leaving entries behind, a source is moved
+    // entry by entry instead.
+    fn rename_roots(&mut self) {
+        self.roots.retain(|root| {
+            let ctx = &root.context;
+            if ctx.keep_from || ctx.selects_entries() {
+                return true;
+            }
+            // An existing file goes through the conflict policy.
+            if !root.is_dir && ctx.dest_fs.symlink_metadata(&ctx.dest).is_ok() {
+                return true;
+            }
+            let renamed = if ctx.dry_run {
+                Self::can_rename(ctx, root.is_dir)
+            } else if ctx.same_fs() {
+                ctx.dest_fs.rename(&ctx.from, &ctx.dest)
+            } else {
+                Err(io::Error::other("not on one filesystem"))
+            };
+            match renamed {
+                Ok(()) => {
+                    ctx.plan(|| {
+                        format!("rename {} to {}", ctx.from.display(), ctx.dest.display())
+                    });
+                    info!("renamed {:?} to {:?}", ctx.from, ctx.dest);
+                    ctx.stats.add_moved();
+                    ctx.emit(|| Event::SourceRenamed {
+                        from: ctx.from.to_string_lossy().into_owned(),
+                        path: ctx.relative(&ctx.dest),
+                    });
+                    false
+                }
+                Err(e) => {
+                    debug!("can't rename {:?}: {}, moving entries", ctx.from, e);
+                    true
+                }
+            }
+        });
+    }
+
+    // What decides whether `rename` would work, checked without trying it: both ends on one
+    // filesystem, and nothing but an empty directory in the way.
+    fn can_rename(ctx: &CopyContext, is_dir: bool) -> Result<(), io::Error> {
+        if !ctx.same_device(&ctx.from, &ctx.dest) {
+            return Err(io::Error::other("not on one filesystem"));
+        }
+        if is_dir && ctx.dest_fs.read_dir(&ctx.dest).is_ok_and(|mut e| e.next().is_some()) {
+            return Err(io::Error::other("destination not empty"));
+        }
+        Ok(())
+    }
+
+    // A dry run creates no directories, the missing ones the copy needs are noted up front.
+    fn plan_roots(&self) {
+        let ctx = &self.context;
+        let mut missing = BTreeSet::new();
+        for root in &self.roots {
+            let dest = &root.context.dest;
+            let dir = if root.is_dir { dest } else { dest.parent().unwrap_or(dest) };
+            if !ctx.dest_fs.is_dir(dir) {
+                missing.insert(dir.to_path_buf());
+            }
+        }
+        for dir in missing {
+            ctx.plan(|| format!("create {}/", dir.display()));
+        }
+    }
+
+    /// Copy, and report what was done. Errs if the run was stopped by an error, or the
+    /// destination couldn't be synced at the end; errors on single entries are reported and
+    /// counted in [`CopyReport::errors`], and the rest is still copied.
+    pub fn run(mut self) -> Result<CopyReport, Error> {
+        if self.to_archive.is_some() {
+            return Ok(self.run_to_archive()?);
+        }
+        if self.from_archive.is_some() {
+            if !self.force {
+                self.check_space()?;
+            }
+            return Ok(self.run_from_archive()?);
+        }
+        if self.context.dry_run {
+            self.plan_roots();
+        }
+        if self.context.moving && self.files_from.is_none() {
+            self.rename_roots();
+        }
+        if !self.force && !self.context.dry_run {
+            self.check_space()?;
+        }
+        let report = if self.files_from.is_some() {
+            self.run_file_list()
+        } else if self.multi_threads {
+            self.run_multi_threads()
+        } else {
+            self.run_single_threads()
+        };
+        Ok(report?)
+    }
+
+    // Copy in chunks so that a cancel request is noticed, and progress is counted, inside big
+    // files too. Returns the written file, so the caller can decide whether to sync it, and the
+    // number of bytes copied.
+    fn copy_file(
+        source_fs: &dyn FileSystem,
+        dest_fs: &dyn FileSystem,
+        from: &Path,
+        to: &Path,
+        cancel: &CancelToken,
+        stats: &CopyStats,
+    ) -> Result<(Box<dyn WriteFile>, u64), io::Error> {
+        Self::copy_stream(source_fs.open(from)?, dest_fs, to, cancel, stats)
+    }
+
+    // `copy_file` from any reader.
+    fn copy_stream(
+        reader: impl Read,
+        dest_fs: &dyn FileSystem,
+        to: &Path,
+        cancel: &CancelToken,
+        stats: &CopyStats,
+    ) -> Result<(Box<dyn WriteFile>, u64), io::Error> {
+        let mut file = dest_fs.create(to)?;
+        let bytes = Self::copy_into(reader, &mut file, cancel, stats)?;
+        Ok((file, bytes))
+    }
+
+    // All of `reader` into `writer`, counting what is read as written.
+    fn copy_into(
+        mut reader: impl Read,
+        writer: &mut impl Write,
+        cancel: &CancelToken,
+        stats: &CopyStats,
+    ) -> Result<u64, io::Error> {
+        let mut buf = vec![0u8; COPY_BUFFER_SIZE];
+        let mut bytes = 0;
+        loop {
+            if cancel.is_cancelled() {
+                return Err(io::Error::new(io::ErrorKind::Interrupted, "copy cancelled"));
+            }
+            let n = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            writer.write_all(&buf[..n])?;
+            stats.add_written(n as u64);
+            bytes += n as u64;
+        }
+        Ok(bytes)
+    }
+
+    fn copy_dir_recursive_single_thread(
+        ctx: &CopyContext,
+        depth_path: &Path,
+        ignores: Option<&Arc<IgnoreStack>>,
+    ) {
+        ctx.walk_dir(depth_path, ignores, false, |entry, ignores| {
+            let creating_path = match ctx.try_dest_path(&entry.depth_path) {
+                Ok(creating_path) => creating_path,
+                Err(e) => {
+                    ctx.report_error(&entry.path, e);
+                    return;
+                }
+            };
+            if entry.file_type.is_dir() {
+                if ctx.create_dir(&creating_path).is_err() {
+                    return;
+                }
+                Self::copy_dir_recursive_single_thread(ctx, &entry.depth_path, ignores);
+                let _ = ctx.dir_complete(&creating_path);
+            } else {
+                debug!("create file {:?}", creating_path);
+                let _ = ctx.copy_file(&entry.path, &creating_path, entry.metadata);
+            }
+        });
+    }
+
+    // Copy the directory of `parent_node` on the pool, its subdirectories becoming tasks of
+    // their own. The node is completed whatever happens inside, the run waits for it.
+    fn copy_dir_recursive(
+        ctx: Arc<CopyContext>,
+        depth_path: PathBuf,
+        sender: Sender<Message>,
+        parent_node: SharedNodeRef,
+        ignores: Option<Arc<IgnoreStack>>,
+    ) {
+        ctx.guard(&ctx.from.join(&depth_path), || {
+            Self::copy_dir_entries(&ctx, &depth_path, &sender, &parent_node, ignores)
+        });
+
+        let mut writer = parent_node.inner().write().unwrap();
+        trace!("start lookup {:?}", writer.path());
+        writer.finish_listing();
+        let copied = writer.set_copied(); //当前node的父node检查
+        drop(writer);
+
+        if copied {
+            // A failed sync is reported, the directories above still complete.
+            DirNode::try_lookup_continuously(parent_node, |path| {
+                let _ = ctx.dir_complete(path);
+            });
+        }
+    }
+
+    fn copy_dir_entries(
+        ctx: &Arc<CopyContext>,
+        depth_path: &Path,
+        sender: &Sender<Message>,
+        parent_node: &SharedNodeRef,
+        ignores: Option<Arc<IgnoreStack>>,
+    ) {
+        ctx.walk_dir(depth_path, ignores.as_ref(), false, |entry, ignores| {
+            let creating_path = match ctx.try_dest_path(&entry.depth_path) {
+                Ok(creating_path) => creating_path,
+                Err(e) => {
+                    ctx.report_error(&entry.path, e);
+                    return;
+                }
+            };
+            if !entry.file_type.is_dir() {
+                debug!("create file {:?}", creating_path);
+                let _ = ctx.copy_file(&entry.path, &creating_path, entry.metadata);
+                return;
+            }
+            if ctx.create_dir(&creating_path).is_err() {
+                return;
+            }
+
+            // Create new node for directory in this loop, and then attach it to directory tree and
+            // set parent for it.
+            trace!("creating new node for path {:?}", creating_path);
+            let mut node = DirNode::new(creating_path);
+            node.set_parent(parent_node.clone());
+            node.set_listing();
+            let node_r = SharedNodeRef::new(node);
+
+            trace!("add new node to parent");
+            let mut writer = parent_node.inner().write().unwrap();
+            writer.add_sub_nodes(node_r.clone());
+            drop(writer);
+
+            trace!("attach node to tree done");
+
+            let new_ctx = ctx.clone();
+            let new_depth_path = entry.depth_path;
+            let new_sender = sender.clone();
+            let new_ignores = ignores.cloned();
+
+            //For directory under this directory, make it as a new task to pool.
+            sender
+                .send(Message::NewTask(Box::new(move || {
+                    Self::copy_dir_recursive(
+                        new_ctx,
+                        new_depth_path,
+                        new_sender,
+                        node_r,
+                        new_ignores,
+                    );
+                })))
+                .unwrap();
+        });
+    }
+}
+#[cfg(test)]
+mod copy_test {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn copy_file_test() {
+        let from = Path::new("./test_dir/copy_test_dir/origin_file");
+        let to = Path::new("./test_dir/copy_test_dir/copied_file1");
+        let (cancel, stats) = (CancelToken::new(), CopyStats::new());
+        Copyer::copy_file(&LocalFs, &LocalFs, from, to, &cancel, &stats).unwrap();
+        let content_from = fs::read_to_string(from).unwrap();
+        let content_to = fs::read_to_string(to).unwrap();
+        assert_eq!(content_to, content_from);
+    }
+
+    #[test]
+    fn files_equal_test() {
+        let dir = Path::new("./test_dir/copy_test_dir");
+        let (a, b) = (dir.join("equal_a"), dir.join("equal_b"));
+        fs::write(&a, "same").unwrap();
+        fs::write(&b, "same").unwrap();
+        let equal = |a: &Path, b: &Path| {
+            files_equal(fs::File::open(a).unwrap(), fs::File::open(b).unwrap()).unwrap()
+        };
+        assert!(equal(&a, &b));
+        fs::write(&b, "same, longer").unwrap();
+        assert!(!equal(&a, &b));
+        assert!(!equal(&b, &a));
+    }
+
+    #[test]
+    fn dry_run_test() {
+        let out = Path::new("./test_dir/dry_run_test/out");
+        let _ = fs::remove_dir_all(out.parent().unwrap());
+        let copyer = Copyer::builder()
+            .set_from("./test_dir/copy_test_dir")
+            .set_to(out.to_str().unwrap())
+            .set_threads_number(2)
+            .set_dry_run(true)
+            .set_quiet(true)
+            .build()
+            .unwrap();
+        let report = copyer.run().unwrap();
+        assert!(!out.parent().unwrap().exists());
+        assert!(report.dry_run && report.files > 0);
+        assert_eq!(report.bytes_needed, report.bytes);
+    }
+
+    #[test]
+    fn resolve_roots_test() {
+        let base = fs::canonicalize("./test_dir/copy_test_dir").unwrap();
+        let src = base.join("origin_file");
+        let dir = base.to_str().unwrap();
+        let out = base.parent().unwrap().join("resolve_roots_test");
+        let _ = fs::remove_dir_all(&out);
+        let out_str = out.to_str().unwrap();
+        let resolve = |sources: &[String], to: &str| {
+            CopyBuilder::resolve_roots(&LocalFs, &LocalFs, sources, to, true)
+        };
+
+        // A single source to a new path becomes that path.
+        let (to, roots) = resolve(&[String::from(dir)], out_str).unwrap();
+        assert_eq!(to, out);
+        assert_eq!(
+            roots,
+            vec![ResolvedRoot {
+                from: base.clone(),
+                dest: out.clone(),
+                is_dir: true,
+                contents: false,
+                own_name: false,
+            }]
+        );
+
+        fs::create_dir_all(&out).unwrap();
+        // `out` exists now: the directory goes inside, with a trailing `/` only its contents.
+        let sources = [String::from(dir), format!("{}/", dir)];
+        let (_, roots) = resolve(&sources, out_str).unwrap();
+        assert_eq!(roots[0].dest, out.join("copy_test_dir"));
+        assert_eq!(roots[1].dest, out);
+        assert!(!roots[0].contents && roots[1].contents);
+
+        let file = [String::from(src.to_str().unwrap())];
+        let (_, roots) = resolve(&file, out_str).unwrap();
+        assert_eq!(
+            roots,
+            vec![ResolvedRoot {
+                from: src.clone(),
+                dest: out.join("origin_file"),
+                is_dir: false,
+                contents: false,
+                own_name: true,
+            }]
+        );
+        let renamed = out.join("renamed");
+        let renamed_str = renamed.to_str().unwrap();
+        let (to, roots) = resolve(&file, renamed_str).unwrap();
+        assert_eq!(to, out);
+        assert_eq!(roots[0].dest, renamed);
+
+        let inside = base.join("inside");
+        let inside = inside.to_str().unwrap();
+        assert!(resolve(&[String::from(dir)], inside).is_err());
     }
 }
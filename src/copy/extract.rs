@@ -0,0 +1,541 @@
+use super::{temp_path, ArchiveFormat, CopyBuilder, CopyContext, CopyRoot, Copyer};
+use super::COPY_BUFFER_SIZE;
+use crate::conflict::Decision;
+use crate::error::Error;
+use crate::file_system::{FileSystem, Xattrs};
+use crate::files_from::normalize_listed;
+use crate::output::Event;
+use crate::pool::{Message, ThreadPool};
+use crate::report::CopyReport;
+use log::{debug, warn};
+use std::collections::{BTreeMap, HashSet};
+use std::io::{self, BufReader, Read};
+use std::mem;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Instant, SystemTime};
+
+// How much read data, and how many tasks, may wait for a worker before reading stops.
+const IN_FLIGHT_BYTES: u64 = 64 * 1024 * 1024;
+const IN_FLIGHT_FILES: usize = 4096;
+
+// Where the archive comes from: a file, or for tar stdin when there is no path.
+#[derive(Clone)]
+pub(super) struct ArchiveSource {
+    path: Option<PathBuf>,
+    format: ArchiveFormat,
+}
+
+impl ArchiveSource {
+    fn open(&self, fs: &dyn FileSystem) -> Result<Box<dyn Read + Send>, io::Error> {
+        match &self.path {
+            Some(path) => fs.open(path),
+            None => Ok(Box::new(io::stdin())),
+        }
+    }
+}
+
+impl CopyBuilder {
+    // Settings for `set_from_tar` and `set_from_zip`. The archive takes the place of the
+    // sources, `to` is where it is extracted.
+    pub(super) fn build_from_archive(self) -> Result<Copyer, Error> {
+        let (format, archive) = self.from_archive.clone().unwrap();
+        let unsupported = [
+            (!self.sources.is_empty(), "sources"),
+            (self.moving, "moving"),
+            (self.dry_run, "a dry run"),
+            (self.verify, "--verify"),
+            (self.compress.is_some(), "--compress"),
+            (self.decompress, "--decompress"),
+            (self.encrypt.is_some(), "--encrypt"),
+            (self.decrypt.is_some(), "--decrypt"),
+            (self.encrypt_names, "--encrypt-names"),
+            (self.files_from.is_some(), "--files-from"),
+            (!self.attr_filter.is_empty(), "attribute filters"),
+            (self.respect_gitignore, "--respect-gitignore"),
+        ];
+        if let Some((_, setting)) = unsupported.iter().find(|(set, _)| *set) {
+            return Err(Error::Config(format!(
+                "--from-{} can't be combined with {}.",
+                format, setting
+            )));
+        }
+        // Zip entries are found through the directory at the end of the archive.
+        if format == ArchiveFormat::Zip && archive == "-" {
+            return Err("--from-zip can't read from stdin, a zip archive has to be a file.".into());
+        }
+        let to = self.to.clone().ok_or("Not set target path.")?;
+        let abs_to = Self::absolute(&*self.dest_fs, Path::new(&to))
+            .map_err(|_| "Preprocess to param failed")?;
+        let path = match archive.as_str() {
+            "-" => None,
+            path => Some(
+                self.source_fs
+                    .canonicalize(Path::new(path))
+                    .map_err(|_| format!("Preprocess --from-{} path failed", format))
+                    .map_err(Error::Config)?,
+            ),
+        };
+        if self.dest_fs.create_dir_all(&abs_to).is_err() {
+            return Err("Create to directory failed".into());
+        }
+
+        let context = Arc::new(CopyContext {
+            from: path.clone().unwrap_or_else(|| PathBuf::from("-")),
+            dest: abs_to.clone(),
+            ..self.context(abs_to, self.backup_policy()?)
+        });
+        Ok(Copyer {
+            multi_threads: self.multi_threads,
+            pool: self.pool(),
+            context: context.clone(),
+            roots: vec![CopyRoot {
+                context,
+                is_dir: false,
+                node: None,
+            }],
+            files_from: None,
+            force: self.force,
+            scanned: None,
+            to_archive: None,
+            from_archive: Some(ArchiveSource { path, format }),
+        })
+    }
+}
+
+impl Copyer {
+    pub(super) fn run_from_archive(self) -> Result<CopyReport, io::Error> {
+        let ctx = self.context.clone();
+        let source = self.from_archive.clone().unwrap();
+        self.start_run(self.pool.as_ref().map_or(1, |pool| pool.size()));
+        let progress = self.start_progress();
+        let now = Instant::now();
+        let mut extractor = Extractor::new(&ctx, self.pool.as_ref());
+        let extracted = match (source.format, &source.path) {
+            (ArchiveFormat::Zip, Some(path)) => extractor.extract_zip(path),
+            (_, _) => source.open(&*ctx.source_fs).map(|reader| {
+                extractor.extract_tar(BufReader::with_capacity(COPY_BUFFER_SIZE, reader))
+            }),
+        };
+        if let Err(e) = extracted {
+            ctx.fail(&ctx.from, e);
+        }
+        extractor.finish();
+        if let Some(progress) = progress {
+            progress.finish();
+        }
+        if !ctx.cancel.is_cancelled() {
+            ctx.say("Extraction complete.");
+        }
+        ctx.finish();
+        ctx.finish_run(now.elapsed())
+    }
+}
+
+// Extracts the archive's entries. Files are written as they come, on the pool when there is
+// one; links are made once every file is written, so nothing from the archive is written
+// through a link the archive made.
+pub(super) struct Extractor<'a> {
+    pub(super) ctx: &'a Arc<CopyContext>,
+    pub(super) pool: Option<&'a ThreadPool>,
+    in_flight: Arc<InFlight>,
+    // Every directory extracted into, with the attributes the archive gives it. They are set
+    // last, as a read-only directory couldn't be filled.
+    dirs: BTreeMap<PathBuf, Option<Attrs>>,
+    // Directories known to be real ones, not links, below the destination.
+    checked: HashSet<PathBuf>,
+    links: Vec<(PathBuf, Link, Attrs)>,
+}
+
+enum Link {
+    Symlink(PathBuf),
+    // The original, in the destination.
+    Hard(PathBuf),
+}
+
+impl<'a> Extractor<'a> {
+    fn new(ctx: &'a Arc<CopyContext>, pool: Option<&'a ThreadPool>) -> Self {
+        Self {
+            ctx,
+            pool,
+            in_flight: Arc::new(InFlight::default()),
+            dirs: BTreeMap::new(),
+            checked: HashSet::new(),
+            links: vec![],
+        }
+    }
+
+    // Where the entry `path` of the archive goes, or None if it is left out. Entries that would
+    // end up outside the destination are skipped, as are those the filters exclude.
+    pub(super) fn destination(&self, path: &Path, is_dir: bool) -> Option<PathBuf> {
+        let ctx = self.ctx;
+        let relative = match normalize_listed(path) {
+            Some(relative) => relative,
+            // `./`, the destination itself.
+            None if path.components().all(|c| c == Component::CurDir) => return None,
+            None => {
+                warn!("{:?} is outside the destination", path);
+                ctx.skip(path, "outside the destination");
+                return None;
+            }
+        };
+        let to = ctx.dest.join(&relative);
+        if ctx.filter.is_excluded(&relative, is_dir) {
+            ctx.skip(&to, "excluded");
+            return None;
+        }
+        // What is below an excluded directory isn't walked into when copying either.
+        let mut parents = relative.ancestors().skip(1);
+        if parents.any(|p| !p.as_os_str().is_empty() && ctx.filter.is_excluded(p, true)) {
+            debug!("{:?} is in an excluded directory", to);
+            return None;
+        }
+        Some(to)
+    }
+
+    pub(super) fn add_dir(&mut self, to: PathBuf, attrs: Attrs) {
+        let relative = to.strip_prefix(&self.ctx.dest).unwrap().to_path_buf();
+        match self.make_dirs(&relative, true) {
+            Ok(()) => {
+                self.dirs.insert(to, Some(attrs));
+            }
+            Err(e) => {
+                self.ctx.report_error(&to, e);
+            }
+        }
+    }
+
+    // Create the directories `to` goes into. If they can't be, that is reported and false
+    // returned.
+    pub(super) fn make_parents(&mut self, to: &Path) -> bool {
+        let relative = to.strip_prefix(&self.ctx.dest).unwrap();
+        match self.make_dirs(relative.parent().unwrap(), true) {
+            Ok(()) => true,
+            Err(e) => {
+                self.ctx.report_error(to, e);
+                false
+            }
+        }
+    }
+
+    pub(super) fn add_symlink(&mut self, to: PathBuf, target: PathBuf, attrs: Attrs) {
+        self.links.push((to, Link::Symlink(target), attrs));
+    }
+
+    // `target` is the name of the original in the archive.
+    pub(super) fn add_hard_link(&mut self, to: PathBuf, target: &Path, attrs: Attrs) {
+        match normalize_listed(target) {
+            Some(original) => {
+                let original = self.ctx.dest.join(original);
+                self.links.push((to, Link::Hard(original), attrs));
+            }
+            None => {
+                warn!("{:?} links to {:?}, outside the destination", to, target);
+                self.ctx.skip(&to, "link outside the destination");
+            }
+        }
+    }
+
+    // Run `task`, writing the files `dests`, on the pool, with `bytes` of read data it holds
+    // counted as in flight until it is done; a panic in it is reported on `path`. An archive can
+    // hold a path twice, so the task waits for earlier ones writing any of `dests`, and the later
+    // entry is the one that lands. Without a pool it runs right away.
+    pub(super) fn spawn<F>(&self, path: PathBuf, dests: Vec<PathBuf>, bytes: u64, task: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        match self.pool {
+            Some(pool) => {
+                self.in_flight.acquire(bytes, &dests);
+                let (ctx, in_flight) = (self.ctx.clone(), self.in_flight.clone());
+                let task = move || {
+                    ctx.guard(&path, task);
+                    in_flight.release(bytes, &dests);
+                };
+                pool.sender.send(Message::NewTask(Box::new(task))).unwrap();
+            }
+            None => task(),
+        }
+    }
+
+    // Wait until no task on the pool writes `to`, before writing it here.
+    pub(super) fn wait_for(&self, to: &Path) {
+        self.in_flight.wait_for(to);
+    }
+
+    // Make sure each directory of `relative`, below the destination, is a real directory, so
+    // nothing is written through a symlink. Missing ones are created if `create` is set.
+    fn make_dirs(&mut self, relative: &Path, create: bool) -> Result<(), io::Error> {
+        let ctx = self.ctx;
+        let mut dir = ctx.dest.clone();
+        for component in relative.components() {
+            dir.push(component);
+            if self.checked.contains(&dir) {
+                continue;
+            }
+            match ctx.dest_fs.symlink_metadata(&dir) {
+                Ok(metadata) if metadata.file_type.is_dir() => {}
+                Ok(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotADirectory,
+                        format!("{} is not a directory", ctx.relative(&dir)),
+                    ))
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound && create => {
+                    debug!("create dir {:?}", dir);
+                    ctx.dest_fs.create_dir_all(&dir)?;
+                }
+                Err(e) => return Err(e),
+            }
+            self.checked.insert(dir.clone());
+            if !self.dirs.contains_key(&dir) {
+                ctx.stats.add_dir();
+                ctx.emit(|| Event::DirCreated {
+                    path: ctx.relative(&dir),
+                });
+                ctx.notify(|o| o.on_dir_start(&dir));
+                self.dirs.insert(dir.clone(), None);
+            }
+        }
+        Ok(())
+    }
+
+    // Wait for the pool, then make the links and finish the directories.
+    fn finish(mut self) {
+        let ctx = self.ctx;
+        self.in_flight.wait_idle();
+        // A link may now stand where a directory was checked, e.g. when a directory was backed
+        // up to make room for it; check again.
+        self.checked.clear();
+        for (to, link, attrs) in mem::take(&mut self.links) {
+            if ctx.cancel.is_cancelled() {
+                break;
+            }
+            let relative = to.strip_prefix(&ctx.dest).unwrap();
+            if let Err(e) = self.make_dirs(relative.parent().unwrap(), true) {
+                ctx.report_error(&to, e);
+                continue;
+            }
+            let fs = &ctx.dest_fs;
+            let _ = match link {
+                Link::Symlink(target) => ctx.extract(&to, attrs.modified, |temp| {
+                    fs.symlink(&target, temp)?;
+                    Ok(fs.symlink_metadata(temp)?.len)
+                }),
+                Link::Hard(original) => {
+                    let relative = original.strip_prefix(&ctx.dest).unwrap();
+                    if let Err(e) = self.make_dirs(relative.parent().unwrap(), false) {
+                        ctx.report_error(&to, e);
+                        continue;
+                    }
+                    ctx.extract(&to, attrs.modified, |temp| {
+                        fs.hard_link(&original, temp)?;
+                        Ok(fs.symlink_metadata(temp)?.len)
+                    })
+                }
+            };
+        }
+        if ctx.cancel.is_cancelled() {
+            return;
+        }
+        // Deepest first, as a directory's attributes include its modification time.
+        for (dir, attrs) in mem::take(&mut self.dirs).into_iter().rev() {
+            if let Some(attrs) = attrs {
+                if let Err(e) = attrs.apply(ctx, &dir) {
+                    ctx.report_error(&dir, e);
+                }
+            }
+            if ctx.sync_directory(&dir).is_ok() {
+                ctx.notify(|o| o.on_dir_complete(&dir));
+            }
+        }
+        match ctx.sync_directory(&ctx.dest) {
+            Ok(()) => ctx.notify(|o| o.on_dir_complete(&ctx.dest)),
+            Err(e) => ctx.set_failure(&ctx.dest, &e),
+        }
+    }
+}
+
+impl CopyContext {
+    // Put an archive entry at `to` like `copy_file` puts a file: `write` makes it under a
+    // temporary name, which then takes the place the conflict policy decides on. `modified` is
+    // the entry's, for `--on-conflict newer`.
+    pub(super) fn extract<F>(
+        &self,
+        to: &Path,
+        modified: Option<SystemTime>,
+        write: F,
+    ) -> Result<(), io::Error>
+    where
+        F: FnOnce(&Path) -> Result<u64, io::Error>,
+    {
+        let now = Instant::now();
+        let decision = match self.dest_fs.symlink_metadata(to) {
+            Ok(existing) => Some(self.conflicts.decide(to, || {
+                matches!((modified, existing.modified), (Some(m), Some(e)) if m > e)
+            })),
+            Err(_) => None,
+        };
+        match decision {
+            Some(Decision::Skip) => {
+                debug!("{:?} exists, kept", to);
+                self.conflict(to, Decision::Skip, None);
+                return Ok(());
+            }
+            Some(Decision::Fail) => {
+                self.conflict(to, Decision::Fail, None);
+                let e = io::Error::new(io::ErrorKind::AlreadyExists, "destination exists");
+                self.fail(to, e);
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        let temp = temp_path(to);
+        // Left over from an earlier run, links can't be made over it.
+        let _ = self.dest_fs.remove_file(&temp);
+        match write(&temp).and_then(|bytes| self.place(&temp, to, decision).map(|d| (bytes, d))) {
+            Ok((bytes, dest)) => {
+                self.file_placed(to, &dest, decision, bytes, now.elapsed());
+                Ok(())
+            }
+            Err(e) => self.file_failed(to, &temp, e),
+        }
+    }
+
+    pub(super) fn write_extracted(
+        &self,
+        data: impl Read,
+        temp: &Path,
+        attrs: &Attrs,
+    ) -> Result<u64, io::Error> {
+        let (mut file, bytes) =
+            Copyer::copy_stream(data, &*self.dest_fs, temp, &self.cancel, &self.stats)?;
+        if self.sync.syncs_files() {
+            self.timed_sync(|| file.sync_all())?;
+        }
+        drop(file);
+        attrs.apply(self, temp)?;
+        Ok(bytes)
+    }
+}
+
+// What is restored of an entry besides its data. Owners aren't, that takes root.
+pub(super) struct Attrs {
+    pub(super) mode: u32,
+    pub(super) modified: Option<SystemTime>,
+    pub(super) xattrs: Xattrs,
+}
+
+impl Attrs {
+    // Permissions go last, they may take away the right to set the rest. An xattr that can't
+    // be set, like one of another namespace, is only warned about.
+    fn apply(&self, ctx: &CopyContext, path: &Path) -> Result<(), io::Error> {
+        let fs = &*ctx.dest_fs;
+        for (name, value) in &self.xattrs {
+            if let Err(e) = fs.set_xattr(path, name, value) {
+                warn!("{:?}: can't set xattr {:?}: {}", path, name, e);
+            }
+        }
+        if let Some(modified) = self.modified {
+            match fs.set_modified(path, modified) {
+                Err(e) if e.kind() != io::ErrorKind::Unsupported => return Err(e),
+                _ => {}
+            }
+        }
+        let mode = if ctx.keep_special_bits {
+            self.mode
+        } else {
+            self.mode & 0o777
+        };
+        fs.set_permissions(path, mode)
+    }
+}
+
+// Tasks handed to the pool, the bytes they hold and the files they write, so reading the
+// archive doesn't run far ahead of the writes, nor two tasks write one file.
+#[derive(Default)]
+struct InFlight {
+    state: Mutex<InFlightState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct InFlightState {
+    tasks: usize,
+    bytes: u64,
+    dests: HashSet<PathBuf>,
+}
+
+impl InFlight {
+    fn acquire(&self, bytes: u64, dests: &[PathBuf]) {
+        let mut state = self.state.lock().unwrap();
+        while state.tasks >= IN_FLIGHT_FILES
+            || (state.tasks > 0 && state.bytes + bytes > IN_FLIGHT_BYTES)
+            || dests.iter().any(|dest| state.dests.contains(dest))
+        {
+            state = self.changed.wait(state).unwrap();
+        }
+        state.tasks += 1;
+        state.bytes += bytes;
+        state.dests.extend(dests.iter().cloned());
+    }
+
+    fn release(&self, bytes: u64, dests: &[PathBuf]) {
+        let mut state = self.state.lock().unwrap();
+        state.tasks -= 1;
+        state.bytes -= bytes;
+        for dest in dests {
+            state.dests.remove(dest);
+        }
+        self.changed.notify_all();
+    }
+
+    fn wait_for(&self, dest: &Path) {
+        let mut state = self.state.lock().unwrap();
+        while state.dests.contains(dest) {
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    fn wait_idle(&self) {
+        let mut state = self.state.lock().unwrap();
+        while state.tasks > 0 {
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn in_flight_test() {
+        let in_flight = Arc::new(InFlight::default());
+        in_flight.acquire(IN_FLIGHT_BYTES, &[]);
+        let waiter = in_flight.clone();
+        let handle = thread::spawn(move || {
+            // Doesn't fit beside the first one.
+            waiter.acquire(1, &[]);
+            waiter.release(1, &[]);
+        });
+        thread::sleep(Duration::from_millis(20));
+        assert!(!handle.is_finished());
+        in_flight.release(IN_FLIGHT_BYTES, &[]);
+        handle.join().unwrap();
+        in_flight.wait_idle();
+
+        // Nor does a task writing the same file.
+        let dest = [PathBuf::from("/out/a")];
+        in_flight.acquire(0, &dest);
+        let waiter = in_flight.clone();
+        let handle = thread::spawn(move || waiter.wait_for(Path::new("/out/a")));
+        thread::sleep(Duration::from_millis(20));
+        assert!(!handle.is_finished());
+        in_flight.release(0, &dest);
+        handle.join().unwrap();
+    }
+}
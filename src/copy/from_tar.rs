@@ -0,0 +1,139 @@
+use super::extract::{Attrs, Extractor};
+use log::warn;
+use std::ffi::OsString;
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+// Files up to this size are read from the archive and written on the pool, bigger ones are
+// written while they are read.
+const PARALLEL_LIMIT: u64 = 1024 * 1024;
+
+impl Extractor<'_> {
+    // The entries in the order of the archive, which is read once front to back.
+    pub(super) fn extract_tar(&mut self, reader: impl Read) {
+        let ctx = self.ctx;
+        let mut archive = tar::Archive::new(reader);
+        let entries = match archive.entries() {
+            Ok(entries) => entries,
+            Err(e) => return ctx.fail(&ctx.from, e),
+        };
+        for entry in entries {
+            if ctx.cancel.is_cancelled() {
+                break;
+            }
+            // Past a broken entry there is no telling where the next one starts.
+            if let Err(e) = entry.and_then(|entry| self.add_tar_entry(entry)) {
+                ctx.fail(&ctx.from, e);
+                break;
+            }
+        }
+    }
+
+    // Errors are the archive's; those of extracting the entry are reported here.
+    fn add_tar_entry<R: Read>(&mut self, mut entry: tar::Entry<R>) -> Result<(), io::Error> {
+        let ctx = self.ctx;
+        let path = entry.path()?.into_owned();
+        let entry_type = entry.header().entry_type();
+        let to = match self.destination(&path, entry_type.is_dir()) {
+            Some(to) => to,
+            None => return Ok(()),
+        };
+        let attrs = Attrs::of_tar(&mut entry)?;
+
+        if entry_type.is_dir() {
+            self.add_dir(to, attrs);
+            return Ok(());
+        }
+        if !self.make_parents(&to) {
+            return Ok(());
+        }
+        if entry_type.is_symlink() || entry_type.is_hard_link() {
+            let target = match entry.link_name()? {
+                Some(target) => target.into_owned(),
+                None => {
+                    let e = io::Error::new(io::ErrorKind::InvalidData, "link without a target");
+                    ctx.report_error(&to, e);
+                    return Ok(());
+                }
+            };
+            if entry_type.is_symlink() {
+                self.add_symlink(to, target, attrs);
+            } else {
+                self.add_hard_link(to, &target, attrs);
+            }
+            return Ok(());
+        }
+        if !(entry_type.is_file() || entry_type.is_contiguous() || entry_type.is_gnu_sparse()) {
+            warn!("{:?} is a {:?}, not extracted", to, entry_type);
+            ctx.skip(&to, "unsupported file type");
+            return Ok(());
+        }
+
+        let size = entry.size();
+        if self.pool.is_some() && size <= PARALLEL_LIMIT {
+            let mut data = Vec::with_capacity(size as usize);
+            entry.read_to_end(&mut data)?;
+            let ctx = ctx.clone();
+            self.spawn(to.clone(), vec![to.clone()], size, move || {
+                let write = |temp: &Path| ctx.write_extracted(&data[..], temp, &attrs);
+                let _ = ctx.extract(&to, attrs.modified, write);
+            });
+        } else {
+            self.wait_for(&to);
+            let mut reader = ArchiveReader {
+                inner: entry,
+                error: None,
+            };
+            let write = |temp: &Path| ctx.write_extracted(&mut reader, temp, &attrs);
+            let _ = ctx.extract(&to, attrs.modified, write);
+            if let Some(e) = reader.error {
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Attrs {
+    fn of_tar<R: Read>(entry: &mut tar::Entry<R>) -> Result<Self, io::Error> {
+        let header = entry.header();
+        let mode = header.mode()? & 0o7777;
+        let modified = header
+            .mtime()
+            .ok()
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+        let mut xattrs = vec![];
+        if let Some(pax) = entry.pax_extensions()? {
+            for extension in pax {
+                let extension = extension?;
+                let name = extension.key().ok().and_then(|k| k.strip_prefix("SCHILY.xattr."));
+                if let Some(name) = name {
+                    xattrs.push((OsString::from(name), extension.value_bytes().to_vec()));
+                }
+            }
+        }
+        Ok(Self {
+            mode,
+            modified,
+            xattrs,
+        })
+    }
+}
+
+// Keeps errors reading the archive apart from those writing the entry, as only the first stop
+// the extraction.
+struct ArchiveReader<R> {
+    inner: R,
+    error: Option<io::Error>,
+}
+
+impl<R: Read> Read for ArchiveReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf).map_err(|e| {
+            let copy = io::Error::new(e.kind(), e.to_string());
+            self.error = Some(e);
+            copy
+        })
+    }
+}
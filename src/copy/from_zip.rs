@@ -0,0 +1,158 @@
+use super::extract::{Attrs, Extractor};
+use super::COPY_BUFFER_SIZE;
+use crate::file_system::{FileSystem, ReadFile};
+use crate::zip_format::{read_central_directory, ZipEntry, ZipKind};
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::mem;
+use std::path::{Path, PathBuf};
+
+// Files are handed to the pool in batches of up to this many, or this many bytes, each read
+// through its own handle on the archive.
+const BATCH_FILES: usize = 64;
+const BATCH_BYTES: u64 = 4 * 1024 * 1024;
+// Longer symlink targets are taken for a broken entry.
+const MAX_TARGET: u64 = 4096;
+
+impl Extractor<'_> {
+    // The central directory lists every entry up front, so files are read from the archive on
+    // the pool, in batches that follow each other in it. Errors are the archive's, one that
+    // can't be read; broken entries are reported as errors of the file they make.
+    pub(super) fn extract_zip(&mut self, path: &Path) -> Result<(), io::Error> {
+        let ctx = self.ctx;
+        let mut archive = ZipFile::open(&*ctx.source_fs, path)?;
+        let mut entries = read_central_directory(&mut archive)?;
+        entries.sort_by_key(|entry| entry.offset());
+        let mut batch = vec![];
+        let mut batch_bytes = 0;
+        for entry in entries {
+            if ctx.cancel.is_cancelled() {
+                break;
+            }
+            let is_dir = entry.kind == ZipKind::Dir;
+            let to = match self.destination(Path::new(&entry.name), is_dir) {
+                Some(to) => to,
+                None => continue,
+            };
+            let attrs = Attrs::of_zip(&entry);
+            if is_dir {
+                self.add_dir(to, attrs);
+                continue;
+            }
+            if !self.make_parents(&to) {
+                continue;
+            }
+            if entry.kind == ZipKind::Symlink {
+                match read_target(&entry, &mut archive) {
+                    Ok(target) => self.add_symlink(to, target, attrs),
+                    Err(e) => {
+                        ctx.report_error(&to, e);
+                    }
+                }
+                continue;
+            }
+            batch_bytes += entry.size;
+            batch.push((to, entry));
+            if batch.len() >= BATCH_FILES || batch_bytes >= BATCH_BYTES {
+                self.extract_batch(path, mem::take(&mut batch));
+                batch_bytes = 0;
+            }
+        }
+        if !batch.is_empty() {
+            self.extract_batch(path, batch);
+        }
+        Ok(())
+    }
+
+    fn extract_batch(&self, path: &Path, batch: Vec<(PathBuf, ZipEntry)>) {
+        let (ctx, path) = (self.ctx.clone(), path.to_path_buf());
+        let dests = batch.iter().map(|(to, _)| to.clone()).collect();
+        // Nothing is read before a worker gets to it.
+        self.spawn(path.clone(), dests, 0, move || {
+            let mut archive = match ZipFile::open(&*ctx.source_fs, &path) {
+                Ok(archive) => archive,
+                Err(e) => {
+                    for (to, _) in &batch {
+                        ctx.report_error(to, io::Error::new(e.kind(), e.to_string()));
+                    }
+                    return;
+                }
+            };
+            for (to, entry) in batch {
+                if ctx.cancel.is_cancelled() {
+                    return;
+                }
+                let attrs = Attrs::of_zip(&entry);
+                let write = |temp: &Path| {
+                    ctx.write_extracted(entry.reader(&mut archive)?, temp, &attrs)
+                };
+                let _ = ctx.extract(&to, attrs.modified, write);
+            }
+        });
+    }
+}
+
+impl Attrs {
+    fn of_zip(entry: &ZipEntry) -> Self {
+        Self {
+            mode: entry.mode,
+            modified: entry.modified,
+            xattrs: vec![],
+        }
+    }
+}
+
+// A symlink's data is its target.
+fn read_target(entry: &ZipEntry, archive: &mut ZipFile) -> Result<PathBuf, io::Error> {
+    if entry.size > MAX_TARGET {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "symlink target too long",
+        ));
+    }
+    let mut target = String::new();
+    entry.reader(archive)?.read_to_string(&mut target)?;
+    Ok(PathBuf::from(target))
+}
+
+// The archive read through a buffer, which seeks within it keep, so entries that follow each
+// other are read without going back to the file for each header.
+struct ZipFile {
+    inner: BufReader<Box<dyn ReadFile>>,
+    position: u64,
+}
+
+impl ZipFile {
+    fn open(fs: &dyn FileSystem, path: &Path) -> Result<Self, io::Error> {
+        Ok(Self {
+            inner: BufReader::with_capacity(COPY_BUFFER_SIZE, fs.open_seekable(path)?),
+            position: 0,
+        })
+    }
+}
+
+impl Read for ZipFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for ZipFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let offset = match pos {
+            SeekFrom::Start(to) => to as i64 - self.position as i64,
+            SeekFrom::Current(offset) => offset,
+            SeekFrom::End(_) => {
+                self.position = self.inner.seek(pos)?;
+                return Ok(self.position);
+            }
+        };
+        let position = self.position.checked_add_signed(offset).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "seek before the start")
+        })?;
+        self.inner.seek_relative(offset)?;
+        self.position = position;
+        Ok(position)
+    }
+}
@@ -0,0 +1,672 @@
+use super::to_tar::TarEncoder;
+use super::to_zip::ZipEncoder;
+use super::{temp_path, ArchiveFormat, CopyBuilder, CopyContext, CopyRoot, Copyer, ResolvedRoot};
+use crate::cancel::CancelToken;
+use crate::error::Error;
+use crate::file_system::{FileSystem, FileType, Metadata, WriteFile, Xattrs};
+use crate::gitignore::IgnoreStack;
+use crate::output::Event;
+use crate::pool::{Message, ThreadPool};
+use crate::report::CopyReport;
+use crate::stats::CopyStats;
+use crate::sync::SyncPolicy;
+use crate::zip_format::{Compressed, ZipCompression};
+use log::{debug, info, warn};
+use std::collections::VecDeque;
+use std::io::{self, BufWriter, Read, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Instant;
+
+// Files up to this size are read ahead on the pool, bigger ones are streamed into the archive
+// when their turn comes.
+const PREFETCH_LIMIT: u64 = 256 * 1024;
+// How many entries may be read ahead of the one being written, which bounds the memory held by
+// read ahead files.
+const WINDOW: usize = 256;
+
+// Where the archive goes: a file, written under a temporary name until it is complete, or
+// stdout when there is no path.
+#[derive(Clone)]
+pub(super) struct ArchiveTarget {
+    path: Option<PathBuf>,
+    format: ArchiveFormat,
+    compression: ZipCompression,
+}
+
+impl ArchiveTarget {
+    fn open(&self, fs: &dyn FileSystem) -> Result<Output, io::Error> {
+        let sink = match &self.path {
+            Some(path) => Sink::File(fs.create(&temp_path(path))?),
+            None => Sink::Stdout(io::stdout()),
+        };
+        Ok(Output {
+            sink,
+            abandoned: false,
+        })
+    }
+}
+
+impl CopyBuilder {
+    // Settings for `set_to_tar` and `set_to_zip`. Sources are named as they would be in a
+    // destination directory: `dir` as `dir/...`, the contents of `dir/` at the top of the
+    // archive.
+    pub(super) fn build_to_archive(self) -> Result<Copyer, Error> {
+        let (format, archive) = self.to_archive.clone().unwrap();
+        let from_archive = self.from_archive.as_ref().map(|(f, _)| format!("--from-{}", f));
+        let unsupported = [
+            (self.to.is_some(), "a destination"),
+            (from_archive.is_some(), from_archive.as_deref().unwrap_or_default()),
+            (self.moving, "moving"),
+            (self.dry_run, "a dry run"),
+            (self.verify, "--verify"),
+            (self.compress.is_some(), "--compress"),
+            (self.decompress, "--decompress"),
+            (self.encrypt.is_some(), "--encrypt"),
+            (self.decrypt.is_some(), "--decrypt"),
+            (self.encrypt_names, "--encrypt-names"),
+            (self.files_from.is_some(), "--files-from"),
+            (self.backup_mode.is_some() || self.backup_dir.is_some(), "backups"),
+        ];
+        if let Some((_, setting)) = unsupported.iter().find(|(set, _)| *set) {
+            return Err(Error::Config(format!(
+                "--to-{} can't be combined with {}.",
+                format, setting
+            )));
+        }
+        let path = match archive.as_str() {
+            "-" => None,
+            path => Some(
+                Self::absolute(&*self.dest_fs, Path::new(path))
+                    .map_err(|_| format!("Preprocess --to-{} path failed", format))
+                    .map_err(Error::Config)?,
+            ),
+        };
+        if let Some(parent) = path.as_ref().and_then(|p| p.parent()) {
+            if self.dest_fs.create_dir_all(parent).is_err() {
+                return Err("Create to directory failed".into());
+            }
+        }
+        let sources = Self::archive_roots(&*self.source_fs, &self.sources)?;
+
+        let to = path.clone().unwrap_or_else(|| PathBuf::from("-"));
+        let mut base = self.context(to, None);
+        // Text on stdout would end up in the archive.
+        base.quiet |= path.is_none();
+        let roots: Vec<CopyRoot> = sources
+            .into_iter()
+            .map(|root| CopyRoot {
+                context: Arc::new(CopyContext {
+                    from: root.from,
+                    dest: root.dest,
+                    ..base.clone()
+                }),
+                is_dir: root.is_dir,
+                node: None,
+            })
+            .collect();
+
+        Ok(Copyer {
+            multi_threads: self.multi_threads,
+            pool: self.pool(),
+            context: roots[0].context.clone(),
+            roots,
+            files_from: None,
+            force: self.force,
+            scanned: None,
+            to_archive: Some(ArchiveTarget {
+                path,
+                format,
+                compression: self.zip_compression,
+            }),
+            from_archive: None,
+        })
+    }
+
+    // Each source with its name in the archive, empty for a directory whose contents go at the
+    // top. A symlink given as a source is archived as the link.
+    fn archive_roots(
+        fs: &dyn FileSystem,
+        sources: &[String],
+    ) -> Result<Vec<ResolvedRoot>, &'static str> {
+        if sources.is_empty() {
+            return Err("No source to copy.");
+        }
+        sources
+            .iter()
+            .map(|source| {
+                let (from, is_dir, contents, name) = Self::resolve_source(fs, source, false)?;
+                let dest = match name {
+                    Some(name) if !contents => PathBuf::from(name),
+                    _ => PathBuf::new(),
+                };
+                Ok(ResolvedRoot {
+                    from,
+                    dest,
+                    is_dir,
+                    contents,
+                    own_name: false,
+                })
+            })
+            .collect()
+    }
+}
+
+impl Copyer {
+    // Walk the sources in a fixed order on this thread and append each entry to the archive,
+    // while the pool reads files ahead. An entry that can't be read is reported and left out;
+    // failing to write the archive stops the run. An archive file is only put in place once it
+    // is complete.
+    pub(super) fn run_to_archive(self) -> Result<CopyReport, io::Error> {
+        let ctx = self.context.clone();
+        let target = self.to_archive.clone().unwrap();
+        self.start_run(self.pool.as_ref().map_or(1, |pool| pool.size()));
+        let progress = self.start_progress();
+        let now = Instant::now();
+        match target.open(&*ctx.dest_fs) {
+            Ok(output) => {
+                let mut archive = ArchiveWriter::new(&ctx, &target, output, self.pool.as_ref());
+                for root in &self.roots {
+                    if ctx.cancel.is_cancelled() {
+                        break;
+                    }
+                    archive.add_root(&root.context, root.is_dir);
+                }
+                archive.finish(&ctx, &target);
+            }
+            Err(e) => ctx.fail(&ctx.to, e),
+        }
+        if let Some(progress) = progress {
+            progress.finish();
+        }
+        if !ctx.cancel.is_cancelled() {
+            ctx.say("Archive complete.");
+        }
+        ctx.finish_run(now.elapsed())
+    }
+}
+
+// The archive being written. Once abandoned nothing more goes out, not even the end of archive
+// marker the tar builder writes when it is dropped, so a cut short archive on stdout shows as
+// such to whoever reads it.
+pub(super) struct Output {
+    sink: Sink,
+    abandoned: bool,
+}
+
+enum Sink {
+    Stdout(io::Stdout),
+    File(Box<dyn WriteFile>),
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.abandoned {
+            return Ok(buf.len());
+        }
+        match &mut self.sink {
+            Sink::Stdout(stdout) => stdout.write(buf),
+            Sink::File(file) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.abandoned {
+            return Ok(());
+        }
+        match &mut self.sink {
+            Sink::Stdout(stdout) => stdout.flush(),
+            Sink::File(file) => file.flush(),
+        }
+    }
+}
+
+// What is read of an entry before it is appended.
+pub(super) struct Loaded {
+    pub(super) metadata: Metadata,
+    pub(super) xattrs: Xattrs,
+    pub(super) content: Content,
+}
+
+pub(super) enum Content {
+    None,
+    Data(Vec<u8>),
+    // A file read ahead and compressed for a zip archive.
+    Compressed(Compressed),
+    // Too big to be held, read as it is appended.
+    Large,
+    Target(PathBuf),
+}
+
+// Hands an entry read on the pool to the writer.
+#[derive(Default)]
+struct Slot {
+    loaded: Mutex<Option<io::Result<Loaded>>>,
+    ready: Condvar,
+}
+
+impl Slot {
+    fn fill(&self, loaded: io::Result<Loaded>) {
+        *self.loaded.lock().unwrap() = Some(loaded);
+        self.ready.notify_one();
+    }
+
+    fn take(&self) -> io::Result<Loaded> {
+        let mut loaded = self.loaded.lock().unwrap();
+        loop {
+            match loaded.take() {
+                Some(loaded) => return loaded,
+                None => loaded = self.ready.wait(loaded).unwrap(),
+            }
+        }
+    }
+}
+
+// An entry walked but not appended yet. Without a slot it marks the end of the directory
+// `name`.
+pub(super) struct Pending {
+    pub(super) ctx: Arc<CopyContext>,
+    pub(super) path: PathBuf,
+    pub(super) name: PathBuf,
+    // Of the entry, not followed, if the walk stat'ed it already.
+    metadata: Option<Metadata>,
+    slot: Option<Arc<Slot>>,
+    walked: Instant,
+}
+
+// Puts entries into the archive in its format.
+enum Encoder {
+    Tar(TarEncoder),
+    Zip(ZipEncoder),
+}
+
+// What an encoder put into the archive for an entry.
+pub(super) enum Appended {
+    Dir,
+    // A file or link; a file that failed to read, and is padded with zeros, has the error.
+    File {
+        size: u64,
+        error: Option<io::Error>,
+    },
+}
+
+struct ArchiveWriter<'a> {
+    encoder: Encoder,
+    pool: Option<&'a ThreadPool>,
+    pending: VecDeque<Pending>,
+    // Files read ahead are compressed on the pool with this, for a zip archive.
+    compression: Option<ZipCompression>,
+    // The archive and its temporary name, when they could be in the sources.
+    skipped: Vec<PathBuf>,
+}
+
+impl<'a> ArchiveWriter<'a> {
+    fn new(
+        ctx: &CopyContext,
+        target: &ArchiveTarget,
+        output: Output,
+        pool: Option<&'a ThreadPool>,
+    ) -> Self {
+        let skipped = match &target.path {
+            Some(path) if ctx.same_fs() => vec![path.clone(), temp_path(path)],
+            _ => vec![],
+        };
+        let output = BufWriter::with_capacity(super::COPY_BUFFER_SIZE, output);
+        let (encoder, compression) = match target.format {
+            ArchiveFormat::Tar => (Encoder::Tar(TarEncoder::new(output)), None),
+            ArchiveFormat::Zip => (
+                Encoder::Zip(ZipEncoder::new(output, target.compression)),
+                Some(target.compression),
+            ),
+        };
+        Self {
+            encoder,
+            pool,
+            pending: VecDeque::new(),
+            compression,
+            skipped,
+        }
+    }
+
+    fn add_root(&mut self, ctx: &Arc<CopyContext>, is_dir: bool) {
+        if !ctx.dest.as_os_str().is_empty() {
+            self.push(ctx, ctx.from.clone(), ctx.dest.clone(), None);
+        }
+        if is_dir {
+            self.add_dir(ctx, Path::new(""), ctx.root_ignores().as_ref());
+        }
+    }
+
+    // Entries of a directory in name order, each directory followed by what is in it.
+    fn add_dir(
+        &mut self,
+        ctx: &Arc<CopyContext>,
+        depth_path: &Path,
+        ignores: Option<&Arc<IgnoreStack>>,
+    ) {
+        ctx.walk_dir(depth_path, ignores, true, |entry, ignores| {
+            if self.skipped.contains(&entry.path) {
+                ctx.skip(&entry.path, "the archive itself");
+                return;
+            }
+            let name = ctx.dest_path(&entry.depth_path);
+            self.push(ctx, entry.path, name, entry.metadata);
+            if entry.file_type.is_dir() {
+                self.add_dir(ctx, &entry.depth_path, ignores);
+            }
+        });
+        self.pending.push_back(Pending {
+            ctx: ctx.clone(),
+            path: ctx.from.join(depth_path),
+            name: ctx.dest_path(depth_path),
+            metadata: None,
+            slot: None,
+            walked: Instant::now(),
+        });
+        self.write_ready();
+    }
+
+    // Queue an entry, read ahead on the pool if there is one.
+    fn push(
+        &mut self,
+        ctx: &Arc<CopyContext>,
+        path: PathBuf,
+        name: PathBuf,
+        metadata: Option<Metadata>,
+    ) {
+        let slot = Arc::new(Slot::default());
+        if let Some(pool) = self.pool {
+            let (ctx, path, slot) = (ctx.clone(), path.clone(), slot.clone());
+            let metadata = metadata.clone();
+            let compression = self.compression;
+            pool.sender
+                .send(Message::NewTask(Box::new(move || {
+                    let loaded = if ctx.cancel.is_cancelled() {
+                        Err(cancelled())
+                    } else {
+                        // The writer waits for the slot, a panic has to fill it too.
+                        let load = || load(&ctx, &path, metadata, true, compression);
+                        panic::catch_unwind(AssertUnwindSafe(load))
+                            .unwrap_or_else(|_| Err(io::Error::other("reading panicked")))
+                    };
+                    slot.fill(loaded);
+                })))
+                .unwrap();
+        }
+        self.pending.push_back(Pending {
+            ctx: ctx.clone(),
+            path,
+            name,
+            metadata,
+            slot: Some(slot),
+            walked: Instant::now(),
+        });
+        self.write_ready();
+    }
+
+    // Append entries until no more than the window is read ahead; without a pool nothing is.
+    fn write_ready(&mut self) {
+        let window = if self.pool.is_some() { WINDOW } else { 0 };
+        while self.pending.len() > window {
+            self.write_next();
+        }
+    }
+
+    fn write_next(&mut self) {
+        let entry = self.pending.pop_front().unwrap();
+        let ctx = &entry.ctx;
+        let slot = match &entry.slot {
+            Some(slot) => slot,
+            None => {
+                ctx.notify(|o| o.on_dir_complete(&entry.name));
+                return;
+            }
+        };
+        let loaded = match self.pool {
+            Some(_) => slot.take(),
+            None => load(ctx, &entry.path, entry.metadata.clone(), false, None),
+        };
+        // Cancelled, or the archive can't be written any more.
+        if ctx.cancel.is_cancelled() {
+            return;
+        }
+        let loaded = match loaded {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                ctx.report_error(&entry.path, e);
+                return;
+            }
+        };
+        if let Err(e) = self.append(&entry, loaded) {
+            if !ctx.cancel.is_cancelled() {
+                ctx.fail(&ctx.to, e);
+            }
+        }
+    }
+
+    // Errors are the archive's, reading the entry can't fail any more once its header is out.
+    fn append(&mut self, entry: &Pending, loaded: Loaded) -> Result<(), io::Error> {
+        let ctx = &entry.ctx;
+        if loaded.metadata.file_type == FileType::Other {
+            warn!("{:?} is not a file or directory", entry.path);
+            ctx.skip(&entry.path, "unsupported file type");
+            return Ok(());
+        }
+        let appended = match &mut self.encoder {
+            Encoder::Tar(tar) => tar.append(entry, loaded)?,
+            Encoder::Zip(zip) => zip.append(entry, loaded)?,
+        };
+
+        let name = entry.name.to_string_lossy().into_owned();
+        match appended {
+            // The entry is in the archive, padded with zeros.
+            Appended::File { error: Some(e), .. } => {
+                ctx.report_error(&entry.path, e);
+            }
+            Appended::Dir => {
+                ctx.stats.add_dir();
+                ctx.emit(|| Event::DirCreated { path: name });
+            }
+            Appended::File { size, error: None } => {
+                let elapsed = entry.walked.elapsed();
+                ctx.stats.add_file(size);
+                ctx.emit(|| Event::FileCopied {
+                    path: name,
+                    bytes: size,
+                    duration_ms: elapsed.as_secs_f64() * 1000.0,
+                });
+                ctx.notify(|o| o.on_file_done(&entry.name, size, elapsed));
+            }
+        }
+        Ok(())
+    }
+
+    // Append what is still queued and close the archive, or drop it if the run was stopped.
+    fn finish(mut self, ctx: &CopyContext, target: &ArchiveTarget) {
+        while !self.pending.is_empty() {
+            self.write_next();
+        }
+        let temp = target.path.as_deref().map(temp_path);
+        if ctx.cancel.is_cancelled() {
+            match &mut self.encoder {
+                Encoder::Tar(tar) => tar.output().abandoned = true,
+                Encoder::Zip(zip) => zip.output().abandoned = true,
+            }
+            drop(self.encoder);
+            if let Some(temp) = temp {
+                debug!("archive not complete, removed {:?}", temp);
+                let _ = ctx.dest_fs.remove_file(&temp);
+            }
+            return;
+        }
+        let buffered = match self.encoder {
+            Encoder::Tar(tar) => tar.finish(),
+            Encoder::Zip(zip) => zip.finish(),
+        };
+        let output =
+            buffered.and_then(|buffered| buffered.into_inner().map_err(|e| e.into_error()));
+        let result = output.and_then(|output| match (output.sink, &target.path) {
+            (Sink::File(mut file), Some(path)) => {
+                if ctx.sync != SyncPolicy::None {
+                    ctx.timed_sync(|| file.sync_all())?;
+                }
+                drop(file);
+                ctx.dest_fs.rename(&temp_path(path), path)?;
+                if ctx.sync != SyncPolicy::None {
+                    let dir = path.parent().unwrap_or(path);
+                    ctx.timed_sync(|| ctx.dest_fs.sync_dir(dir))?;
+                }
+                info!("archive written to {:?}", path);
+                Ok(())
+            }
+            (_, _) => Ok(()),
+        });
+        if let Err(e) = result {
+            if let Some(temp) = temp {
+                let _ = ctx.dest_fs.remove_file(&temp);
+            }
+            ctx.fail(&ctx.to, e);
+        }
+    }
+}
+
+// Metadata, unless the walk has it, extended attributes and, if `prefetch` and the file is
+// small, the data of the entry at `path`, compressed if there is a `compression`.
+fn load(
+    ctx: &CopyContext,
+    path: &Path,
+    metadata: Option<Metadata>,
+    prefetch: bool,
+    compression: Option<ZipCompression>,
+) -> Result<Loaded, io::Error> {
+    let fs = &ctx.source_fs;
+    let metadata = match metadata {
+        Some(metadata) => metadata,
+        None => fs.symlink_metadata(path)?,
+    };
+    let content = match metadata.file_type {
+        FileType::File if prefetch && metadata.len <= PREFETCH_LIMIT => {
+            let mut data = Vec::with_capacity(metadata.len as usize);
+            fs.open(path)?.read_to_end(&mut data)?;
+            match compression {
+                Some(compression) => Content::Compressed(Compressed::new(&data, compression)?),
+                None => Content::Data(data),
+            }
+        }
+        FileType::File => Content::Large,
+        FileType::Symlink => Content::Target(fs.read_link(path)?),
+        FileType::Dir | FileType::Other => Content::None,
+    };
+    let xattrs = fs.xattrs(path).unwrap_or_else(|e| {
+        debug!("no xattrs of {:?}: {}", path, e);
+        vec![]
+    });
+    Ok(Loaded {
+        metadata,
+        xattrs,
+        content,
+    })
+}
+
+// Exactly `left` bytes of a file for its archive entry, whose header already gives the size. A
+// file that shrank, or failed to read, is padded with zeros and the error kept, so the archive
+// stays readable. Cancelling fails the read.
+pub(super) struct EntryReader<'a> {
+    inner: Option<Box<dyn Read + Send>>,
+    left: u64,
+    pub(super) error: Option<io::Error>,
+    cancel: &'a CancelToken,
+    stats: &'a CopyStats,
+}
+
+impl<'a> EntryReader<'a> {
+    // The file of `entry`, `size` bytes when it was walked.
+    pub(super) fn open(entry: &'a Pending, size: u64) -> Self {
+        let ctx = &entry.ctx;
+        let (inner, error) = match ctx.source_fs.open(&entry.path) {
+            Ok(file) => (Some(file), None),
+            Err(e) => (None, Some(e)),
+        };
+        Self {
+            inner,
+            left: size,
+            error,
+            cancel: &ctx.cancel,
+            stats: &ctx.stats,
+        }
+    }
+}
+
+impl Read for EntryReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.left == 0 {
+            return Ok(0);
+        }
+        if self.cancel.is_cancelled() {
+            return Err(cancelled());
+        }
+        let max = buf.len().min(self.left.min(usize::MAX as u64) as usize);
+        let read = match &mut self.inner {
+            Some(inner) if self.error.is_none() => inner.read(&mut buf[..max]),
+            _ => Ok(0),
+        };
+        let n = match read {
+            Ok(0) => {
+                self.error.get_or_insert_with(|| {
+                    io::Error::new(io::ErrorKind::UnexpectedEof, "file shrank while archived")
+                });
+                buf[..max].fill(0);
+                max
+            }
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => return Err(e),
+            Err(e) => {
+                self.error = Some(e);
+                buf[..max].fill(0);
+                max
+            }
+        };
+        self.left -= n as u64;
+        self.stats.add_written(n as u64);
+        Ok(n)
+    }
+}
+
+// Not `Interrupted`, which `io::copy` would retry.
+fn cancelled() -> io::Error {
+    io::Error::other("archiving cancelled")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn entry_reader_test() {
+        let (cancel, stats) = (CancelToken::new(), CopyStats::new());
+        // The file shrank from 6 to 4 bytes since its header was written.
+        let mut reader = EntryReader {
+            inner: Some(Box::new(&b"data"[..])),
+            left: 6,
+            error: None,
+            cancel: &cancel,
+            stats: &stats,
+        };
+        let mut data = vec![];
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"data\0\0");
+        assert_eq!(reader.error.unwrap().kind(), io::ErrorKind::UnexpectedEof);
+
+        cancel.cancel();
+        let mut reader = EntryReader {
+            inner: Some(Box::new(&b"data"[..])),
+            left: 4,
+            error: None,
+            cancel: &cancel,
+            stats: &stats,
+        };
+        assert!(reader.read_to_end(&mut data).is_err());
+        assert_eq!(stats.written(), 6);
+    }
+}
@@ -0,0 +1,153 @@
+use super::to_archive::{Appended, Content, EntryReader, Loaded, Output, Pending};
+use crate::file_system::FileType;
+use log::warn;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tar::{EntryType, Header};
+
+// Largest size an ustar header holds; pax records the size of bigger files.
+const USTAR_MAX_SIZE: u64 = 0o77777777777;
+
+pub(super) struct TarEncoder {
+    builder: tar::Builder<BufWriter<Output>>,
+    // Archive names of files with more than one link by device and inode, later links to them
+    // are archived as hard links.
+    links: HashMap<(u64, u64), PathBuf>,
+}
+
+impl TarEncoder {
+    pub(super) fn new(output: BufWriter<Output>) -> Self {
+        Self {
+            builder: tar::Builder::new(output),
+            links: HashMap::new(),
+        }
+    }
+
+    pub(super) fn output(&mut self) -> &mut Output {
+        self.builder.get_mut().get_mut()
+    }
+
+    // Writes the end of archive marker.
+    pub(super) fn finish(self) -> Result<BufWriter<Output>, io::Error> {
+        self.builder.into_inner()
+    }
+
+    pub(super) fn append(
+        &mut self,
+        entry: &Pending,
+        loaded: Loaded,
+    ) -> Result<Appended, io::Error> {
+        let ctx = &entry.ctx;
+        let Loaded {
+            metadata,
+            xattrs,
+            content,
+        } = loaded;
+        let mut pax: Vec<(String, Vec<u8>)> = vec![];
+        let mut name = entry.name.clone();
+        let mut link = None;
+        let entry_type = match metadata.file_type {
+            FileType::Dir => {
+                // The trailing `/` tar gives directories.
+                name.push("");
+                EntryType::Directory
+            }
+            FileType::Symlink => {
+                if let Content::Target(target) = &content {
+                    link = Some(target.clone());
+                }
+                EntryType::Symlink
+            }
+            FileType::File if metadata.nlink > 1 && metadata.ino != 0 => {
+                let key = (metadata.dev, metadata.ino);
+                match self.links.get(&key) {
+                    Some(first) => {
+                        link = Some(first.clone());
+                        EntryType::Link
+                    }
+                    None => {
+                        self.links.insert(key, entry.name.clone());
+                        EntryType::Regular
+                    }
+                }
+            }
+            FileType::File | FileType::Other => EntryType::Regular,
+        };
+        let size = match (&content, entry_type) {
+            (Content::Data(data), EntryType::Regular) => data.len() as u64,
+            (Content::Large, EntryType::Regular) => metadata.len,
+            _ => 0,
+        };
+
+        let mut header = Header::new_ustar();
+        if header.set_path(&name).is_err() {
+            header = Header::new_ustar();
+            set_truncated(&mut header.as_old_mut().name, &name);
+            pax.push((String::from("path"), path_bytes(&name).into_owned()));
+        }
+        if let Some(link) = &link {
+            if header.set_link_name(link).is_err() {
+                set_truncated(&mut header.as_old_mut().linkname, link);
+                pax.push((String::from("linkpath"), path_bytes(link).into_owned()));
+            }
+        }
+        header.set_entry_type(entry_type);
+        header.set_mode(metadata.mode & 0o7777);
+        header.set_uid(metadata.uid as u64);
+        header.set_gid(metadata.gid as u64);
+        let mtime = metadata.modified.and_then(|m| m.duration_since(UNIX_EPOCH).ok());
+        header.set_mtime(mtime.map_or(0, |m| m.as_secs()));
+        header.set_size(size);
+        if size > USTAR_MAX_SIZE {
+            pax.push((String::from("size"), size.to_string().into_bytes()));
+        }
+        for (key, value) in xattrs {
+            match key.to_str() {
+                Some(key) => pax.push((format!("SCHILY.xattr.{}", key), value)),
+                None => warn!("{:?}: xattr {:?} left out, not UTF-8", entry.path, key),
+            }
+        }
+        header.set_cksum();
+
+        self.builder
+            .append_pax_extensions(pax.iter().map(|(k, v)| (k.as_str(), v.as_slice())))?;
+        let mut error = None;
+        match content {
+            Content::Data(data) if entry_type == EntryType::Regular => {
+                self.builder.append(&header, &data[..])?;
+                ctx.stats.add_written(size);
+            }
+            Content::Large if entry_type == EntryType::Regular => {
+                let mut reader = EntryReader::open(entry, size);
+                self.builder.append(&header, &mut reader)?;
+                error = reader.error;
+            }
+            _ => self.builder.append(&header, io::empty())?,
+        }
+        Ok(match entry_type {
+            EntryType::Directory => Appended::Dir,
+            _ => Appended::File { size, error },
+        })
+    }
+}
+
+// As much of `path` as fits into a header field, the rest is in a pax record.
+fn set_truncated(field: &mut [u8], path: &Path) {
+    let bytes = path_bytes(path);
+    let n = bytes.len().min(field.len());
+    field[..n].copy_from_slice(&bytes[..n]);
+}
+
+#[cfg(unix)]
+fn path_bytes(path: &Path) -> Cow<'_, [u8]> {
+    use std::os::unix::ffi::OsStrExt;
+    Cow::Borrowed(path.as_os_str().as_bytes())
+}
+
+#[cfg(not(unix))]
+fn path_bytes(path: &Path) -> Cow<'_, [u8]> {
+    Cow::Owned(path.to_string_lossy().replace('\\', "/").into_bytes())
+}
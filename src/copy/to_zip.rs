@@ -0,0 +1,102 @@
+use super::to_archive::{Appended, Content, EntryReader, Loaded, Output, Pending};
+use crate::file_system::FileType;
+use crate::zip_format::{Compressed, EntryHeader, ZipCompression, ZipKind, ZipWriter};
+use log::debug;
+use std::io::{self, BufWriter};
+
+// Zip has no hard links, a file with several is archived as a copy for each; extended
+// attributes are left out.
+pub(super) struct ZipEncoder {
+    writer: ZipWriter<BufWriter<Output>>,
+    compression: ZipCompression,
+}
+
+impl ZipEncoder {
+    pub(super) fn new(output: BufWriter<Output>, compression: ZipCompression) -> Self {
+        Self {
+            writer: ZipWriter::new(output),
+            compression,
+        }
+    }
+
+    pub(super) fn output(&mut self) -> &mut Output {
+        self.writer.get_mut().get_mut()
+    }
+
+    // Writes the central directory.
+    pub(super) fn finish(self) -> Result<BufWriter<Output>, io::Error> {
+        self.writer.finish()
+    }
+
+    pub(super) fn append(
+        &mut self,
+        entry: &Pending,
+        loaded: Loaded,
+    ) -> Result<Appended, io::Error> {
+        let ctx = &entry.ctx;
+        let Loaded {
+            metadata,
+            xattrs,
+            content,
+        } = loaded;
+        if !xattrs.is_empty() {
+            debug!("{:?}: {} xattrs left out of the zip", entry.path, xattrs.len());
+        }
+        let mut name = entry
+            .name
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        let kind = match metadata.file_type {
+            FileType::Dir => {
+                name.push('/');
+                ZipKind::Dir
+            }
+            FileType::Symlink => ZipKind::Symlink,
+            FileType::File | FileType::Other => ZipKind::File,
+        };
+        let header = EntryHeader {
+            name,
+            kind,
+            mode: metadata.mode & 0o7777,
+            modified: metadata.modified,
+        };
+
+        let mut error = None;
+        let size = match (kind, content) {
+            (ZipKind::File, Content::Compressed(data)) => {
+                self.writer.add(&header, &data)?;
+                ctx.stats.add_written(data.size());
+                data.size()
+            }
+            (ZipKind::File, Content::Data(data)) => {
+                self.writer.add(&header, &Compressed::new(&data, self.compression)?)?;
+                ctx.stats.add_written(data.len() as u64);
+                data.len() as u64
+            }
+            (ZipKind::File, Content::Large) => {
+                let mut reader = EntryReader::open(entry, metadata.len);
+                self.writer
+                    .add_streamed(&header, self.compression, &mut reader, metadata.len)?;
+                error = reader.error;
+                metadata.len
+            }
+            // A symlink's data is its target.
+            (ZipKind::Symlink, Content::Target(target)) => {
+                let target = target.to_string_lossy();
+                let data = Compressed::new(target.as_bytes(), ZipCompression::Store)?;
+                self.writer.add(&header, &data)?;
+                0
+            }
+            (_, _) => {
+                self.writer.add(&header, &Compressed::new(&[], ZipCompression::Store)?)?;
+                0
+            }
+        };
+        Ok(match kind {
+            ZipKind::Dir => Appended::Dir,
+            _ => Appended::File { size, error },
+        })
+    }
+}
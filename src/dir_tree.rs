@@ -1,6 +1,7 @@
+use log::trace;
 use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
 
 // Wrapper of reference of node for convenience.
@@ -47,18 +48,18 @@ pub struct DirNode {
     _parent: Option<SharedNodeRef>,
     _path: PathBuf,
     _is_copied: bool,
+    _is_listing: bool,
     _sub_nodes: HashMap<String, SharedNodeRef>,
-    verbose: bool
 }
 
 impl DirNode {
-    pub fn new(path: PathBuf, verbose: bool) -> Self {
+    pub fn new(path: PathBuf) -> Self {
         Self {
             _parent: None,
             _path: path,
             _is_copied: false,
+            _is_listing: false,
             _sub_nodes: HashMap::new(),
-            verbose,
         }
     }
 
@@ -71,35 +72,46 @@ impl DirNode {
         self._sub_nodes.insert(key, node);
     }
 
-    pub fn set_copied(&mut self) {
+    // A node whose directory is still being read may get more children, so it must not be set
+    // copied even if all children known so far are.
+    pub fn set_listing(&mut self) {
+        self._is_listing = true;
+    }
+
+    pub fn finish_listing(&mut self) {
+        self._is_listing = false;
+    }
+
+    // Returns true only for the call that turns the node copied, so that exactly one caller
+    // continues the lookup to the parent.
+    pub fn set_copied(&mut self) -> bool {
+        if self._is_copied {
+            return false;
+        }
+        if self._is_listing {
+            trace!("{:?} is still listing, not copied.", self._path);
+            return false;
+        }
         //If leaf, set copied.
         if self._sub_nodes.is_empty() {
-            if self.verbose {
-                println!(
-                    "{:?} has no children, is leaf. Set copied true.",
-                    self._path
-                );
-            }
-            self._is_copied = true;
-            return;
-        }
-        //Try delete children when is not leaf.
-        if self.verbose {
-            println!(
-                "{:?} has children, is not leaf, try delete children.",
+            trace!(
+                "{:?} has no children, is leaf. Set copied true.",
                 self._path
             );
+            self._is_copied = true;
+            return true;
         }
+        //Try delete children when is not leaf.
+        trace!(
+            "{:?} has children, is not leaf, try delete children.",
+            self._path
+        );
         self._sub_nodes.retain(|_k, r| {
             //delete those nodes that had been copied
             let read = r.0.read().unwrap();
-            if self.verbose {
-                println!("{:?} has read lock when judge if to delete", read._path);
-            }
+            trace!("{:?} has read lock when judge if to delete", read._path);
             let r = !read.is_copied();
-            if self.verbose {
-                println!("{:?}'s read lock drop when judge if to delete", read._path);
-            }
+            trace!("{:?}'s read lock drop when judge if to delete", read._path);
             drop(read);
             r
         });
@@ -107,21 +119,22 @@ impl DirNode {
         //If children is empty then is leaf, set copied.
         self._is_copied = self._sub_nodes.is_empty();
 
-        if self._is_copied && self.verbose {
-            println!(
+        if self._is_copied {
+            trace!(
                 "{:?} has no children after delete, is leaf. return.",
                 self._path
             );
-        } else if self.verbose {
-            println!(
+        } else {
+            trace!(
                 "{:?} has children after delete, is not leaf. return.",
                 self._path
             );
         }
+        self._is_copied
     }
 
     pub fn is_copied(&self) -> bool {
-        return self._is_copied;
+        self._is_copied
     }
 
     pub fn set_parent(&mut self, p: SharedNodeRef) {
@@ -133,9 +146,17 @@ impl DirNode {
     }
 
     // If current node is copied and has parent, set current node to parent and repeat.
-    pub fn try_lookup_continuously(start_node: SharedNodeRef) {
+    // `on_complete` is called once with the path of every node that is copied, starting node
+    // included.
+    pub fn try_lookup_continuously<F>(start_node: SharedNodeRef, mut on_complete: F)
+    where
+        F: FnMut(&Path),
+    {
         let reader = start_node.inner().read().unwrap();
         let mut lookup_flag = reader.is_copied();
+        if lookup_flag {
+            on_complete(reader.path());
+        }
 
         let mut may_parent = None;
 
@@ -147,11 +168,13 @@ impl DirNode {
         while lookup_flag && may_parent.is_some() {
             let parent = may_parent.take().unwrap();
             let mut writer = parent.inner().write().unwrap();
-            writer.set_copied();
+            lookup_flag = writer.set_copied();
             drop(writer);
 
             let reader = parent.inner().read().unwrap();
-            lookup_flag = reader.is_copied();
+            if lookup_flag {
+                on_complete(reader.path());
+            }
 
             if let Some(p) = &reader.parent() {
                 may_parent = Some(p.clone());
@@ -177,12 +200,12 @@ mod test {
     //                   |          |         |         |           |
     //                  <B1>      <B2>      <B3>       <B4>        <B5>
     fn build_tree() -> SharedNodeRef {
-        let r = DirNode::new(PathBuf::from(String::from("./test_dir/tree")), true);
+        let r = DirNode::new(PathBuf::from(String::from("./test_dir/tree")));
         let root_rc = SharedNodeRef::new(r);
 
         for i in 0..5 {
             let p = PathBuf::from("./test_dir/tree/A".to_string() + &(i + 1).to_string());
-            let mut tn = DirNode::new(p, true);
+            let mut tn = DirNode::new(p);
             tn.set_parent(root_rc.clone());
             root_rc
                 .0
@@ -197,7 +220,7 @@ mod test {
 
         for i in 0..5 {
             let p = PathBuf::from("./test_dir/tree/A3/B".to_string() + &(i + 1).to_string());
-            let mut tn = DirNode::new(p, true);
+            let mut tn = DirNode::new(p);
             tn.set_parent(r2.clone());
             r2.0.write().unwrap().add_sub_nodes(SharedNodeRef::new(tn));
         }
@@ -213,50 +236,28 @@ mod test {
 
         println!("start");
 
-        for (_key, r) in &root.0.read().unwrap()._sub_nodes {
-            let shared_node = r.0.clone();
+        for r in root.0.read().unwrap()._sub_nodes.values() {
+            let shared_node = r.clone();
             handlers.push(thread::spawn(move || {
-                let mut writer = shared_node.write().unwrap();
+                let mut writer = shared_node.0.write().unwrap();
                 writer.set_copied();
                 drop(writer);
-                let reader = shared_node.read().unwrap();
-                let lookup_flag = reader.is_copied();
-                if lookup_flag {
-                    let mut p = None;
-                    if let Some(inner_p) = &reader._parent {
-                        p = Some(inner_p.clone());
-                    }
-                    println!("lookup {:?}", reader.path());
-                    drop(reader);
-                    if let Some(p) = p {
-                        let mut writer = p.0.write().unwrap();
-                        writer.set_copied();
-                    }
-                }
+                DirNode::try_lookup_continuously(shared_node, |path| {
+                    println!("lookup {:?}", path);
+                });
             }));
 
             let reader = r.0.read().unwrap();
             if !reader._sub_nodes.is_empty() {
-                for (_, r) in &reader._sub_nodes {
-                    let shared_node = r.0.clone();
+                for r in reader._sub_nodes.values() {
+                    let shared_node = r.clone();
                     handlers.push(thread::spawn(move || {
-                        let mut writer = shared_node.write().unwrap();
+                        let mut writer = shared_node.0.write().unwrap();
                         writer.set_copied();
                         drop(writer);
-                        let reader = shared_node.read().unwrap();
-                        let lookup_flag = reader.is_copied();
-                        if lookup_flag {
-                            let mut p = None;
-                            if let Some(inner_p) = &reader._parent {
-                                p = Some(inner_p.clone());
-                            }
-                            println!("lookup {:?}", reader.path());
-                            drop(reader);
-                            if let Some(p) = p {
-                                let mut writer = p.0.write().unwrap();
-                                writer.set_copied();
-                            }
-                        }
+                        DirNode::try_lookup_continuously(shared_node, |path| {
+                            println!("lookup {:?}", path);
+                        });
                     }))
                 }
             }
@@ -0,0 +1,450 @@
+use crate::file_system::FileSystem;
+use argon2::Argon2;
+use base64ct::{Base64UrlUnpadded, Encoding};
+use blake2::digest::consts::{U24, U32};
+use blake2::digest::Mac;
+use blake2::Blake2bMac;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// Starts every encrypted file, followed by the salt of its key and the nonce prefix of its
+// stream.
+const MAGIC: &[u8; 8] = b"rfc-enc\x01";
+const NONCE_PREFIX: usize = 19;
+// Plaintext per chunk; each chunk is sealed with a tag of its own. The last chunk is shorter,
+// possibly empty, so a file cut at a chunk boundary doesn't pass for a complete one.
+const CHUNK: usize = 64 * 1024;
+const TAG: usize = 16;
+// Longest file name most filesystems take.
+const MAX_NAME: usize = 255;
+// Of encrypted files, unless their names are encrypted too.
+const EXTENSION: &str = "enc";
+// Kept at the top of a tree with encrypted names, holding the salt they are encrypted with.
+pub(crate) const SALT_FILE: &str = ".rfc-salt";
+const SALT_MAGIC: &[u8; 8] = b"rfc-slt\x01";
+const SALT: usize = 16;
+
+pub(crate) type Salt = [u8; SALT];
+
+/// The key files are encrypted with, see [`CopyBuilder::set_encrypt`].
+///
+/// [`CopyBuilder::set_encrypt`]: crate::CopyBuilder::set_encrypt
+#[derive(Clone)]
+pub struct EncryptionKey(Secret);
+
+#[derive(Clone)]
+enum Secret {
+    Key([u8; 32]),
+    Passphrase(String),
+}
+
+impl EncryptionKey {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self(Secret::Key(key))
+    }
+
+    /// Read a key file, which holds the 32 bytes of the key, e.g. as made by
+    /// `head -c 32 /dev/urandom`.
+    pub fn from_keyfile(path: &Path) -> io::Result<Self> {
+        let key = fs::read(path)?;
+        let key = key.try_into().map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, "a key file holds exactly 32 bytes")
+        })?;
+        Ok(Self::new(key))
+    }
+
+    /// Derive keys from a passphrase with Argon2id. Each run salts them at random, and stores
+    /// the salt with what it encrypts, so equal passphrases don't give equal keys.
+    pub fn from_passphrase(passphrase: &str) -> Self {
+        Self(Secret::Passphrase(passphrase.to_owned()))
+    }
+
+    // The key for `salt`, from which the keys of each use are derived.
+    fn derive(&self, salt: &Salt) -> [u8; 32] {
+        match &self.0 {
+            Secret::Key(key) => subkey(key, salt),
+            Secret::Passphrase(passphrase) => {
+                let mut key = [0; 32];
+                Argon2::default()
+                    .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+                    .expect("Argon2 takes any passphrase");
+                key
+            }
+        }
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EncryptionKey(..)")
+    }
+}
+
+// Encrypts and decrypts file data and names with keys derived from an `EncryptionKey`, one
+// for each use and salt.
+pub(crate) struct Cipher {
+    key: EncryptionKey,
+    // Data written is encrypted with the key of this salt, random for each run.
+    salt: Salt,
+    // Data keys by salt, derived once: most files read back share the salt of the run that
+    // wrote them.
+    data_keys: Mutex<HashMap<Salt, XChaCha20Poly1305>>,
+    names: Option<NameKeys>,
+}
+
+struct NameKeys {
+    aead: XChaCha20Poly1305,
+    nonces: [u8; 32],
+}
+
+impl Cipher {
+    pub(crate) fn new(key: &EncryptionKey) -> Self {
+        Self {
+            key: key.clone(),
+            salt: random_salt(),
+            data_keys: Mutex::new(HashMap::new()),
+            names: None,
+        }
+    }
+
+    // Encrypt and decrypt names too, with the salt of the tree, see `read_salt`.
+    pub(crate) fn with_names(mut self, salt: &Salt) -> Self {
+        let key = self.key.derive(salt);
+        self.names = Some(NameKeys {
+            aead: XChaCha20Poly1305::new(&subkey(&key, b"names").into()),
+            nonces: subkey(&key, b"name nonces"),
+        });
+        self
+    }
+
+    fn data_key(&self, salt: &Salt) -> XChaCha20Poly1305 {
+        let mut keys = self.data_keys.lock().unwrap();
+        keys.entry(*salt)
+            .or_insert_with(|| {
+                let key = subkey(&self.key.derive(salt), b"data");
+                XChaCha20Poly1305::new(&key.into())
+            })
+            .clone()
+    }
+
+    // Encrypts what is written into `out`, which `EncryptWriter::finish` completes.
+    pub(crate) fn encryptor<W: Write>(&self, mut out: W) -> io::Result<EncryptWriter<W>> {
+        let mut prefix = [0; NONCE_PREFIX];
+        OsRng.fill_bytes(&mut prefix);
+        out.write_all(MAGIC)?;
+        out.write_all(&self.salt)?;
+        out.write_all(&prefix)?;
+        let stream = EncryptorBE32::from_aead(self.data_key(&self.salt), &prefix.into());
+        Ok(EncryptWriter {
+            out,
+            stream: Some(stream),
+            buf: Vec::with_capacity(CHUNK + TAG),
+        })
+    }
+
+    // The data of an encrypted file, checked chunk by chunk as it is read.
+    pub(crate) fn decryptor<R: Read>(&self, mut input: R) -> io::Result<DecryptReader<R>> {
+        let mut header = [0; MAGIC.len() + SALT + NONCE_PREFIX];
+        match read_full(&mut input, &mut header)? {
+            n if n == header.len() && header.starts_with(MAGIC) => {}
+            _ => return Err(invalid("not an encrypted file")),
+        }
+        let (salt, prefix) = header[MAGIC.len()..].split_at(SALT);
+        let key = self.data_key(salt.try_into().unwrap());
+        Ok(DecryptReader {
+            input,
+            stream: Some(DecryptorBE32::from_aead(key, prefix.into())),
+            buf: Vec::with_capacity(CHUNK + TAG),
+            pos: 0,
+        })
+    }
+
+    fn name_keys(&self) -> io::Result<&NameKeys> {
+        self.names
+            .as_ref()
+            .ok_or_else(|| io::Error::other("names aren't encrypted"))
+    }
+
+    // The same name always gives the same encrypted one, its nonce is derived from it, so
+    // later runs find the files of earlier ones. Encrypted names are URL safe base64.
+    pub(crate) fn encrypt_name(&self, name: &OsStr) -> io::Result<OsString> {
+        let keys = self.name_keys()?;
+        let plain = name.as_encoded_bytes();
+        let mut mac = <Blake2bMac<U24> as Mac>::new_from_slice(&keys.nonces).unwrap();
+        mac.update(plain);
+        let nonce = mac.finalize().into_bytes();
+        let sealed = keys
+            .aead
+            .encrypt(XNonce::from_slice(&nonce), plain)
+            .map_err(|_| io::Error::other("name encryption failed"))?;
+        let mut bytes = nonce.to_vec();
+        bytes.extend_from_slice(&sealed);
+        let encoded = Base64UrlUnpadded::encode_string(&bytes);
+        if encoded.len() > MAX_NAME {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "name too long to be encrypted",
+            ));
+        }
+        Ok(OsString::from(encoded))
+    }
+
+    pub(crate) fn decrypt_name(&self, name: &OsStr) -> io::Result<OsString> {
+        let keys = self.name_keys()?;
+        let bytes = name
+            .to_str()
+            .and_then(|name| Base64UrlUnpadded::decode_vec(name).ok())
+            .filter(|bytes| bytes.len() >= 24 + TAG)
+            .ok_or_else(|| invalid("not an encrypted name"))?;
+        let (nonce, sealed) = bytes.split_at(24);
+        let plain = keys
+            .aead
+            .decrypt(XNonce::from_slice(nonce), sealed)
+            .map_err(|_| invalid("name can't be decrypted, wrong key?"))?;
+        os_string(plain)
+    }
+}
+
+pub(crate) fn random_salt() -> Salt {
+    let mut salt = [0; SALT];
+    OsRng.fill_bytes(&mut salt);
+    salt
+}
+
+// The salt in the salt file of `dir`, if it has one.
+pub(crate) fn read_salt(fs: &dyn FileSystem, dir: &Path) -> io::Result<Option<Salt>> {
+    let mut data = vec![];
+    match fs.open(&dir.join(SALT_FILE)) {
+        Ok(mut file) => file.read_to_end(&mut data)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    match data.strip_prefix(SALT_MAGIC).and_then(|salt| salt.try_into().ok()) {
+        Some(salt) => Ok(Some(salt)),
+        None => Err(invalid("not a salt file")),
+    }
+}
+
+pub(crate) fn write_salt(fs: &dyn FileSystem, dir: &Path, salt: &Salt) -> io::Result<()> {
+    let mut file = fs.create(&dir.join(SALT_FILE))?;
+    file.write_all(SALT_MAGIC)?;
+    file.write_all(salt)?;
+    file.sync_all()
+}
+
+// A key of its own for `label`, from `key`.
+fn subkey(key: &[u8; 32], label: &[u8]) -> [u8; 32] {
+    let mut mac = <Blake2bMac<U32> as Mac>::new_from_slice(key).unwrap();
+    mac.update(label);
+    mac.finalize().into_bytes().into()
+}
+
+pub(crate) struct EncryptWriter<W: Write> {
+    out: W,
+    // Taken to seal the last chunk.
+    stream: Option<EncryptorBE32<XChaCha20Poly1305>>,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    // Seal and write the last chunk, and return the output.
+    pub(crate) fn finish(mut self) -> io::Result<W> {
+        let stream = self.stream.take().unwrap();
+        stream
+            .encrypt_last_in_place(b"", &mut self.buf)
+            .map_err(|_| io::Error::other("encryption failed"))?;
+        self.out.write_all(&self.buf)?;
+        Ok(self.out)
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let n = data.len().min(CHUNK - self.buf.len());
+        self.buf.extend_from_slice(&data[..n]);
+        if self.buf.len() == CHUNK {
+            let stream = self.stream.as_mut().unwrap();
+            stream
+                .encrypt_next_in_place(b"", &mut self.buf)
+                .map_err(|_| io::Error::other("encryption failed"))?;
+            self.out.write_all(&self.buf)?;
+            self.buf.clear();
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+pub(crate) struct DecryptReader<R> {
+    input: R,
+    // Gone once the last chunk is read.
+    stream: Option<DecryptorBE32<XChaCha20Poly1305>>,
+    // The decrypted chunk, read from `pos`.
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.buf.len() {
+            let stream = match &mut self.stream {
+                Some(stream) => stream,
+                None => return Ok(0),
+            };
+            self.buf.resize(CHUNK + TAG, 0);
+            let n = read_full(&mut self.input, &mut self.buf)?;
+            self.buf.truncate(n);
+            self.pos = 0;
+            let opened = if n == CHUNK + TAG {
+                stream.decrypt_next_in_place(b"", &mut self.buf)
+            } else {
+                let stream = self.stream.take().unwrap();
+                stream.decrypt_last_in_place(b"", &mut self.buf)
+            };
+            opened.map_err(|_| invalid("data can't be decrypted, wrong key or damaged file"))?;
+        }
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+// `path` with the extension of encrypted files added.
+pub(crate) fn encrypted_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(EXTENSION);
+    PathBuf::from(name)
+}
+
+pub(crate) fn is_encrypted(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == EXTENSION)
+}
+
+// Fill `buf` unless the input ends first; how much was read.
+fn read_full(input: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match input.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(read) => n += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+#[cfg(unix)]
+fn os_string(bytes: Vec<u8>) -> io::Result<OsString> {
+    use std::os::unix::ffi::OsStringExt;
+    Ok(OsString::from_vec(bytes))
+}
+
+#[cfg(not(unix))]
+fn os_string(bytes: Vec<u8>) -> io::Result<OsString> {
+    String::from_utf8(bytes)
+        .map(OsString::from)
+        .map_err(|_| invalid("decrypted name isn't UTF-8"))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn round_trip(cipher: &Cipher, data: &[u8]) -> Vec<u8> {
+        let mut writer = cipher.encryptor(vec![]).unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn encrypt_test() {
+        let cipher = Cipher::new(&EncryptionKey::new([7; 32]));
+        for len in [0, 10, CHUNK, 2 * CHUNK + 5] {
+            let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let sealed = round_trip(&cipher, &data);
+            let header = MAGIC.len() + SALT + NONCE_PREFIX;
+            assert_eq!(sealed.len(), header + len + (len / CHUNK + 1) * TAG);
+            let mut opened = vec![];
+            cipher.decryptor(&sealed[..]).unwrap().read_to_end(&mut opened).unwrap();
+            assert_eq!(opened, data);
+
+            // Cut at a chunk boundary, or with a byte changed.
+            let mut cut = vec![];
+            let short = &sealed[..sealed.len() - TAG];
+            assert!(cipher.decryptor(short).unwrap().read_to_end(&mut cut).is_err());
+            let mut changed = sealed.clone();
+            *changed.last_mut().unwrap() ^= 1;
+            assert!(cipher.decryptor(&changed[..]).unwrap().read_to_end(&mut cut).is_err());
+        }
+        let other = Cipher::new(&EncryptionKey::from_passphrase("not the key"));
+        let sealed = round_trip(&cipher, b"secret");
+        assert!(other.decryptor(&sealed[..]).unwrap().read_to_end(&mut vec![]).is_err());
+        assert!(cipher.decryptor(&b"plain text"[..]).is_err());
+    }
+
+    #[test]
+    fn salt_test() {
+        // The same file and passphrase, in two runs.
+        let key = EncryptionKey::from_passphrase("passphrase");
+        let (first, second) = (Cipher::new(&key), Cipher::new(&key));
+        let sealed = [round_trip(&first, b"secret"), round_trip(&second, b"secret")];
+        assert_ne!(sealed[0], sealed[1]);
+        let salts = sealed.each_ref().map(|s| &s[MAGIC.len()..MAGIC.len() + SALT]);
+        assert_ne!(salts[0], salts[1]);
+        // Either is read back with the salt it stores.
+        let reader = Cipher::new(&key);
+        for sealed in &sealed {
+            let mut opened = vec![];
+            reader.decryptor(&sealed[..]).unwrap().read_to_end(&mut opened).unwrap();
+            assert_eq!(opened, b"secret");
+        }
+        assert_eq!(reader.data_keys.lock().unwrap().len(), 2);
+
+        // Names too depend on the salt.
+        let name = OsStr::new("a.txt");
+        let encrypted = |salt| Cipher::new(&key).with_names(salt).encrypt_name(name).unwrap();
+        assert_eq!(encrypted(&[1; SALT]), encrypted(&[1; SALT]));
+        assert_ne!(encrypted(&[1; SALT]), encrypted(&[2; SALT]));
+
+        let fs = crate::MemoryFs::new();
+        assert_eq!(read_salt(&fs, Path::new("/")).unwrap(), None);
+        write_salt(&fs, Path::new("/"), &[3; SALT]).unwrap();
+        assert_eq!(read_salt(&fs, Path::new("/")).unwrap(), Some([3; SALT]));
+    }
+
+    #[test]
+    fn name_test() {
+        let cipher = Cipher::new(&EncryptionKey::new([7; 32])).with_names(&[0; SALT]);
+        let name = OsStr::new("report.pdf");
+        let encrypted = cipher.encrypt_name(name).unwrap();
+        assert_eq!(encrypted, cipher.encrypt_name(name).unwrap());
+        assert!(!encrypted.to_str().unwrap().contains(['/', '.']));
+        assert_eq!(cipher.decrypt_name(&encrypted).unwrap(), name);
+        assert!(cipher.decrypt_name(name).is_err());
+        assert!(cipher.encrypt_name(OsStr::new(&"n".repeat(200))).is_err());
+        assert!(Cipher::new(&EncryptionKey::new([7; 32])).encrypt_name(name).is_err());
+
+        let path = encrypted_path(Path::new("dir/a.txt"));
+        assert!(is_encrypted(&path) && !is_encrypted(Path::new("dir/a.txt")));
+        assert_eq!(path.with_extension(""), Path::new("dir/a.txt"));
+    }
+}
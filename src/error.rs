@@ -0,0 +1,48 @@
+use std::fmt;
+use std::io;
+
+/// Why a copy couldn't be set up or was stopped.
+///
+/// Errors on single entries don't stop a run: they are counted in the
+/// [`CopyReport`](crate::CopyReport) and published as events, and the rest is still copied.
+#[derive(Debug)]
+pub enum Error {
+    /// The builder's settings don't make a copy, e.g. no destination was set or a directory
+    /// would be copied into itself.
+    Config(String),
+    /// The run was stopped, e.g. by a conflict under [`ConflictPolicy::Fail`] or because the
+    /// destination has no room for the copy. The message names the path it happened on.
+    ///
+    /// [`ConflictPolicy::Fail`]: crate::ConflictPolicy::Fail
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Config(message) => write!(f, "{}", message),
+            Error::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Config(_) => None,
+            Error::Io(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<&str> for Error {
+    fn from(message: &str) -> Self {
+        Error::Config(String::from(message))
+    }
+}
@@ -0,0 +1,811 @@
+use crate::space::{self, Space};
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::{self, Cursor, Read, Seek, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// Type of a filesystem entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileType {
+    /// A regular file.
+    File,
+    /// A directory.
+    Dir,
+    /// A symbolic link, only reported where links aren't followed.
+    Symlink,
+    /// Devices, sockets, pipes and the like, which aren't copied.
+    Other,
+}
+
+impl FileType {
+    /// Whether this is a regular file.
+    pub fn is_file(&self) -> bool {
+        *self == FileType::File
+    }
+
+    /// Whether this is a directory.
+    pub fn is_dir(&self) -> bool {
+        *self == FileType::Dir
+    }
+
+    /// Whether this is a symbolic link.
+    pub fn is_symlink(&self) -> bool {
+        *self == FileType::Symlink
+    }
+}
+
+impl From<fs::FileType> for FileType {
+    fn from(file_type: fs::FileType) -> Self {
+        if file_type.is_symlink() {
+            FileType::Symlink
+        } else if file_type.is_dir() {
+            FileType::Dir
+        } else if file_type.is_file() {
+            FileType::File
+        } else {
+            FileType::Other
+        }
+    }
+}
+
+/// What the copy needs to know about an entry.
+#[derive(Clone, Debug)]
+pub struct Metadata {
+    /// Type of the entry.
+    pub file_type: FileType,
+    /// Size in bytes; for a symlink the length of its target path.
+    pub len: u64,
+    /// Last modification, if the filesystem keeps it.
+    pub modified: Option<SystemTime>,
+    /// Unix permission bits.
+    pub mode: u32,
+    /// Owner's user id.
+    pub uid: u32,
+    /// Owner's group id.
+    pub gid: u32,
+    /// Device the entry is on, 0 if unknown. Entries on the same device of the same filesystem
+    /// can be renamed and hard linked onto each other.
+    pub dev: u64,
+    /// Inode number on `dev`, 0 if unknown. Hard links to one file share it.
+    pub ino: u64,
+    /// Number of hard links to the entry.
+    pub nlink: u64,
+}
+
+impl From<fs::Metadata> for Metadata {
+    #[cfg(unix)]
+    fn from(metadata: fs::Metadata) -> Self {
+        use std::os::unix::fs::MetadataExt;
+        Self {
+            file_type: metadata.file_type().into(),
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            mode: metadata.mode(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+            nlink: metadata.nlink(),
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn from(metadata: fs::Metadata) -> Self {
+        Self {
+            file_type: metadata.file_type().into(),
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            mode: if metadata.permissions().readonly() { 0o444 } else { 0o644 },
+            uid: 0,
+            gid: 0,
+            dev: 0,
+            ino: 0,
+            nlink: 1,
+        }
+    }
+}
+
+/// An entry of a directory listing.
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    /// Name of the entry in its directory.
+    pub name: OsString,
+    /// Type of the entry, symlinks not followed.
+    pub file_type: FileType,
+}
+
+/// A file being written by the copy.
+pub trait WriteFile: Write + Send {
+    /// Flush the data to the device.
+    fn sync_all(&mut self) -> io::Result<()>;
+}
+
+impl WriteFile for fs::File {
+    fn sync_all(&mut self) -> io::Result<()> {
+        fs::File::sync_all(self)
+    }
+}
+
+/// A file being read out of order, like a zip archive.
+pub trait ReadFile: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> ReadFile for T {}
+
+/// Listing of a directory, entry by entry.
+pub type ReadDir = Box<dyn Iterator<Item = io::Result<DirEntry>> + Send>;
+
+/// Extended attributes of an entry, as names and values.
+pub type Xattrs = Vec<(OsString, Vec<u8>)>;
+
+/// Where the copy reads sources from and writes to. The operations mirror their `std::fs`
+/// namesakes, so errors are expected to have the same kinds.
+pub trait FileSystem: Send + Sync {
+    /// Entries of the directory at `path`, without `.` and `..`.
+    fn read_dir(&self, path: &Path) -> io::Result<ReadDir>;
+
+    /// Metadata of `path`, following symlinks.
+    fn metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+    /// Metadata of `path` itself, also if it is a symlink.
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+    /// Absolute form of the existing `path` with symlinks resolved.
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf>;
+
+    /// Open a file for reading, following symlinks.
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>>;
+
+    /// Create a file for writing, or truncate an existing one.
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WriteFile>>;
+
+    /// Open a file for reading at any offset, following symlinks.
+    fn open_seekable(&self, _path: &Path) -> io::Result<Box<dyn ReadFile>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "files can only be read in order",
+        ))
+    }
+
+    /// Create a directory and any missing parents.
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Remove a file or symlink.
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Remove an empty directory.
+    fn remove_dir(&self, path: &Path) -> io::Result<()>;
+
+    /// Rename `from` to `to`, replacing a file or empty directory there.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Make `link` another name of the file `original`.
+    fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()>;
+
+    /// Target of the symlink at `path`.
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf>;
+
+    /// Create a symlink at `link` pointing to `target`.
+    fn symlink(&self, target: &Path, link: &Path) -> io::Result<()>;
+
+    /// Set the unix permission bits of `path`.
+    fn set_permissions(&self, path: &Path, mode: u32) -> io::Result<()>;
+
+    /// Whether `path` exists, following symlinks.
+    fn exists(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok()
+    }
+
+    /// Whether `path` is a directory, following symlinks.
+    fn is_dir(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok_and(|m| m.file_type.is_dir())
+    }
+
+    /// Flush the entries of the directory at `path` to the device.
+    fn sync_dir(&self, path: &Path) -> io::Result<()>;
+
+    /// Flush everything written to the filesystem `path` is on.
+    fn sync_fs(&self, path: &Path) -> io::Result<()>;
+
+    /// Set the modification time of `path`, following symlinks.
+    fn set_modified(&self, _path: &Path, _modified: SystemTime) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "modification times can't be set",
+        ))
+    }
+
+    /// Extended attributes of `path` itself, not following symlinks. Filesystems without them
+    /// have none.
+    fn xattrs(&self, _path: &Path) -> io::Result<Xattrs> {
+        Ok(vec![])
+    }
+
+    /// Set the extended attribute `name` of `path` itself.
+    fn set_xattr(&self, _path: &Path, _name: &OsStr, _value: &[u8]) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "extended attributes are not supported",
+        ))
+    }
+
+    /// Room left on the filesystem `path` is on, or would be on.
+    fn available(&self, _path: &Path) -> io::Result<Space> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "free space is unknown",
+        ))
+    }
+}
+
+/// The local filesystem, through `std::fs`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LocalFs;
+
+impl FileSystem for LocalFs {
+    fn read_dir(&self, path: &Path) -> io::Result<ReadDir> {
+        let entries = fs::read_dir(path)?.map(|entry| {
+            let entry = entry?;
+            Ok(DirEntry {
+                file_type: entry.file_type()?.into(),
+                name: entry.file_name(),
+            })
+        });
+        Ok(Box::new(entries))
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        fs::metadata(path).map(Metadata::from)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        fs::symlink_metadata(path).map(Metadata::from)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::canonicalize(path)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(fs::File::open(path)?))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WriteFile>> {
+        Ok(Box::new(fs::File::create(path)?))
+    }
+
+    fn open_seekable(&self, path: &Path) -> io::Result<Box<dyn ReadFile>> {
+        Ok(Box::new(fs::File::open(path)?))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()> {
+        fs::hard_link(original, link)
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::read_link(path)
+    }
+
+    #[cfg(unix)]
+    fn symlink(&self, target: &Path, link: &Path) -> io::Result<()> {
+        std::os::unix::fs::symlink(target, link)
+    }
+
+    #[cfg(not(unix))]
+    fn symlink(&self, _target: &Path, _link: &Path) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "symlinks are only made on unix",
+        ))
+    }
+
+    #[cfg(unix)]
+    fn set_permissions(&self, path: &Path, mode: u32) -> io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+    }
+
+    #[cfg(not(unix))]
+    fn set_permissions(&self, path: &Path, mode: u32) -> io::Result<()> {
+        let mut permissions = fs::metadata(path)?.permissions();
+        permissions.set_readonly(mode & 0o222 == 0);
+        fs::set_permissions(path, permissions)
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        crate::sync::sync_dir(path)
+    }
+
+    fn set_modified(&self, path: &Path, modified: SystemTime) -> io::Result<()> {
+        filetime::set_file_mtime(path, filetime::FileTime::from_system_time(modified))
+    }
+
+    #[cfg(unix)]
+    fn xattrs(&self, path: &Path) -> io::Result<Xattrs> {
+        let mut xattrs = vec![];
+        for name in xattr::list(path)? {
+            // Removed since it was listed.
+            if let Some(value) = xattr::get(path, &name)? {
+                xattrs.push((name, value));
+            }
+        }
+        Ok(xattrs)
+    }
+
+    #[cfg(unix)]
+    fn set_xattr(&self, path: &Path, name: &OsStr, value: &[u8]) -> io::Result<()> {
+        xattr::set(path, name, value)
+    }
+
+    fn sync_fs(&self, path: &Path) -> io::Result<()> {
+        crate::sync::sync_fs(path)
+    }
+
+    fn available(&self, path: &Path) -> io::Result<Space> {
+        space::available(path)
+    }
+}
+
+// Symlinks pointing to symlinks are followed this many times at most, as on Linux.
+const MAX_SYMLINK_HOPS: usize = 40;
+
+/// A filesystem kept in memory, to test copies without touching the disk. Paths must be
+/// absolute. Only a symlink at the end of a path is followed, not ones leading to it.
+pub struct MemoryFs {
+    entries: Mutex<BTreeMap<PathBuf, MemEntry>>,
+}
+
+#[derive(Clone)]
+struct MemEntry {
+    kind: MemKind,
+    mode: u32,
+    modified: SystemTime,
+    xattrs: BTreeMap<OsString, Vec<u8>>,
+}
+
+#[derive(Clone)]
+enum MemKind {
+    // Hard links share the data.
+    File(Arc<Mutex<Vec<u8>>>),
+    Dir,
+    Symlink(PathBuf),
+}
+
+impl MemEntry {
+    fn new(kind: MemKind) -> Self {
+        let mode = match kind {
+            MemKind::File(_) => 0o644,
+            MemKind::Dir => 0o755,
+            MemKind::Symlink(_) => 0o777,
+        };
+        Self {
+            kind,
+            mode,
+            modified: SystemTime::now(),
+            xattrs: BTreeMap::new(),
+        }
+    }
+
+    // Called on the entry in the map, so that the data's reference count is its number of links.
+    fn metadata(&self) -> Metadata {
+        let (file_type, len) = match &self.kind {
+            MemKind::File(data) => (FileType::File, data.lock().unwrap().len() as u64),
+            MemKind::Dir => (FileType::Dir, 0),
+            MemKind::Symlink(target) => (FileType::Symlink, target.as_os_str().len() as u64),
+        };
+        // Hard links share the data, whose address serves as inode number.
+        let (ino, nlink) = match &self.kind {
+            MemKind::File(data) => (Arc::as_ptr(data) as u64, Arc::strong_count(data) as u64),
+            _ => (0, 1),
+        };
+        Metadata {
+            file_type,
+            len,
+            modified: Some(self.modified),
+            mode: self.mode,
+            uid: 0,
+            gid: 0,
+            dev: 1,
+            ino,
+            nlink,
+        }
+    }
+}
+
+impl Default for MemoryFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryFs {
+    /// An empty filesystem, only `/` exists.
+    pub fn new() -> Self {
+        let mut entries = BTreeMap::new();
+        entries.insert(PathBuf::from("/"), MemEntry::new(MemKind::Dir));
+        Self {
+            entries: Mutex::new(entries),
+        }
+    }
+
+    /// Write a file, creating its parent directories.
+    pub fn write_file(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            self.create_dir_all(parent)?;
+        }
+        self.create(path)?.write_all(data)
+    }
+
+    /// The data of a file.
+    pub fn read_file(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let mut data = vec![];
+        self.open(path)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    // `path` with `.` and `..` resolved lexically.
+    fn normalize(path: &Path) -> io::Result<PathBuf> {
+        if !path.is_absolute() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "paths of a MemoryFs must be absolute",
+            ));
+        }
+        let mut normalized = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    normalized.pop();
+                }
+                c => normalized.push(c),
+            }
+        }
+        Ok(normalized)
+    }
+
+    // The entry at `path` and its path, after following a final symlink if `follow`.
+    fn resolve(
+        entries: &BTreeMap<PathBuf, MemEntry>,
+        path: &Path,
+        follow: bool,
+    ) -> io::Result<(PathBuf, MemEntry)> {
+        let mut path = Self::normalize(path)?;
+        for _ in 0..MAX_SYMLINK_HOPS {
+            let entry = entries
+                .get(&path)
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+            match &entry.kind {
+                MemKind::Symlink(target) if follow => {
+                    let dir = path.parent().unwrap_or(Path::new("/"));
+                    path = Self::normalize(&dir.join(target))?;
+                }
+                _ => return Ok((path, entry.clone())),
+            }
+        }
+        Err(io::Error::other("too many levels of symbolic links"))
+    }
+
+    // Normalized `path`, whose parent has to be an existing directory.
+    fn in_dir(entries: &BTreeMap<PathBuf, MemEntry>, path: &Path) -> io::Result<PathBuf> {
+        let path = Self::normalize(path)?;
+        let parent = path
+            .parent()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        match entries.get(parent).map(|e| &e.kind) {
+            Some(MemKind::Dir) => Ok(path),
+            Some(_) => Err(io::Error::from(io::ErrorKind::NotADirectory)),
+            None => Err(io::Error::from(io::ErrorKind::NotFound)),
+        }
+    }
+
+    fn has_children(entries: &BTreeMap<PathBuf, MemEntry>, path: &Path) -> bool {
+        entries
+            .range(path.to_path_buf()..)
+            .nth(1)
+            .is_some_and(|(p, _)| p.starts_with(path))
+    }
+}
+
+struct MemFile(Arc<Mutex<Vec<u8>>>);
+
+impl Write for MemFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl WriteFile for MemFile {
+    fn sync_all(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl FileSystem for MemoryFs {
+    fn read_dir(&self, path: &Path) -> io::Result<ReadDir> {
+        let entries = self.entries.lock().unwrap();
+        let (dir, entry) = Self::resolve(&entries, path, true)?;
+        if !matches!(entry.kind, MemKind::Dir) {
+            return Err(io::Error::from(io::ErrorKind::NotADirectory));
+        }
+        // Descendants of `dir` sort right after it.
+        let children: Vec<io::Result<DirEntry>> = entries
+            .range(dir.clone()..)
+            .skip(1)
+            .take_while(|(p, _)| p.starts_with(&dir))
+            .filter(|(p, _)| p.parent() == Some(&dir))
+            .map(|(p, e)| {
+                Ok(DirEntry {
+                    name: p.file_name().unwrap_or_default().to_os_string(),
+                    file_type: e.metadata().file_type,
+                })
+            })
+            .collect();
+        Ok(Box::new(children.into_iter()))
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let entries = self.entries.lock().unwrap();
+        let (path, _) = Self::resolve(&entries, path, true)?;
+        Ok(entries[&path].metadata())
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let entries = self.entries.lock().unwrap();
+        let (path, _) = Self::resolve(&entries, path, false)?;
+        Ok(entries[&path].metadata())
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        let entries = self.entries.lock().unwrap();
+        Self::resolve(&entries, path, true).map(|(p, _)| p)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        let entries = self.entries.lock().unwrap();
+        match Self::resolve(&entries, path, true)?.1.kind {
+            MemKind::File(data) => Ok(Box::new(Cursor::new(data.lock().unwrap().clone()))),
+            _ => Err(io::Error::from(io::ErrorKind::IsADirectory)),
+        }
+    }
+
+    fn open_seekable(&self, path: &Path) -> io::Result<Box<dyn ReadFile>> {
+        let entries = self.entries.lock().unwrap();
+        match Self::resolve(&entries, path, true)?.1.kind {
+            MemKind::File(data) => Ok(Box::new(Cursor::new(data.lock().unwrap().clone()))),
+            _ => Err(io::Error::from(io::ErrorKind::IsADirectory)),
+        }
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WriteFile>> {
+        let mut entries = self.entries.lock().unwrap();
+        let path = match Self::resolve(&entries, path, true) {
+            Ok((path, _)) => path,
+            Err(_) => Self::in_dir(&entries, path)?,
+        };
+        let entry = entries.get_mut(&path);
+        let data = match entry.as_ref().map(|e| &e.kind) {
+            Some(MemKind::File(data)) => {
+                data.lock().unwrap().clear();
+                data.clone()
+            }
+            Some(_) => return Err(io::Error::from(io::ErrorKind::IsADirectory)),
+            None => Arc::new(Mutex::new(vec![])),
+        };
+        match entry {
+            Some(entry) => entry.modified = SystemTime::now(),
+            None => {
+                let entry = MemEntry::new(MemKind::File(data.clone()));
+                entries.insert(path, entry);
+            }
+        }
+        Ok(Box::new(MemFile(data)))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        let path = Self::normalize(path)?;
+        for dir in path.ancestors().collect::<Vec<_>>().into_iter().rev() {
+            match Self::resolve(&entries, dir, true) {
+                Ok((_, entry)) if matches!(entry.kind, MemKind::Dir) => {}
+                Ok(_) => return Err(io::Error::from(io::ErrorKind::NotADirectory)),
+                Err(_) => {
+                    entries.insert(dir.to_path_buf(), MemEntry::new(MemKind::Dir));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        let (path, entry) = Self::resolve(&entries, path, false)?;
+        if matches!(entry.kind, MemKind::Dir) {
+            return Err(io::Error::from(io::ErrorKind::IsADirectory));
+        }
+        entries.remove(&path);
+        Ok(())
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        let (path, entry) = Self::resolve(&entries, path, false)?;
+        if !matches!(entry.kind, MemKind::Dir) {
+            return Err(io::Error::from(io::ErrorKind::NotADirectory));
+        }
+        if Self::has_children(&entries, &path) {
+            return Err(io::Error::from(io::ErrorKind::DirectoryNotEmpty));
+        }
+        entries.remove(&path);
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        let (from, entry) = Self::resolve(&entries, from, false)?;
+        let to = Self::in_dir(&entries, to)?;
+        if from == to {
+            return Ok(());
+        }
+        let is_dir = matches!(entry.kind, MemKind::Dir);
+        if is_dir && to.starts_with(&from) {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        match entries.get(&to).map(|e| matches!(e.kind, MemKind::Dir)) {
+            Some(true) if !is_dir => return Err(io::Error::from(io::ErrorKind::IsADirectory)),
+            Some(true) if Self::has_children(&entries, &to) => {
+                return Err(io::Error::from(io::ErrorKind::DirectoryNotEmpty))
+            }
+            Some(false) if is_dir => return Err(io::Error::from(io::ErrorKind::NotADirectory)),
+            _ => {}
+        }
+        entries.remove(&to);
+        let moved: Vec<PathBuf> = entries
+            .range(from.clone()..)
+            .take_while(|(p, _)| p.starts_with(&from))
+            .map(|(p, _)| p.clone())
+            .collect();
+        for path in moved {
+            let entry = entries.remove(&path).unwrap();
+            let rest = path.strip_prefix(&from).unwrap();
+            let new_path = if rest.as_os_str().is_empty() {
+                to.clone()
+            } else {
+                to.join(rest)
+            };
+            entries.insert(new_path, entry);
+        }
+        Ok(())
+    }
+
+    fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        let (_, entry) = Self::resolve(&entries, original, false)?;
+        if matches!(entry.kind, MemKind::Dir) {
+            return Err(io::Error::from(io::ErrorKind::PermissionDenied));
+        }
+        let link = Self::in_dir(&entries, link)?;
+        if entries.contains_key(&link) {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+        }
+        entries.insert(link, entry);
+        Ok(())
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        let entries = self.entries.lock().unwrap();
+        match Self::resolve(&entries, path, false)?.1.kind {
+            MemKind::Symlink(target) => Ok(target),
+            _ => Err(io::Error::from(io::ErrorKind::InvalidInput)),
+        }
+    }
+
+    fn symlink(&self, target: &Path, link: &Path) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        let link = Self::in_dir(&entries, link)?;
+        if entries.contains_key(&link) {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+        }
+        let entry = MemEntry::new(MemKind::Symlink(target.to_path_buf()));
+        entries.insert(link, entry);
+        Ok(())
+    }
+
+    fn set_permissions(&self, path: &Path, mode: u32) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        let (path, _) = Self::resolve(&entries, path, true)?;
+        entries.get_mut(&path).unwrap().mode = mode;
+        Ok(())
+    }
+
+    // Written entries are already as lasting as they get.
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        self.metadata(path).map(|_| ())
+    }
+
+    fn sync_fs(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_modified(&self, path: &Path, modified: SystemTime) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        let (path, _) = Self::resolve(&entries, path, true)?;
+        entries.get_mut(&path).unwrap().modified = modified;
+        Ok(())
+    }
+
+    fn xattrs(&self, path: &Path) -> io::Result<Xattrs> {
+        let entries = self.entries.lock().unwrap();
+        let (_, entry) = Self::resolve(&entries, path, false)?;
+        Ok(entry.xattrs.into_iter().collect())
+    }
+
+    fn set_xattr(&self, path: &Path, name: &OsStr, value: &[u8]) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        let (path, _) = Self::resolve(&entries, path, false)?;
+        let xattrs = &mut entries.get_mut(&path).unwrap().xattrs;
+        xattrs.insert(name.to_os_string(), value.to_vec());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn memory_fs_test() {
+        let fs = MemoryFs::new();
+        let dir = Path::new("/a/b");
+        fs.write_file(&dir.join("f"), b"data").unwrap();
+        fs.write_file(Path::new("/a b"), b"").unwrap();
+        fs.symlink(Path::new("b/f"), Path::new("/a/link")).unwrap();
+        assert_eq!(fs.read_file(Path::new("/a/link")).unwrap(), b"data");
+        assert!(fs.symlink_metadata(Path::new("/a/link")).unwrap().file_type.is_symlink());
+
+        let mut names: Vec<OsString> = fs
+            .read_dir(Path::new("/a"))
+            .unwrap()
+            .map(|e| e.unwrap().name)
+            .collect();
+        names.sort();
+        assert_eq!(names, vec![OsString::from("b"), OsString::from("link")]);
+
+        fs.hard_link(&dir.join("f"), &dir.join("g")).unwrap();
+        fs.create(&dir.join("g")).unwrap().write_all(b"new").unwrap();
+        assert_eq!(fs.read_file(&dir.join("f")).unwrap(), b"new");
+        let (f, g) = (fs.metadata(&dir.join("f")).unwrap(), fs.metadata(&dir.join("g")).unwrap());
+        assert_eq!((f.ino, f.nlink), (g.ino, 2));
+
+        let err = fs.remove_dir(dir).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::DirectoryNotEmpty);
+        fs.rename(dir, Path::new("/c")).unwrap();
+        assert_eq!(fs.read_file(Path::new("/c/g")).unwrap(), b"new");
+        assert!(!fs.exists(dir));
+        let err = fs.create_dir_all(Path::new("/c/f/x")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotADirectory);
+    }
+}
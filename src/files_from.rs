@@ -0,0 +1,89 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+
+/// Read the paths for `--files-from` from `source`, `-` being stdin.
+pub fn read_file_list(source: &str) -> Result<Vec<PathBuf>, io::Error> {
+    let mut data = vec![];
+    if source == "-" {
+        io::stdin().lock().read_to_end(&mut data)?;
+    } else {
+        File::open(source)?.read_to_end(&mut data)?;
+    }
+    Ok(parse_file_list(&data))
+}
+
+/// Entries are NUL separated if there is any NUL (as from `find -print0`), otherwise one per
+/// line. Empty entries are dropped.
+pub fn parse_file_list(data: &[u8]) -> Vec<PathBuf> {
+    let nul_separated = data.contains(&0);
+    data.split(|b| if nul_separated { *b == 0 } else { *b == b'\n' })
+        .map(|entry| {
+            if !nul_separated && entry.ends_with(b"\r") {
+                &entry[..entry.len() - 1]
+            } else {
+                entry
+            }
+        })
+        .filter(|entry| !entry.is_empty())
+        .map(bytes_to_path)
+        .collect()
+}
+
+#[cfg(unix)]
+fn bytes_to_path(bytes: &[u8]) -> PathBuf {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    PathBuf::from(OsStr::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+fn bytes_to_path(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+// Listed paths are taken relative to the source, `./` prefixes are fine but nothing may lead
+// out of it.
+pub fn normalize_listed(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    if normalized.as_os_str().is_empty() {
+        None
+    } else {
+        Some(normalized)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_file_list_test() {
+        let lines = parse_file_list(b"a/b.txt\r\n./c d\n\nlast");
+        assert_eq!(
+            lines,
+            vec![
+                PathBuf::from("a/b.txt"),
+                PathBuf::from("./c d"),
+                PathBuf::from("last")
+            ]
+        );
+        let nul = parse_file_list(b"./x\ny\0z\0");
+        assert_eq!(nul, vec![PathBuf::from("./x\ny"), PathBuf::from("z")]);
+
+        assert_eq!(
+            normalize_listed(Path::new("./a/./b")),
+            Some(PathBuf::from("a/b"))
+        );
+        assert_eq!(normalize_listed(Path::new("a/../../b")), None);
+        assert_eq!(normalize_listed(Path::new("/etc/passwd")), None);
+        assert_eq!(normalize_listed(Path::new(".")), None);
+    }
+}
@@ -0,0 +1,108 @@
+use globset::{GlobBuilder, GlobMatcher};
+use std::path::Path;
+
+#[derive(Clone)]
+struct Rule {
+    include: bool,
+    // Patterns containing a `/` are matched against the whole path relative to the copy root,
+    // others against the entry name only, at any depth.
+    anchored: bool,
+    // A trailing `/` restricts the pattern to directories.
+    dir_only: bool,
+    matcher: GlobMatcher,
+}
+
+/// Ordered include/exclude glob rules in the manner of rsync: the last rule matching an entry
+/// decides, entries no rule matches are copied.
+#[derive(Clone, Default)]
+pub struct Filter {
+    rules: Vec<Rule>,
+}
+
+impl Filter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Add a rule copying what matches `pattern`, even if an earlier rule excludes it.
+    pub fn include(&mut self, pattern: &str) -> Result<(), globset::Error> {
+        self.push(true, pattern)
+    }
+
+    /// Add a rule skipping what matches `pattern`. Patterns with a `/` match the path relative
+    /// to the source, others the name at any depth; a trailing `/` matches directories only.
+    pub fn exclude(&mut self, pattern: &str) -> Result<(), globset::Error> {
+        self.push(false, pattern)
+    }
+
+    fn push(&mut self, include: bool, pattern: &str) -> Result<(), globset::Error> {
+        let dir_only = pattern.len() > 1 && pattern.ends_with('/');
+        let pattern = pattern.trim_end_matches('/');
+        let anchored = pattern.contains('/');
+        let pattern = pattern.trim_start_matches('/');
+        let matcher = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()?
+            .compile_matcher();
+        self.rules.push(Rule {
+            include,
+            anchored,
+            dir_only,
+            matcher,
+        });
+        Ok(())
+    }
+
+    /// `depth_path` is the entry's path relative to the copy root.
+    pub fn is_excluded(&self, depth_path: &Path, is_dir: bool) -> bool {
+        let name = match depth_path.file_name() {
+            Some(name) => Path::new(name),
+            None => return false,
+        };
+        for rule in self.rules.iter().rev() {
+            if rule.dir_only && !is_dir {
+                continue;
+            }
+            let target = if rule.anchored { depth_path } else { name };
+            if rule.matcher.is_match(target) {
+                return !rule.include;
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn last_match_wins_test() {
+        let mut f = Filter::new();
+        f.exclude("*.log").unwrap();
+        f.include("keep.log").unwrap();
+        assert!(f.is_excluded(Path::new("a/b/debug.log"), false));
+        assert!(!f.is_excluded(Path::new("a/keep.log"), false));
+        assert!(!f.is_excluded(Path::new("a/main.rs"), false));
+
+        f.exclude("*").unwrap();
+        assert!(f.is_excluded(Path::new("a/keep.log"), false));
+    }
+
+    #[test]
+    fn anchored_and_dir_only_test() {
+        let mut f = Filter::new();
+        f.exclude("target/").unwrap();
+        f.exclude("/docs/*.md").unwrap();
+        assert!(f.is_excluded(Path::new("target"), true));
+        assert!(f.is_excluded(Path::new("crates/x/target"), true));
+        assert!(!f.is_excluded(Path::new("target"), false));
+        assert!(f.is_excluded(Path::new("docs/readme.md"), false));
+        assert!(!f.is_excluded(Path::new("src/docs/readme.md"), false));
+        assert!(!f.is_excluded(Path::new("docs/sub/readme.md"), false));
+    }
+}
@@ -0,0 +1,143 @@
+use crate::file_system::FileSystem;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use log::warn;
+use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+
+// Files read in every directory, later ones take precedence.
+const IGNORE_FILES: [&str; 2] = [".gitignore", ".ignore"];
+
+// Ignore rules in effect for one directory: its own `.gitignore`/`.ignore` on top of the rules
+// inherited from its ancestors. Layers are shared, so the stack handed to a subdirectory task
+// only costs a new layer when that directory has ignore files of its own.
+pub struct IgnoreStack {
+    parent: Option<Arc<IgnoreStack>>,
+    matcher: Gitignore,
+}
+
+impl IgnoreStack {
+    // Base of the stack for a copy of `from`: the user's global git excludes and, if `from` is in
+    // a repository, its `.git/info/exclude` and the ignore files of every directory from the
+    // repository root down to `from`'s parent. The ignore files of `from` itself are added by
+    // `child` like for every other directory.
+    pub fn root(fs: &dyn FileSystem, from: &Path) -> Arc<Self> {
+        let (global, err) = GitignoreBuilder::new(from).build_global();
+        if let Some(err) = err {
+            warn!("global gitignore: {}", err);
+        }
+        let mut stack = Arc::new(IgnoreStack {
+            parent: None,
+            matcher: global,
+        });
+
+        let repo = from
+            .ancestors()
+            .find(|dir| fs.symlink_metadata(&dir.join(".git")).is_ok());
+        if let Some(repo) = repo {
+            let info_exclude = repo.join(".git").join("info").join("exclude");
+            let mut builder = GitignoreBuilder::new(repo);
+            if add_file(fs, &mut builder, &info_exclude) {
+                stack = Self::push(stack, &builder);
+            }
+            // Outermost first, nearer directories take precedence.
+            let parents: Vec<&Path> = from
+                .ancestors()
+                .skip(1)
+                .take_while(|dir| dir.starts_with(repo))
+                .collect();
+            for dir in parents.into_iter().rev() {
+                stack = stack.child(fs, dir);
+            }
+        }
+        stack
+    }
+
+    pub fn child(self: &Arc<Self>, fs: &dyn FileSystem, dir: &Path) -> Arc<Self> {
+        let mut builder = GitignoreBuilder::new(dir);
+        let mut found = false;
+        for name in IGNORE_FILES {
+            found |= add_file(fs, &mut builder, &dir.join(name));
+        }
+        if found {
+            Self::push(self.clone(), &builder)
+        } else {
+            self.clone()
+        }
+    }
+
+    fn push(parent: Arc<Self>, builder: &GitignoreBuilder) -> Arc<Self> {
+        match builder.build() {
+            Ok(matcher) => Arc::new(IgnoreStack {
+                parent: Some(parent),
+                matcher,
+            }),
+            Err(err) => {
+                warn!("{}", err);
+                parent
+            }
+        }
+    }
+
+    // The innermost directory with a rule matching `path` decides, so a negation in a
+    // subdirectory can re-include what a parent ignores.
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let mut layer = Some(self);
+        while let Some(l) = layer {
+            match l.matcher.matched(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => layer = l.parent.as_deref(),
+            }
+        }
+        false
+    }
+}
+
+// Add the rules of the ignore file at `path`, read through `fs`, if there is one.
+fn add_file(fs: &dyn FileSystem, builder: &mut GitignoreBuilder, path: &Path) -> bool {
+    if !fs.metadata(path).is_ok_and(|m| m.file_type.is_file()) {
+        return false;
+    }
+    let mut text = String::new();
+    if let Err(err) = fs.open(path).and_then(|mut file| file.read_to_string(&mut text)) {
+        warn!("{:?}: {}", path, err);
+        return false;
+    }
+    for line in text.lines() {
+        if let Err(err) = builder.add_line(Some(path.to_path_buf()), line) {
+            warn!("{:?}: {}", path, err);
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::file_system::LocalFs;
+    use std::fs;
+
+    #[test]
+    fn nested_ignore_test() {
+        fs::create_dir_all("./test_dir/gitignore_test").unwrap();
+        let root = fs::canonicalize("./test_dir/gitignore_test").unwrap();
+        let sub = root.join("sub");
+        fs::create_dir_all(&sub).unwrap();
+        fs::write(root.join(".gitignore"), "*.log\n/build/\n").unwrap();
+        fs::write(sub.join(".ignore"), "!keep.log\n").unwrap();
+
+        let top = IgnoreStack::root(&LocalFs, &root).child(&LocalFs, &root);
+        assert!(top.is_ignored(&root.join("a.log"), false));
+        assert!(top.is_ignored(&root.join("build"), true));
+        assert!(!top.is_ignored(&root.join("build"), false));
+        assert!(!top.is_ignored(&root.join("main.rs"), false));
+
+        let nested = top.child(&LocalFs, &sub);
+        assert!(nested.is_ignored(&sub.join("other.log"), false));
+        assert!(!nested.is_ignored(&sub.join("keep.log"), false));
+        // `/build/` is anchored to the directory of the `.gitignore` declaring it.
+        assert!(!nested.is_ignored(&sub.join("build"), true));
+    }
+}
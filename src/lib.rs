@@ -0,0 +1,81 @@
+//! Multi-threaded copy of directory trees, focused on large numbers of small files.
+//!
+//! A copy is set up with [`CopyBuilder`] and started with [`Copyer::run`]:
+//!
+//! ```no_run
+//! use r_fast_copy::{ConflictPolicy, Copyer};
+//!
+//! let report = Copyer::builder()
+//!     .add_from("photos/")
+//!     .set_to("/mnt/backup/photos")
+//!     .set_threads_number(8)
+//!     .set_conflict_policy(ConflictPolicy::Newer)
+//!     .set_quiet(true)
+//!     .build()?
+//!     .run()?;
+//! println!("{} files, {} bytes", report.files, report.bytes);
+//! # Ok::<(), r_fast_copy::Error>(())
+//! ```
+//!
+//! Per entry progress is published as [`Event`]s through an [`EventWriter`] or, for async
+//! callers running the copy with [`Copyer::run_async`], an [`EventStream`]. Embedders can also
+//! hook in with a [`CopyObserver`]. A running copy is stopped with the [`CancelToken`] it was
+//! built with.
+//!
+//! Files can be compressed, with [`CopyBuilder::set_compress`], or encrypted, with
+//! [`CopyBuilder::set_encrypt`], one by one as they are copied, on the copying threads.
+//!
+//! Instead of a destination, [`CopyBuilder::set_to_tar`] has the sources written into a tar
+//! archive, and [`CopyBuilder::set_from_tar`] extracts one in place of sources;
+//! [`CopyBuilder::set_to_zip`] and [`CopyBuilder::set_from_zip`] do the same with zip archives.
+//!
+//! All file access goes through a [`FileSystem`], the local one unless
+//! [`CopyBuilder::set_file_system`] says otherwise. [`MemoryFs`] keeps a tree in memory, to
+//! test code driving a copy without touching the disk.
+
+mod attr_filter;
+mod backup;
+mod cancel;
+mod compression;
+mod conflict;
+mod copy;
+mod date;
+mod dir_tree;
+mod encryption;
+mod error;
+mod file_system;
+mod files_from;
+mod filter;
+mod gitignore;
+mod observer;
+mod output;
+mod pool;
+mod progress;
+mod report;
+mod space;
+mod stats;
+mod sync;
+mod task;
+mod zip_format;
+
+pub use crate::attr_filter::{parse_age, parse_size, AttrFilter, EntryType};
+pub use crate::backup::BackupMode;
+pub use crate::cancel::CancelToken;
+pub use crate::compression::Compression;
+pub use crate::conflict::ConflictPolicy;
+pub use crate::copy::{CopyBuilder, Copyer};
+pub use crate::encryption::EncryptionKey;
+pub use crate::error::Error;
+pub use crate::file_system::{
+    DirEntry, FileSystem, FileType, LocalFs, MemoryFs, Metadata, ReadDir, ReadFile, WriteFile,
+    Xattrs,
+};
+pub use crate::files_from::{parse_file_list, read_file_list};
+pub use crate::filter::Filter;
+pub use crate::observer::CopyObserver;
+pub use crate::output::{ConflictCounts, DryRunCounts, Event, EventWriter, OutputFormat};
+pub use crate::report::CopyReport;
+pub use crate::space::Space;
+pub use crate::sync::SyncPolicy;
+pub use crate::task::{CopyTask, EventStream};
+pub use crate::zip_format::ZipCompression;
@@ -1,15 +1,17 @@
-mod copy;
-mod dir_tree;
-mod pool;
 mod test_gen;
 
-use crate::copy::Copyer;
-use crate::pool::ThreadPool;
 use crate::test_gen::TestDirGenerator;
-use clap::{Parser, Subcommand};
-use std::fs::create_dir_all;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+use log::LevelFilter;
+use r_fast_copy::{
+    parse_age, parse_size, read_file_list, AttrFilter, BackupMode, CancelToken, Compression,
+    ConflictPolicy, Copyer, EncryptionKey, EntryType, EventWriter, Filter, OutputFormat,
+    SyncPolicy, ZipCompression,
+};
+use std::fs::{create_dir_all, File};
+use std::io::{self, BufRead, BufReader, IsTerminal};
 use std::path::{Path, PathBuf};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 #[derive(Subcommand, Debug)]
 enum SubCommands {
@@ -25,6 +27,14 @@ enum SubCommands {
         threshold: Option<f64>,
     },
 
+    /// Move sources to the destination: renamed where possible, otherwise copied with the copy
+    /// options and removed once copied. Emptied source directories are removed
+    Mv {
+        ///Sources to move followed by the destination
+        #[clap(value_parser, value_name = "PATHS")]
+        paths: Vec<String>,
+    },
+
     /// Benchmark, show copy cost time when threads number is 0(single-thread), 4, 8, 16, 32, 64
     /// and repeat 3 times. !!!Not for you to use.
     Benchmark
@@ -63,6 +73,8 @@ impl SubCommands {
 
                 g.build().unwrap().gen().unwrap()
             }
+            // Runs like a copy, see `main`.
+            SubCommands::Mv { .. } => {}
             SubCommands::Benchmark => {
                 let mut t_s = 0f64;
                 let mut t_4 = 0f64;
@@ -76,12 +88,12 @@ impl SubCommands {
 
                 for i in 0..3 {
                     println!("-------{} loop start--------", i);
-                    let builder = Copyer::builder().set_from(&from);
+                    let builder = Copyer::builder().set_from(from);
 
                     let now = Instant::now();
                     let to = format!("./test_dir/origin_2_cp_{}t_{}", 's', i);
                     let builder_t_s = builder.clone().set_to(&to);
-                    builder_t_s.build().unwrap().run();
+                    builder_t_s.build().unwrap().run().unwrap();
                     let elapsed_time = now.elapsed();
                     t_s += elapsed_time.as_millis() as f64;
                     println!("Single thread: {}", elapsed_time.as_millis());
@@ -90,7 +102,7 @@ impl SubCommands {
                     let now = Instant::now();
                     let to = format!("./test_dir/origin_2_cp_{}t_{}", 4,i);
                     let builder_t_4 = builder.clone().set_to(&to);
-                    builder_t_4.set_threads_number(4).build().unwrap().run();
+                    builder_t_4.set_threads_number(4).build().unwrap().run().unwrap();
                     let elapsed_time = now.elapsed();
                     t_4 += elapsed_time.as_millis() as f64;
                     println!("4 threads: {}", elapsed_time.as_millis());
@@ -99,7 +111,7 @@ impl SubCommands {
                     let now = Instant::now();
                     let to = format!("./test_dir/origin_2_cp_{}t_{}", 8,i);
                     let builder_t_8 = builder.clone().set_to(&to);
-                    builder_t_8.set_threads_number(4).build().unwrap().run();
+                    builder_t_8.set_threads_number(4).build().unwrap().run().unwrap();
                     let elapsed_time = now.elapsed();
                     t_8 += elapsed_time.as_millis() as f64;
                     println!("8 threads: {}", elapsed_time.as_millis());
@@ -108,7 +120,7 @@ impl SubCommands {
                     let now = Instant::now();
                     let to = format!("./test_dir/origin_2_cp_{}t_{}", 16,i);
                     let builder_t_16 = builder.clone().set_to(&to);
-                    builder_t_16.set_threads_number(4).build().unwrap().run();
+                    builder_t_16.set_threads_number(4).build().unwrap().run().unwrap();
                     let elapsed_time = now.elapsed();
                     t_16 += elapsed_time.as_millis() as f64;
                     println!("16 threads: {}", elapsed_time.as_millis());
@@ -117,7 +129,7 @@ impl SubCommands {
                     let now = Instant::now();
                     let to = format!("./test_dir/origin_2_cp_{}t_{}", 32,i);
                     let builder_t_32 = builder.clone().set_to(&to);
-                    builder_t_32.set_threads_number(4).build().unwrap().run();
+                    builder_t_32.set_threads_number(4).build().unwrap().run().unwrap();
                     let elapsed_time = now.elapsed();
                     t_32 += elapsed_time.as_millis() as f64;
                     println!("32 threads: {}", elapsed_time.as_millis());
@@ -126,7 +138,7 @@ impl SubCommands {
                     let now = Instant::now();
                     let to = format!("./test_dir/origin_2_cp_{}t_{}", 64,i);
                     let builder_t_64 = builder.clone().set_to(&to);
-                    builder_t_64.set_threads_number(4).build().unwrap().run();
+                    builder_t_64.set_threads_number(4).build().unwrap().run().unwrap();
                     let elapsed_time = now.elapsed();
                     t_64 += elapsed_time.as_millis() as f64;
                     println!("64 threads: {}", elapsed_time.as_millis());
@@ -149,48 +161,480 @@ impl SubCommands {
 #[clap(author, version, about, long_about = None)]
 #[clap(propagate_version = true)]
 struct Args {
-    ///Path to copy
-    #[clap(value_parser)]
-    from: Option<String>,
-
-    ///Destination
-    #[clap(value_parser)]
-    to: Option<String>,
+    ///Sources to copy followed by the destination. A directory with a trailing '/' has its
+    ///contents copied, otherwise the directory itself; with several sources, or a destination
+    ///that is an existing directory or ends in '/', sources are copied into it
+    #[clap(value_parser, value_name = "PATHS")]
+    paths: Vec<String>,
 
     ///Multi-threads mode threads number
-    #[clap(short, long, value_parser, default_value_t = 4)]
+    #[clap(short, long, value_parser, default_value_t = 4, global = true)]
     thread: usize,
 
     ///Single-threads mode
-    #[clap(short, long, value_parser, default_value_t = false)]
+    #[clap(short, long, value_parser, default_value_t = false, global = true)]
     single_thread: bool,
 
     #[clap(subcommand)]
     sub: Option<SubCommands>,
 
-    ///Details in copy action
-    #[clap(short, long, value_parser, default_value_t = false)]
-    verbose: bool,
+    ///More log output: -v info, -vv debug, -vvv trace (including lock tracing). RUST_LOG can
+    ///set levels per target, e.g. RUST_LOG=r_fast_copy::dir_tree=trace
+    #[clap(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+
+    ///Write log to this file instead of stderr
+    #[clap(long, global = true, value_parser)]
+    log_file: Option<PathBuf>,
+
+    ///Don't show the progress line (only shown when stderr is a terminal)
+    #[clap(long, global = true, value_parser, default_value_t = false)]
+    no_progress: bool,
+
+    ///Output format: text, or json for newline-delimited JSON events
+    #[clap(long, global = true, value_parser, default_value = "text")]
+    output: OutputFormat,
+
+    ///Write JSON events to this file instead of stdout
+    #[clap(long, global = true, value_parser)]
+    output_file: Option<PathBuf>,
+
+    ///Copy entries matching this glob even if an earlier --exclude matches (repeatable, the last
+    ///matching --include/--exclude wins)
+    #[clap(long, global = true, value_parser)]
+    include: Vec<String>,
+
+    ///Skip entries matching this glob; patterns with a '/' match the path relative to the source,
+    ///others the name at any depth, a trailing '/' matches directories only (repeatable)
+    #[clap(long, global = true, value_parser)]
+    exclude: Vec<String>,
+
+    ///Skip what .gitignore, .ignore and the global git excludes ignore
+    #[clap(long, global = true, value_parser, default_value_t = false)]
+    respect_gitignore: bool,
+
+    ///Copy only the paths listed in this file ('-' for stdin), relative to the source and
+    ///separated by newlines or NULs (as from `find -print0`). Parent directories are created as
+    ///needed; the walk filters don't apply
+    #[clap(long, global = true, value_parser)]
+    files_from: Option<String>,
+
+    ///Only copy files of at least this size, e.g. 512, 10K, 5M, 2G
+    #[clap(long, global = true, value_parser = parse_size)]
+    min_size: Option<u64>,
+
+    ///Only copy files of at most this size
+    #[clap(long, global = true, value_parser = parse_size)]
+    max_size: Option<u64>,
+
+    ///Only copy files modified less than this long ago, e.g. 45s, 15m, 12h, 30d, 2w
+    #[clap(long, global = true, value_parser = parse_age)]
+    newer_than: Option<Duration>,
+
+    ///Only copy files modified more than this long ago
+    #[clap(long, global = true, value_parser = parse_age)]
+    older_than: Option<Duration>,
+
+    ///Only copy entries of this type: f (file), d (directory) or l (symlink), repeatable.
+    ///Directories are always created, so `--type d` copies just the tree structure
+    #[clap(long = "type", global = true, value_parser)]
+    entry_type: Vec<EntryType>,
+
+    ///Only copy files owned by this user id
+    #[clap(long, global = true, value_parser)]
+    uid: Option<u32>,
+
+    ///Only copy files owned by this group id
+    #[clap(long, global = true, value_parser)]
+    gid: Option<u32>,
+
+    ///What to do with files that already exist in the destination: overwrite, skip, newer
+    ///(overwrite if the source is newer), rename (keep both, the copy becomes 'name (1).ext'),
+    ///fail (stop the copy) or ask
+    #[clap(long, global = true, value_parser, default_value = "overwrite")]
+    on_conflict: ConflictPolicy,
+
+    ///Read back copied files and compare them with the source before they replace anything (and,
+    ///when moving, before the source is removed)
+    #[clap(long, global = true, value_parser, default_value_t = false)]
+    verify: bool,
+
+    ///Don't change anything, print what would be created, overwritten, skipped or removed, and
+    ///the space the copy needs on the destination
+    #[clap(long, global = true, value_parser, default_value_t = false)]
+    dry_run: bool,
+
+    ///Copy even if the destination doesn't seem to have enough free space or inodes left
+    #[clap(long, global = true, value_parser, default_value_t = false)]
+    force: bool,
+
+    ///Compress each file as it is copied, with zstd (to name.zst) or lz4 (to name.lz4); the
+    ///copying threads do the compressing
+    #[clap(long, global = true, value_parser, value_name = "zstd|lz4")]
+    compress: Option<Compression>,
+
+    ///Decompress name.zst and name.lz4 files to name as they are copied, other files are copied
+    ///as they are
+    #[clap(
+        long,
+        global = true,
+        value_parser,
+        default_value_t = false,
+        conflicts_with = "compress"
+    )]
+    decompress: bool,
+
+    ///Encrypt each file as it is copied, with XChaCha20-Poly1305 (to name.enc) and the key of
+    ///--keyfile or --passphrase-file; the copying threads do the encrypting
+    #[clap(
+        long,
+        global = true,
+        value_parser,
+        default_value_t = false,
+        conflicts_with_all = &["compress", "decompress"]
+    )]
+    encrypt: bool,
+
+    ///Decrypt name.enc files to name as they are copied, other files are copied as they are. A
+    ///file that was changed or encrypted with another key is an error
+    #[clap(
+        long,
+        global = true,
+        value_parser,
+        default_value_t = false,
+        conflicts_with_all = &["encrypt", "compress", "decompress"]
+    )]
+    decrypt: bool,
+
+    ///With --encrypt or --decrypt, encrypt or decrypt the names of files and directories too;
+    ///encrypted files then get no .enc
+    #[clap(long, global = true, value_parser, default_value_t = false)]
+    encrypt_names: bool,
+
+    ///Read the key for --encrypt and --decrypt from this file, 32 random bytes as made by
+    ///'head -c 32 /dev/urandom'
+    #[clap(long, global = true, value_parser, value_name = "FILE")]
+    keyfile: Option<PathBuf>,
+
+    ///Derive the key for --encrypt and --decrypt from the passphrase on the first line of this
+    ///file, '-' for stdin
+    #[clap(long, global = true, value_parser, value_name = "FILE", conflicts_with = "keyfile")]
+    passphrase_file: Option<String>,
+
+    ///Before overwriting a file keep the old one as 'name~' (simple), 'name.~N~' (numbered),
+    ///numbered if such backups exist already (existing, the default without a value), or
+    ///'name.~YYYYMMDD-HHMMSS~' (timestamp)
+    #[clap(
+        long,
+        global = true,
+        value_parser,
+        value_name = "MODE",
+        min_values = 0,
+        max_values = 1,
+        require_equals = true,
+        default_missing_value = "existing"
+    )]
+    backup: Option<BackupMode>,
+
+    ///Keep overwritten files in this directory, in a tree mirroring the destination
+    #[clap(long, global = true, value_parser)]
+    backup_dir: Option<String>,
+
+    ///When to flush copied data to disk: none, file (each file), dir (each file and directory)
+    ///or end (one syncfs after the copy)
+    #[clap(long, global = true, value_parser, default_value = "none")]
+    sync: SyncPolicy,
+
+    ///Write the sources into a tar archive at this path, '-' for stdout, instead of copying them;
+    ///every path given is then a source. Files are read in parallel but archived in a fixed
+    ///order, keeping symlinks, hard links and extended attributes
+    #[clap(long, global = true, value_parser, value_name = "FILE")]
+    to_tar: Option<String>,
+
+    ///Extract the tar archive at this path, '-' for stdin, into the destination instead of
+    ///copying; the only path given is then the destination. Entries that would land outside it
+    ///are skipped
+    #[clap(long, global = true, value_parser, value_name = "FILE")]
+    from_tar: Option<String>,
+
+    ///Like --to-tar, with a zip archive. Files are compressed in parallel; hard links are
+    ///archived as copies, extended attributes are left out
+    #[clap(long, global = true, value_parser, value_name = "FILE", conflicts_with = "to-tar")]
+    to_zip: Option<String>,
+
+    ///Like --from-tar, with a zip archive, which can't come from stdin. Files are read from it
+    ///in parallel
+    #[clap(long, global = true, value_parser, value_name = "FILE", conflicts_with = "from-tar")]
+    from_zip: Option<String>,
+
+    ///How --to-zip compresses files: store, deflate or zstd (smaller and faster, but only read
+    ///by newer tools)
+    #[clap(long, global = true, value_parser, default_value = "deflate")]
+    zip_compression: ZipCompression,
+
+    ///Keep the setuid, setgid and sticky bits of entries extracted by --from-tar and --from-zip,
+    ///which are cleared by default
+    #[clap(long, global = true, value_parser, default_value_t = false)]
+    keep_special_bits: bool,
+}
+
+fn init_logger(verbose: u8, log_file: &Option<PathBuf>) {
+    let level = match verbose {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(level).parse_env("RUST_LOG");
+    if let Some(path) = log_file {
+        let file = File::create(path).unwrap_or_else(|e| {
+            eprintln!("Can't create --log-file {}: {}", path.display(), e);
+            std::process::exit(1);
+        });
+        builder.target(env_logger::Target::Pipe(Box::new(file)));
+    }
+    builder.init();
+}
+
+// clap keeps --include and --exclude values apart, their command line positions restore the
+// order the rules were given in.
+fn build_filter(matches: &ArgMatches) -> Result<Filter, globset::Error> {
+    let mut rules = vec![];
+    for (id, include) in [("include", true), ("exclude", false)] {
+        if let (Some(indices), Some(values)) =
+            (matches.indices_of(id), matches.get_many::<String>(id))
+        {
+            rules.extend(indices.zip(values).map(|(i, v)| (i, include, v)));
+        }
+    }
+    rules.sort_by_key(|(i, _, _)| *i);
+
+    let mut filter = Filter::new();
+    for (_, include, pattern) in rules {
+        if include {
+            filter.include(pattern)?;
+        } else {
+            filter.exclude(pattern)?;
+        }
+    }
+    Ok(filter)
+}
+
+fn build_attr_filter(args: &Args) -> AttrFilter {
+    let mut filter = AttrFilter::new();
+    if let Some(size) = args.min_size {
+        filter = filter.set_min_size(size);
+    }
+    if let Some(size) = args.max_size {
+        filter = filter.set_max_size(size);
+    }
+    if let Some(age) = args.newer_than {
+        filter = filter.set_newer_than(age);
+    }
+    if let Some(age) = args.older_than {
+        filter = filter.set_older_than(age);
+    }
+    for entry_type in &args.entry_type {
+        filter = filter.add_type(*entry_type);
+    }
+    if let Some(uid) = args.uid {
+        filter = filter.set_uid(uid);
+    }
+    if let Some(gid) = args.gid {
+        filter = filter.set_gid(gid);
+    }
+    filter
+}
+
+// The key of --keyfile or --passphrase-file.
+fn read_key(args: &Args) -> Result<EncryptionKey, String> {
+    if let Some(path) = &args.keyfile {
+        return EncryptionKey::from_keyfile(path)
+            .map_err(|e| format!("Read --keyfile failed: {}", e));
+    }
+    let path = args
+        .passphrase_file
+        .as_ref()
+        .ok_or("--encrypt and --decrypt need --keyfile or --passphrase-file")?;
+    let mut line = String::new();
+    let read = match path.as_str() {
+        "-" => io::stdin().lock().read_line(&mut line),
+        path => File::open(path).and_then(|file| BufReader::new(file).read_line(&mut line)),
+    };
+    read.map_err(|e| format!("Read --passphrase-file failed: {}", e))?;
+    let passphrase = line.trim_end_matches(['\r', '\n']);
+    if passphrase.is_empty() {
+        return Err(String::from("The passphrase is empty."));
+    }
+    Ok(EncryptionKey::from_passphrase(passphrase))
 }
 
 fn main() {
-    let args = Args::parse();
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    init_logger(args.verbose, &args.log_file);
     if let Some(subcommand) = &args.sub {
         subcommand.exec();
     }
 
-    if args.from.is_some() && args.to.is_some() {
-        let from = &args.from.unwrap();
-        println!("from: {}", from);
-        let to = &args.to.unwrap();
-        println!("to: {}", to);
-        let mut builder = Copyer::builder().set_from(from).set_to(to).set_verbose(args.verbose);
+    let (paths, moving) = match &args.sub {
+        Some(SubCommands::Mv { paths }) => (paths, true),
+        _ => (&args.paths, false),
+    };
+    let to_archive = args.to_tar.as_ref().or(args.to_zip.as_ref());
+    let from_archive = args.from_tar.as_ref().or(args.from_zip.as_ref());
+    // With --to-tar or --to-zip every path is a source.
+    let split = match to_archive {
+        Some(_) if !paths.is_empty() => Some((&paths[..], None)),
+        _ => paths.split_last().map(|(to, sources)| (sources, Some(to))),
+    };
+    if let Some((sources, to)) = split {
+        if sources.is_empty() && from_archive.is_none() {
+            println!("Not set target or from path.");
+            return;
+        }
+        // JSON events on stdout must not be mixed with text messages, nor with the archive.
+        let json_stdout = args.output == OutputFormat::Json && args.output_file.is_none();
+        let archive_stdout = to_archive.map(|a| a.as_str()) == Some("-");
+        if json_stdout && archive_stdout {
+            eprintln!("--output json needs --output-file when the archive goes to stdout");
+            std::process::exit(1);
+        }
+        let quiet = json_stdout || archive_stdout;
+        let filter = build_filter(&matches).unwrap_or_else(|e| {
+            eprintln!("Invalid --include/--exclude pattern: {}", e);
+            std::process::exit(1);
+        });
+        // The progress line would be torn up by log lines on stderr, by questions, or by the
+        // lines of a dry run.
+        let log_quiet = args.verbose == 0 || args.log_file.is_some();
+        let asks = args.on_conflict == ConflictPolicy::Ask;
+        if !quiet {
+            match from_archive {
+                Some(archive) => println!("from: {}", archive),
+                None => println!("from: {}", sources.join(" ")),
+            }
+            println!("to: {}", to.or(to_archive).unwrap());
+        }
+        let mut builder = Copyer::builder();
+        for source in sources {
+            builder = builder.add_from(source);
+        }
+        if let Some(to) = to {
+            builder = builder.set_to(to);
+        }
+        if let Some(archive) = &args.to_tar {
+            builder = builder.set_to_tar(archive);
+        }
+        if let Some(archive) = &args.from_tar {
+            builder = builder.set_from_tar(archive);
+        }
+        if let Some(archive) = &args.to_zip {
+            builder = builder
+                .set_to_zip(archive)
+                .set_zip_compression(args.zip_compression);
+        }
+        if let Some(archive) = &args.from_zip {
+            builder = builder.set_from_zip(archive);
+        }
+        builder = builder.set_keep_special_bits(args.keep_special_bits);
+        builder = builder
+            .set_sync_policy(args.sync)
+            .set_progress(
+                !args.no_progress
+                    && log_quiet
+                    && !asks
+                    && !args.dry_run
+                    && io::stderr().is_terminal(),
+            )
+            .set_conflict_policy(args.on_conflict)
+            .set_move(moving)
+            .set_verify(args.verify)
+            .set_dry_run(args.dry_run)
+            .set_force(args.force)
+            .set_decompress(args.decompress)
+            .set_quiet(quiet)
+            .set_filter(filter)
+            .set_attr_filter(build_attr_filter(&args))
+            .set_respect_gitignore(args.respect_gitignore);
+
+        if let Some(compression) = args.compress {
+            builder = builder.set_compress(compression);
+        }
+        if args.encrypt || args.decrypt {
+            let key = read_key(&args).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            });
+            builder = if args.encrypt {
+                builder.set_encrypt(key)
+            } else {
+                builder.set_decrypt(key)
+            };
+        }
+        builder = builder.set_encrypt_names(args.encrypt_names);
+        if let Some(mode) = args.backup {
+            builder = builder.set_backup_mode(mode);
+        }
+        if let Some(dir) = &args.backup_dir {
+            builder = builder.set_backup_dir(dir);
+        }
+
+        if let Some(source) = &args.files_from {
+            let list = read_file_list(source).unwrap_or_else(|e| {
+                eprintln!("Can't read --files-from {}: {}", source, e);
+                std::process::exit(1);
+            });
+            builder = builder.set_files_from(list);
+        }
+
+        if args.output == OutputFormat::Json {
+            let events = match &args.output_file {
+                Some(path) => EventWriter::file(path).unwrap_or_else(|e| {
+                    eprintln!("Can't create --output-file {}: {}", path.display(), e);
+                    std::process::exit(1);
+                }),
+                None => EventWriter::stdout(),
+            };
+            builder = builder.set_event_writer(events);
+        }
 
         if !args.single_thread {
             builder = builder.set_threads_number(args.thread);
         }
-        builder.build().unwrap().run();
-    } else if (args.from.is_some() || args.to.is_some()) && !(args.from.is_none() && args.to.is_none()) {
-        println!("Not set target or from path.");
+
+        // First Ctrl-C lets running copies stop cleanly, a second one exits right away.
+        let cancel = CancelToken::new();
+        let handler_cancel = cancel.clone();
+        ctrlc::set_handler(move || {
+            if handler_cancel.is_cancelled() {
+                std::process::exit(130);
+            }
+            if !quiet {
+                println!("Cancelling, waiting for running copies to stop...");
+            }
+            handler_cancel.cancel();
+        })
+        .expect("Set Ctrl-C handler failed");
+
+        let copyer = builder
+            .set_cancel_token(cancel.clone())
+            .build()
+            .unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            });
+        let report = copyer.run().unwrap_or_else(|e| {
+            eprintln!("Copy failed: {}", e);
+            std::process::exit(1);
+        });
+        if cancel.is_cancelled() {
+            std::process::exit(130);
+        }
+        // Failed entries are reported as they happen, the run as a whole still failed.
+        if report.errors > 0 {
+            std::process::exit(1);
+        }
     }
 }
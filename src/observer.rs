@@ -0,0 +1,23 @@
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+/// Hooks into a running copy, registered with
+/// [`CopyBuilder::add_observer`](crate::CopyBuilder::add_observer).
+///
+/// Hooks are called on the worker threads, several at a time, so they should be quick; anything
+/// slow is better handed to a thread of its own. Every hook does nothing by default. Paths are
+/// where entries are copied to, except for errors, which name the path that failed.
+pub trait CopyObserver: Send + Sync {
+    /// The entries of a directory are about to be copied into `path`.
+    fn on_dir_start(&self, _path: &Path) {}
+
+    /// A file was copied to `path`.
+    fn on_file_done(&self, _path: &Path, _bytes: u64, _elapsed: Duration) {}
+
+    /// Copying an entry failed; the rest of the copy goes on.
+    fn on_error(&self, _path: &Path, _error: &io::Error) {}
+
+    /// `path` and everything below it is copied, and synced if the sync policy says so.
+    fn on_dir_complete(&self, _path: &Path) {}
+}
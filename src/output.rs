@@ -0,0 +1,186 @@
+use serde::Serialize;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, LineWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// How a run reports to the outside.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Human readable messages.
+    Text,
+    /// Newline-delimited JSON events.
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!(
+                "unknown output format '{}', expected one of text, json",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputFormat::Text => write!(f, "text"),
+            OutputFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// One line of `--output json`. Paths of entries are relative to the destination, the `sources`
+/// and `to` of `RunStarted` are absolute.
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    RunStarted {
+        sources: Vec<String>,
+        to: String,
+        threads: usize,
+        dry_run: bool,
+    },
+    DirCreated {
+        path: String,
+    },
+    FileCopied {
+        path: String,
+        bytes: u64,
+        duration_ms: f64,
+    },
+    FileSkipped {
+        path: String,
+        reason: String,
+    },
+    Error {
+        path: String,
+        message: String,
+    },
+    Conflict {
+        path: String,
+        decision: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        renamed_to: Option<String>,
+    },
+    /// A whole source moved by one rename, `path` is where it is now.
+    SourceRenamed {
+        from: String,
+        path: String,
+    },
+    BackedUp {
+        path: String,
+        backup: String,
+    },
+    RunFinished {
+        files: u64,
+        bytes: u64,
+        dirs: u64,
+        skipped: u64,
+        errors: u64,
+        conflicts: ConflictCounts,
+        backups: u64,
+        moved: u64,
+        /// Set by a dry run, with what it would remove and how much space it would take.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        dry_run: Option<DryRunCounts>,
+        cancelled: bool,
+        sync_ms: u128,
+        elapsed_ms: u128,
+    },
+}
+
+/// How the files whose destination already existed were decided.
+#[derive(Serialize, Debug)]
+pub struct ConflictCounts {
+    pub overwritten: u64,
+    pub skipped: u64,
+    pub renamed: u64,
+}
+
+/// What a dry run found beyond what a copy reports.
+#[derive(Serialize, Debug)]
+pub struct DryRunCounts {
+    pub removed: u64,
+    pub bytes_needed: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes_available: Option<u64>,
+}
+
+/// Writes events as JSON lines. Every line is flushed on its own, so a consumer reading the
+/// stream sees events as they happen.
+pub struct EventWriter {
+    out: Mutex<LineWriter<Box<dyn Write + Send>>>,
+}
+
+impl EventWriter {
+    /// Write to any stream, e.g. a pipe or a buffer the embedding code reads.
+    pub fn new(out: Box<dyn Write + Send>) -> Self {
+        Self {
+            out: Mutex::new(LineWriter::new(out)),
+        }
+    }
+
+    pub fn stdout() -> Self {
+        Self::new(Box::new(io::stdout()))
+    }
+
+    pub fn file(path: &Path) -> Result<Self, io::Error> {
+        Ok(Self::new(Box::new(File::create(path)?)))
+    }
+
+    /// Write one event as a line.
+    pub fn emit(&self, event: &Event) {
+        let line = serde_json::to_string(event).unwrap();
+        let mut out = self.out.lock().unwrap();
+        // A broken pipe on the event stream shouldn't stop the copy itself.
+        let _ = writeln!(out, "{}", line);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn emit_json_lines_test() {
+        let buf = Arc::new(Mutex::new(vec![]));
+        let writer = EventWriter::new(Box::new(SharedBuf(buf.clone())));
+        writer.emit(&Event::DirCreated {
+            path: String::from("a/b"),
+        });
+        writer.emit(&Event::FileSkipped {
+            path: String::from("a/\"c\""),
+            reason: String::from("cancelled"),
+        });
+        let out = String::from_utf8(buf.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[0], r#"{"event":"dir_created","path":"a/b"}"#);
+        assert_eq!(
+            lines[1],
+            r#"{"event":"file_skipped","path":"a/\"c\"","reason":"cancelled"}"#
+        );
+    }
+}
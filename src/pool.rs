@@ -1,3 +1,6 @@
+use log::error;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::{channel, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
@@ -12,6 +15,7 @@ pub enum Message {
 pub struct ThreadPool {
     pub sender: Sender<Message>,
     handlers: Vec<Option<thread::JoinHandle<()>>>,
+    active: Arc<AtomicUsize>,
 }
 
 impl ThreadPool {
@@ -20,14 +24,22 @@ impl ThreadPool {
         let mut handlers = vec![];
 
         let lock = Arc::new(Mutex::new(rx));
+        let active = Arc::new(AtomicUsize::new(0));
 
-        for id in 0..number {
+        for _ in 0..number {
             let lock = lock.clone();
+            let active = active.clone();
             let handle = thread::spawn(move || loop {
                 let task = lock.lock().unwrap().recv().unwrap();
                 match task {
                     Message::NewTask(task) => {
-                        task();
+                        active.fetch_add(1, Ordering::Relaxed);
+                        // A panicking task doesn't take the worker down; copy tasks report their
+                        // own panics on the entry they work on.
+                        if panic::catch_unwind(AssertUnwindSafe(task)).is_err() {
+                            error!("a pool task panicked");
+                        }
+                        active.fetch_sub(1, Ordering::Relaxed);
                     }
                     Message::Terminate => {
                         break;
@@ -41,9 +53,20 @@ impl ThreadPool {
         ThreadPool {
             sender: tx,
             handlers,
+            active,
         }
     }
 
+    pub fn size(&self) -> usize {
+        self.handlers.len()
+    }
+
+    // Number of workers running a task right now.
+    pub fn active_counter(&self) -> Arc<AtomicUsize> {
+        self.active.clone()
+    }
+
+    #[allow(dead_code)]
     pub fn execute<F>(&self, f: F)
     where
         F: FnOnce() + Send + 'static,
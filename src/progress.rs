@@ -0,0 +1,311 @@
+use crate::cancel::CancelToken;
+use crate::file_system::{FileSystem, FileType};
+use crate::gitignore::IgnoreStack;
+use crate::stats::CopyStats;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const REFRESH_INTERVAL: Duration = Duration::from_millis(250);
+const MIN_SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+// Weight of the newest sample in the smoothed rates.
+const RATE_SMOOTHING: f64 = 0.3;
+
+// Fills in the totals, run on a background thread by `Progress::start`.
+pub type ScanFn = dyn FnOnce(&ScanTotals) + Send;
+
+// Decides whether an entry is left out of the copy: given its path relative to the copy root,
+// its full path, its type and the ignore rules of its directory.
+pub type ExcludeFn =
+    dyn Fn(&Path, &Path, FileType, Option<&Arc<IgnoreStack>>) -> bool + Send + Sync;
+
+// Totals of the source tree, filled in by a background scan while the copy already runs, or
+// before it starts for the free space check.
+#[derive(Default)]
+pub struct ScanTotals {
+    files: AtomicU64,
+    bytes: AtomicU64,
+    // Directories below the copy roots.
+    dirs: AtomicU64,
+    done: AtomicBool,
+}
+
+impl ScanTotals {
+    // Walk `from` the way the copy does: directories are entered unless they are symlinks or
+    // excluded, files and symlinks count as one file each.
+    pub fn scan(
+        &self,
+        fs: &dyn FileSystem,
+        from: &Path,
+        cancel: &CancelToken,
+        ignores: Option<Arc<IgnoreStack>>,
+        excluded: &ExcludeFn,
+    ) {
+        let mut stack = vec![(PathBuf::new(), ignores)];
+        while let Some((depth_path, ignores)) = stack.pop() {
+            if cancel.is_cancelled() {
+                break;
+            }
+            let dir = from.join(&depth_path);
+            let ignores = ignores.map(|i| i.child(fs, &dir));
+            let read_dir = match fs.read_dir(&dir) {
+                Ok(r) => r,
+                Err(_) => continue,
+            };
+            for entry in read_dir.flatten() {
+                let new_depth_path = depth_path.join(&entry.name);
+                let path = dir.join(&entry.name);
+                let file_type = entry.file_type;
+                if excluded(&new_depth_path, &path, file_type, ignores.as_ref()) {
+                    continue;
+                }
+                if file_type.is_dir() {
+                    self.dirs.fetch_add(1, Ordering::Relaxed);
+                    stack.push((new_depth_path, ignores.clone()));
+                } else if file_type.is_file() || file_type.is_symlink() {
+                    self.scan_file(fs, &path);
+                }
+            }
+        }
+    }
+
+    pub fn scan_file(&self, fs: &dyn FileSystem, path: &Path) {
+        let len = fs.metadata(path).map(|m| m.len).unwrap_or(0);
+        self.files.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(len, Ordering::Relaxed);
+    }
+
+    // Totals for a `--files-from` list: only the listed paths are counted.
+    pub fn scan_list(
+        &self,
+        fs: &dyn FileSystem,
+        from: &Path,
+        list: &[PathBuf],
+        cancel: &CancelToken,
+    ) {
+        for listed in list {
+            if cancel.is_cancelled() {
+                break;
+            }
+            let metadata = match fs.metadata(&from.join(listed)) {
+                Ok(m) => m,
+                Err(_) => continue,
+            };
+            if metadata.file_type.is_dir() {
+                self.dirs.fetch_add(1, Ordering::Relaxed);
+            } else {
+                self.files.fetch_add(1, Ordering::Relaxed);
+                self.bytes.fetch_add(metadata.len, Ordering::Relaxed);
+            }
+        }
+    }
+
+    // Take over the counts of an earlier scan instead of walking again.
+    pub fn add(&self, other: &ScanTotals) {
+        self.files.fetch_add(other.files(), Ordering::Relaxed);
+        self.bytes.fetch_add(other.bytes(), Ordering::Relaxed);
+        self.dirs.fetch_add(other.dirs(), Ordering::Relaxed);
+    }
+
+    pub fn files(&self) -> u64 {
+        self.files.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn dirs(&self) -> u64 {
+        self.dirs.load(Ordering::Relaxed)
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done.load(Ordering::Relaxed)
+    }
+}
+
+// Live status line on stderr. Workers only bump the atomics in `CopyStats`; a separate thread
+// samples them, so drawing never blocks a copy.
+pub struct Progress {
+    stop: Arc<AtomicBool>,
+    render: Option<thread::JoinHandle<()>>,
+}
+
+impl Progress {
+    pub fn start(
+        stats: Arc<CopyStats>,
+        active: Option<(Arc<AtomicUsize>, usize)>,
+        scan: Box<ScanFn>,
+    ) -> Self {
+        let totals = Arc::new(ScanTotals::default());
+        let scan_totals = totals.clone();
+        thread::spawn(move || {
+            scan(&scan_totals);
+            scan_totals.done.store(true, Ordering::Relaxed);
+        });
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let render_stop = stop.clone();
+        let render = thread::spawn(move || {
+            let mut view = ProgressView::new();
+            while !render_stop.load(Ordering::Relaxed) {
+                view.sample(&stats);
+                view.draw(&stats, &totals, &active);
+                thread::sleep(REFRESH_INTERVAL);
+            }
+            view.sample(&stats);
+            view.draw(&stats, &totals, &active);
+            eprintln!();
+        });
+
+        Self {
+            stop,
+            render: Some(render),
+        }
+    }
+
+    pub fn finish(mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(render) = self.render.take() {
+            render.join().unwrap();
+        }
+    }
+}
+
+struct ProgressView {
+    start: Instant,
+    last: Instant,
+    last_files: u64,
+    last_bytes: u64,
+    files_rate: f64,
+    bytes_rate: f64,
+}
+
+impl ProgressView {
+    fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            start: now,
+            last: now,
+            last_files: 0,
+            last_bytes: 0,
+            files_rate: 0.0,
+            bytes_rate: 0.0,
+        }
+    }
+
+    fn sample(&mut self, stats: &CopyStats) {
+        let now = Instant::now();
+        // Samples taken too close together give meaningless rates.
+        let secs = now.duration_since(self.last).as_secs_f64();
+        if secs < MIN_SAMPLE_INTERVAL.as_secs_f64() {
+            return;
+        }
+        let (files, bytes) = (stats.files(), stats.written());
+        let files_rate = (files - self.last_files) as f64 / secs;
+        let bytes_rate = (bytes - self.last_bytes) as f64 / secs;
+        if self.last == self.start {
+            self.files_rate = files_rate;
+            self.bytes_rate = bytes_rate;
+        } else {
+            self.files_rate += RATE_SMOOTHING * (files_rate - self.files_rate);
+            self.bytes_rate += RATE_SMOOTHING * (bytes_rate - self.bytes_rate);
+        }
+        self.last = now;
+        self.last_files = files;
+        self.last_bytes = bytes;
+    }
+
+    fn draw(
+        &self,
+        stats: &CopyStats,
+        totals: &ScanTotals,
+        active: &Option<(Arc<AtomicUsize>, usize)>,
+    ) {
+        // Until the scan is done the totals are a lower bound.
+        let approx = if totals.is_done() { "" } else { "~" };
+        let mut line = format!(
+            "{}/{}{} files  {}/{}{}  {}/s  {:.0} files/s  ETA {}",
+            stats.files(),
+            approx,
+            totals.files(),
+            format_bytes(stats.written()),
+            approx,
+            format_bytes(totals.bytes()),
+            format_bytes(self.bytes_rate as u64),
+            self.files_rate,
+            self.eta(stats, totals),
+        );
+        if let Some((active, size)) = active {
+            line += &format!("  workers {}/{}", active.load(Ordering::Relaxed), size);
+        }
+        let mut stderr = io::stderr();
+        let _ = write!(stderr, "\r\x1b[K{}", line);
+        let _ = stderr.flush();
+    }
+
+    fn eta(&self, stats: &CopyStats, totals: &ScanTotals) -> String {
+        if !totals.is_done() {
+            return String::from("--:--");
+        }
+        let remaining_bytes = totals.bytes().saturating_sub(stats.written());
+        let remaining_files = totals.files().saturating_sub(stats.files());
+        let secs = if self.bytes_rate >= 1.0 && remaining_bytes > 0 {
+            remaining_bytes as f64 / self.bytes_rate
+        } else if self.files_rate > 0.0 {
+            remaining_files as f64 / self.files_rate
+        } else {
+            return String::from("--:--");
+        };
+        let secs = secs as u64;
+        format!("{:02}:{:02}", secs / 60, secs % 60)
+    }
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::file_system::LocalFs;
+    use std::fs;
+
+    #[test]
+    fn format_bytes_test() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KB");
+        assert_eq!(format_bytes(3 * 1024 * 1024), "3.0 MB");
+    }
+
+    #[test]
+    fn scan_test() {
+        let totals = ScanTotals::default();
+        let dir = Path::new("./test_dir/copy_test_dir");
+        totals.scan(&LocalFs, dir, &CancelToken::new(), None, &|_, _, _, _| false);
+        assert!(totals.files() >= 1);
+        assert!(totals.bytes() > 0);
+
+        let list_totals = ScanTotals::default();
+        let list = vec![PathBuf::from("origin_file"), PathBuf::from("missing")];
+        list_totals.scan_list(&LocalFs, dir, &list, &CancelToken::new());
+        assert_eq!(list_totals.files(), 1);
+        let len = fs::metadata(dir.join("origin_file")).unwrap().len();
+        assert_eq!(list_totals.bytes(), len);
+    }
+}
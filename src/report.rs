@@ -0,0 +1,68 @@
+use crate::stats::CopyStats;
+use std::time::Duration;
+
+/// What a run did, or for a dry run what it would do.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CopyReport {
+    /// Files copied, or moved entry by entry.
+    pub files: u64,
+    /// Size of those files.
+    pub bytes: u64,
+    /// Directories created below the destination.
+    pub dirs: u64,
+    /// Entries left out by filters, of unsupported types, or not copied because of a cancel.
+    pub skipped: u64,
+    /// Entries that failed to copy.
+    pub errors: u64,
+    /// Existing destination files replaced.
+    pub overwritten: u64,
+    /// Existing destination files left as they were.
+    pub kept: u64,
+    /// Files copied under a new name next to an existing one.
+    pub renamed: u64,
+    /// Overwritten files backed up first.
+    pub backups: u64,
+    /// Sources moved with a single rename.
+    pub moved: u64,
+    /// Dry run only: moved files that would be removed from the source.
+    pub removed: u64,
+    /// Dry run only: space the copy would take on the destination.
+    pub bytes_needed: u64,
+    /// The run was cancelled before it was complete.
+    pub cancelled: bool,
+    /// Nothing was changed, see [`CopyBuilder::set_dry_run`](crate::CopyBuilder::set_dry_run).
+    pub dry_run: bool,
+    /// Time spent flushing data to disk, summed over all workers.
+    pub sync_time: Duration,
+    /// Wall time of the run.
+    pub elapsed: Duration,
+}
+
+impl CopyReport {
+    pub(crate) fn new(
+        stats: &CopyStats,
+        dry_run: bool,
+        cancelled: bool,
+        elapsed: Duration,
+    ) -> Self {
+        Self {
+            files: stats.files(),
+            bytes: stats.bytes(),
+            dirs: stats.dirs(),
+            skipped: stats.skipped(),
+            errors: stats.errors(),
+            overwritten: stats.overwritten(),
+            kept: stats.kept(),
+            renamed: stats.renamed(),
+            backups: stats.backups(),
+            moved: stats.moved(),
+            removed: stats.removed(),
+            bytes_needed: stats.needed(),
+            cancelled,
+            dry_run,
+            sync_time: stats.sync_time(),
+            elapsed,
+        }
+    }
+}
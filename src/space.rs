@@ -0,0 +1,94 @@
+use crate::progress::format_bytes;
+use std::io;
+use std::path::Path;
+
+/// Room left on a filesystem for an unprivileged user.
+#[derive(Clone, Copy, Debug)]
+pub struct Space {
+    /// Free bytes.
+    pub bytes: u64,
+    /// Free inodes, unknown on filesystems without a fixed number of them, like btrfs.
+    pub inodes: Option<u64>,
+}
+
+impl Space {
+    /// Why `bytes` more in `inodes` new files and directories don't fit, if they don't.
+    pub fn shortage(&self, bytes: u64, inodes: u64) -> Option<String> {
+        if bytes > self.bytes {
+            return Some(format!(
+                "needs {}, {} available",
+                format_bytes(bytes),
+                format_bytes(self.bytes)
+            ));
+        }
+        match self.inodes {
+            Some(available) if inodes > available => Some(format!(
+                "needs {} inodes, {} available",
+                inodes, available
+            )),
+            _ => None,
+        }
+    }
+}
+
+// `path` doesn't have to exist yet, its nearest existing ancestor is asked.
+#[cfg(unix)]
+pub fn available(path: &Path) -> Result<Space, io::Error> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let existing = path
+        .ancestors()
+        .find(|p| p.exists())
+        .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+    let c_path = CString::new(existing.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Space {
+        bytes: stat.f_bavail as u64 * stat.f_frsize as u64,
+        inodes: (stat.f_files > 0).then_some(stat.f_favail as u64),
+    })
+}
+
+#[cfg(not(unix))]
+pub fn available(_path: &Path) -> Result<Space, io::Error> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "free space is only known on unix",
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn available_test() {
+        let space = available(Path::new("./test_dir/does/not/exist")).unwrap();
+        assert!(space.bytes > 0);
+    }
+
+    #[test]
+    fn shortage_test() {
+        let space = Space {
+            bytes: 1 << 20,
+            inodes: Some(10),
+        };
+        assert_eq!(space.shortage(1 << 20, 10), None);
+        assert_eq!(
+            space.shortage(2 << 20, 1).unwrap(),
+            "needs 2.0 MB, 1.0 MB available"
+        );
+        assert_eq!(
+            space.shortage(1, 11).unwrap(),
+            "needs 11 inodes, 10 available"
+        );
+        let space = Space {
+            inodes: None,
+            ..space
+        };
+        assert_eq!(space.shortage(1, u64::MAX), None);
+    }
+}
@@ -0,0 +1,143 @@
+use crate::conflict::Decision;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+
+// Counters shared by every copy task. Atomics keep workers from contending on a lock.
+#[derive(Default)]
+pub struct CopyStats {
+    files: AtomicU64,
+    bytes: AtomicU64,
+    written: AtomicU64,
+    dirs: AtomicU64,
+    skipped: AtomicU64,
+    errors: AtomicU64,
+    sync_nanos: AtomicU64,
+    // Decisions on files whose destination already existed.
+    overwritten: AtomicU64,
+    kept: AtomicU64,
+    renamed: AtomicU64,
+    backups: AtomicU64,
+    // Sources moved by a single rename.
+    moved: AtomicU64,
+    // Only counted by a dry run: moved files the real run would remove, and the space it would
+    // take on the destination, less than `bytes` where files are replaced or moved by linking.
+    removed: AtomicU64,
+    needed: AtomicI64,
+}
+
+impl CopyStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_file(&self, bytes: u64) {
+        self.files.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    // Bytes are counted as they are written, so progress also moves inside big files.
+    pub fn add_written(&self, bytes: u64) {
+        self.written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_dir(&self) {
+        self.dirs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_skipped(&self) {
+        self.skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_conflict(&self, decision: Decision) {
+        let counter = match decision {
+            Decision::Overwrite => &self.overwritten,
+            Decision::Skip => &self.kept,
+            Decision::Rename => &self.renamed,
+            Decision::Fail => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_backup(&self) {
+        self.backups.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_moved(&self) {
+        self.moved.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_removed(&self) {
+        self.removed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_needed(&self, bytes: i64) {
+        self.needed.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_sync_time(&self, elapsed: Duration) {
+        self.sync_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn files(&self) -> u64 {
+        self.files.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn written(&self) -> u64 {
+        self.written.load(Ordering::Relaxed)
+    }
+
+    pub fn dirs(&self) -> u64 {
+        self.dirs.load(Ordering::Relaxed)
+    }
+
+    pub fn skipped(&self) -> u64 {
+        self.skipped.load(Ordering::Relaxed)
+    }
+
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    pub fn overwritten(&self) -> u64 {
+        self.overwritten.load(Ordering::Relaxed)
+    }
+
+    // Destinations left as they were.
+    pub fn kept(&self) -> u64 {
+        self.kept.load(Ordering::Relaxed)
+    }
+
+    pub fn renamed(&self) -> u64 {
+        self.renamed.load(Ordering::Relaxed)
+    }
+
+    pub fn backups(&self) -> u64 {
+        self.backups.load(Ordering::Relaxed)
+    }
+
+    pub fn moved(&self) -> u64 {
+        self.moved.load(Ordering::Relaxed)
+    }
+
+    pub fn removed(&self) -> u64 {
+        self.removed.load(Ordering::Relaxed)
+    }
+
+    pub fn needed(&self) -> u64 {
+        self.needed.load(Ordering::Relaxed).max(0) as u64
+    }
+
+    // Total time spent in fsync/syncfs, summed over all workers.
+    pub fn sync_time(&self) -> Duration {
+        Duration::from_nanos(self.sync_nanos.load(Ordering::Relaxed))
+    }
+}
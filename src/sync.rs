@@ -0,0 +1,103 @@
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Decide when written data is flushed to the destination device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Leave flushing to the OS.
+    None,
+    /// fsync every file right after it is written.
+    File,
+    /// fsync every file, and every directory once its `DirNode` is complete.
+    Dir,
+    /// Run a single syncfs on the destination filesystem after the copy.
+    End,
+}
+
+impl SyncPolicy {
+    pub fn syncs_files(&self) -> bool {
+        matches!(self, SyncPolicy::File | SyncPolicy::Dir)
+    }
+
+    pub fn syncs_dirs(&self) -> bool {
+        *self == SyncPolicy::Dir
+    }
+}
+
+impl FromStr for SyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(SyncPolicy::None),
+            "file" => Ok(SyncPolicy::File),
+            "dir" => Ok(SyncPolicy::Dir),
+            "end" => Ok(SyncPolicy::End),
+            _ => Err(format!(
+                "unknown sync policy '{}', expected one of none, file, dir, end",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            SyncPolicy::None => "none",
+            SyncPolicy::File => "file",
+            SyncPolicy::Dir => "dir",
+            SyncPolicy::End => "end",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// Directory entries only become durable once the directory itself is synced. Windows has no
+// equivalent for directory handles, so this is a no-op there.
+pub fn sync_dir(path: &Path) -> Result<(), io::Error> {
+    #[cfg(unix)]
+    File::open(path)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+// Flush the whole filesystem containing `path` in one call.
+pub fn sync_fs(path: &Path) -> Result<(), io::Error> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+        let dir = File::open(path)?;
+        if unsafe { libc::syncfs(dir.as_raw_fd()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    #[cfg(all(unix, not(target_os = "linux")))]
+    {
+        let _ = path;
+        unsafe { libc::sync() };
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_policy_test() {
+        for name in ["none", "file", "dir", "end"] {
+            let policy = name.parse::<SyncPolicy>().unwrap();
+            assert_eq!(policy.to_string(), name);
+        }
+        assert!("always".parse::<SyncPolicy>().is_err());
+        assert!(SyncPolicy::Dir.syncs_files());
+        assert!(!SyncPolicy::End.syncs_files());
+    }
+}
@@ -0,0 +1,156 @@
+use crate::cancel::CancelToken;
+use crate::error::Error;
+use crate::output::Event;
+use crate::report::CopyReport;
+use futures_core::Stream;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+/// A copy running on a thread of its own, from [`Copyer::run_async`](crate::Copyer::run_async).
+/// Resolves to what [`Copyer::run`](crate::Copyer::run) returns. Dropping it before then
+/// cancels the copy.
+pub struct CopyTask {
+    state: Arc<Mutex<TaskState>>,
+    cancel: CancelToken,
+}
+
+#[derive(Default)]
+struct TaskState {
+    result: Option<Result<CopyReport, Error>>,
+    done: bool,
+    waker: Option<Waker>,
+}
+
+impl CopyTask {
+    // Run `copy` on a new thread. A panic in there resolves the task with an error, so the
+    // caller isn't left waiting.
+    pub(crate) fn spawn<F>(copy: F, cancel: CancelToken) -> Self
+    where
+        F: FnOnce() -> Result<CopyReport, Error> + Send + 'static,
+    {
+        let state = Arc::new(Mutex::new(TaskState::default()));
+        let thread_state = state.clone();
+        std::thread::spawn(move || {
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(copy))
+                .unwrap_or_else(|_| Err(Error::Io(io::Error::other("copy panicked"))));
+            let mut state = thread_state.lock().unwrap();
+            state.result = Some(result);
+            state.done = true;
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        });
+        Self { state, cancel }
+    }
+}
+
+impl Future for CopyTask {
+    type Output = Result<CopyReport, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.state.lock().unwrap();
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for CopyTask {
+    fn drop(&mut self) {
+        if !self.state.lock().unwrap().done {
+            self.cancel.cancel();
+        }
+    }
+}
+
+/// The events of a copy as they happen, from
+/// [`Copyer::event_stream`](crate::Copyer::event_stream). Ends after
+/// [`Event::RunFinished`]. Events are queued until they are read.
+pub struct EventStream {
+    queue: Arc<Mutex<EventQueue>>,
+}
+
+#[derive(Default)]
+struct EventQueue {
+    events: VecDeque<Event>,
+    closed: bool,
+    // The stream was dropped, nobody reads events anymore.
+    abandoned: bool,
+    waker: Option<Waker>,
+}
+
+// The copy's end of an `EventStream`.
+pub(crate) struct EventSender {
+    queue: Arc<Mutex<EventQueue>>,
+}
+
+impl EventStream {
+    pub(crate) fn channel() -> (EventSender, EventStream) {
+        let queue = Arc::new(Mutex::new(EventQueue::default()));
+        (
+            EventSender {
+                queue: queue.clone(),
+            },
+            EventStream { queue },
+        )
+    }
+}
+
+impl Stream for EventStream {
+    type Item = Event;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Event>> {
+        let mut queue = self.queue.lock().unwrap();
+        if let Some(event) = queue.events.pop_front() {
+            return Poll::Ready(Some(event));
+        }
+        if queue.closed {
+            return Poll::Ready(None);
+        }
+        queue.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.abandoned = true;
+        queue.events.clear();
+    }
+}
+
+impl EventSender {
+    pub(crate) fn send(&self, event: Event) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.abandoned || queue.closed {
+            return;
+        }
+        queue.events.push_back(event);
+        if let Some(waker) = queue.waker.take() {
+            waker.wake();
+        }
+    }
+
+    pub(crate) fn close(&self) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.closed = true;
+        if let Some(waker) = queue.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl Drop for EventSender {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
@@ -5,6 +5,7 @@ use std::io;
 use std::io::Write;
 use std::path::Path;
 
+/// Fills a directory with a random tree of small files, to benchmark copies with.
 pub struct TestDirGenerator<'a> {
     threshold: f64,
     max_depth: u32,
@@ -12,8 +13,8 @@ pub struct TestDirGenerator<'a> {
 }
 
 impl<'a> TestDirGenerator<'a> {
-    pub fn builder() -> TestBirGeneratorBuilder<'a> {
-        TestBirGeneratorBuilder::new()
+    pub fn builder() -> TestDirGeneratorBuilder<'a> {
+        TestDirGeneratorBuilder::new()
     }
 
     pub fn gen(self) -> Result<(), io::Error> {
@@ -56,7 +57,7 @@ impl<'a> TestDirGenerator<'a> {
     fn if_continue_gen(&self, level: u32) -> bool {
         let mut i: f64 = thread_rng().gen_range(0.0..1.0);
         //the lesser the level is,  the return tends to true
-        i = i * (self.max_depth - level) as f64;
+        i *= (self.max_depth - level) as f64;
         i > self.threshold
     }
 
@@ -74,13 +75,13 @@ impl<'a> TestDirGenerator<'a> {
     }
 }
 
-pub struct TestBirGeneratorBuilder<'a> {
+pub struct TestDirGeneratorBuilder<'a> {
     threshold: f64,
     max_depth: u32,
     upper_path: Option<&'a Path>,
 }
 
-impl<'a> TestBirGeneratorBuilder<'a> {
+impl<'a> TestDirGeneratorBuilder<'a> {
     fn new() -> Self {
         Self {
             threshold: 0.2,
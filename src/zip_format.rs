@@ -0,0 +1,818 @@
+use crate::date::{civil_from_days, days_from_civil};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const LOCAL_HEADER: u32 = 0x04034b50;
+const CENTRAL_HEADER: u32 = 0x02014b50;
+const DATA_DESCRIPTOR: u32 = 0x08074b50;
+const END_OF_CENTRAL_DIR: u32 = 0x06054b50;
+const ZIP64_END_OF_CENTRAL_DIR: u32 = 0x06064b50;
+const ZIP64_LOCATOR: u32 = 0x07064b50;
+const ZIP64_EXTRA: u16 = 0x0001;
+// Info-ZIP's extended timestamp, the modification time in seconds.
+const TIMESTAMP_EXTRA: u16 = 0x5455;
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+const ZSTD: u16 = 93;
+const FLAG_ENCRYPTED: u16 = 1;
+// Sizes and checksum follow the data, in a data descriptor.
+const FLAG_DESCRIPTOR: u16 = 1 << 3;
+const FLAG_UTF8: u16 = 1 << 11;
+// Made on unix, after version 6.3 of the spec.
+const MADE_BY: u16 = 3 << 8 | 63;
+const UNIX: u8 = 3;
+const OS_X: u8 = 19;
+const DOS_READ_ONLY: u32 = 0x01;
+const DOS_DIR: u32 = 0x10;
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+// Past this a size or offset takes a zip64 field.
+const MAX_32: u64 = 0xffffffff;
+// Streamed files this big may end up past `MAX_32` once compressed, their local header has
+// zip64 fields.
+const STREAM_ZIP64: u64 = 0xf0000000;
+
+/// How files are compressed in a zip archive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ZipCompression {
+    /// Stored as they are.
+    Store,
+    /// Deflate, which every zip tool reads.
+    Deflate,
+    /// Zstandard, faster and smaller but only read by newer tools.
+    Zstd,
+}
+
+impl FromStr for ZipCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "store" => Ok(ZipCompression::Store),
+            "deflate" => Ok(ZipCompression::Deflate),
+            "zstd" => Ok(ZipCompression::Zstd),
+            _ => Err(format!(
+                "unknown zip compression '{}', expected one of store, deflate, zstd",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ZipCompression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ZipCompression::Store => "store",
+            ZipCompression::Deflate => "deflate",
+            ZipCompression::Zstd => "zstd",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl ZipCompression {
+    fn method(&self) -> u16 {
+        match self {
+            ZipCompression::Store => STORED,
+            ZipCompression::Deflate => DEFLATED,
+            ZipCompression::Zstd => ZSTD,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ZipKind {
+    File,
+    Dir,
+    Symlink,
+}
+
+// What the headers of an entry say besides its data.
+pub(crate) struct EntryHeader {
+    // Separated by `/`, which directories end with.
+    pub(crate) name: String,
+    pub(crate) kind: ZipKind,
+    // Permission bits.
+    pub(crate) mode: u32,
+    pub(crate) modified: Option<SystemTime>,
+}
+
+// The data of an entry, compressed with the checksum and size of the original, as worker
+// threads hand it to the writer.
+pub(crate) struct Compressed {
+    method: u16,
+    crc: u32,
+    size: u64,
+    data: Vec<u8>,
+}
+
+impl Compressed {
+    // Data that doesn't get smaller is stored.
+    pub(crate) fn new(data: &[u8], compression: ZipCompression) -> io::Result<Self> {
+        let packed = match compression {
+            ZipCompression::Store => None,
+            ZipCompression::Deflate => {
+                let out = Vec::with_capacity(data.len() / 2);
+                let mut encoder = DeflateEncoder::new(out, flate2::Compression::default());
+                encoder.write_all(data)?;
+                Some(encoder.finish()?)
+            }
+            ZipCompression::Zstd => Some(zstd::bulk::compress(data, 0)?),
+        };
+        let (method, data_out) = match packed {
+            Some(packed) if packed.len() < data.len() => (compression.method(), packed),
+            _ => (STORED, data.to_vec()),
+        };
+        Ok(Self {
+            method,
+            crc: crc32fast::hash(data),
+            size: data.len() as u64,
+            data: data_out,
+        })
+    }
+
+    // Of the data before compression.
+    pub(crate) fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+struct Sizes {
+    crc: u32,
+    compressed: u64,
+    size: u64,
+}
+
+// Writes a zip archive front to back, so it can go to a pipe: entries with their data, then the
+// central directory listing them. Sizes of streamed entries follow their data.
+pub(crate) struct ZipWriter<W: Write> {
+    out: W,
+    offset: u64,
+    central: Vec<u8>,
+    entries: u64,
+}
+
+impl<W: Write> ZipWriter<W> {
+    pub(crate) fn new(out: W) -> Self {
+        Self {
+            out,
+            offset: 0,
+            central: vec![],
+            entries: 0,
+        }
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut W {
+        &mut self.out
+    }
+
+    // A directory or symlink takes no data, use an empty `Compressed` for it; a symlink's is
+    // its target.
+    pub(crate) fn add(&mut self, header: &EntryHeader, data: &Compressed) -> io::Result<()> {
+        let offset = self.offset;
+        let sizes = Sizes {
+            crc: data.crc,
+            compressed: data.data.len() as u64,
+            size: data.size,
+        };
+        let zip64 = sizes.compressed >= MAX_32 || sizes.size >= MAX_32;
+        self.write_local(header, data.method, 0, Some(&sizes), zip64)?;
+        self.write_all(&data.data)?;
+        self.add_central(header, data.method, 0, offset, &sizes);
+        Ok(())
+    }
+
+    // A file too big to be held, compressed while it is read. `len` is about how big it is.
+    pub(crate) fn add_streamed(
+        &mut self,
+        header: &EntryHeader,
+        compression: ZipCompression,
+        reader: &mut dyn Read,
+        len: u64,
+    ) -> io::Result<()> {
+        let offset = self.offset;
+        let method = compression.method();
+        let zip64 = len >= STREAM_ZIP64;
+        self.write_local(header, method, FLAG_DESCRIPTOR, None, zip64)?;
+        let mut input = Checksummed {
+            inner: reader,
+            crc: crc32fast::Hasher::new(),
+            count: 0,
+        };
+        let mut output = Counted {
+            inner: &mut self.out,
+            count: 0,
+        };
+        match compression {
+            ZipCompression::Store => {
+                io::copy(&mut input, &mut output)?;
+            }
+            ZipCompression::Deflate => {
+                let mut encoder = DeflateEncoder::new(&mut output, flate2::Compression::default());
+                io::copy(&mut input, &mut encoder)?;
+                encoder.finish()?;
+            }
+            ZipCompression::Zstd => {
+                let mut encoder = zstd::Encoder::new(&mut output, 0)?;
+                io::copy(&mut input, &mut encoder)?;
+                encoder.finish()?;
+            }
+        }
+        self.offset += output.count;
+        let sizes = Sizes {
+            crc: input.crc.finalize(),
+            compressed: output.count,
+            size: input.count,
+        };
+        if !zip64 && (sizes.compressed >= MAX_32 || sizes.size >= MAX_32) {
+            return Err(io::Error::other("file grew past 4 GiB while archived"));
+        }
+
+        let mut descriptor = vec![];
+        put32(&mut descriptor, DATA_DESCRIPTOR);
+        put32(&mut descriptor, sizes.crc);
+        if zip64 {
+            put64(&mut descriptor, sizes.compressed);
+            put64(&mut descriptor, sizes.size);
+        } else {
+            put32(&mut descriptor, sizes.compressed as u32);
+            put32(&mut descriptor, sizes.size as u32);
+        }
+        self.write_all(&descriptor)?;
+        self.add_central(header, method, FLAG_DESCRIPTOR, offset, &sizes);
+        Ok(())
+    }
+
+    // Write the central directory, and return the output.
+    pub(crate) fn finish(mut self) -> io::Result<W> {
+        let (start, size) = (self.offset, self.central.len() as u64);
+        let central = std::mem::take(&mut self.central);
+        self.write_all(&central)?;
+        let mut end = vec![];
+        if self.entries >= 0xffff || start >= MAX_32 || size >= MAX_32 {
+            let record = self.offset;
+            put32(&mut end, ZIP64_END_OF_CENTRAL_DIR);
+            put64(&mut end, 44);
+            put16(&mut end, MADE_BY);
+            put16(&mut end, 45);
+            put32(&mut end, 0);
+            put32(&mut end, 0);
+            put64(&mut end, self.entries);
+            put64(&mut end, self.entries);
+            put64(&mut end, size);
+            put64(&mut end, start);
+            put32(&mut end, ZIP64_LOCATOR);
+            put32(&mut end, 0);
+            put64(&mut end, record);
+            put32(&mut end, 1);
+        }
+        put32(&mut end, END_OF_CENTRAL_DIR);
+        put16(&mut end, 0);
+        put16(&mut end, 0);
+        put16(&mut end, self.entries.min(0xffff) as u16);
+        put16(&mut end, self.entries.min(0xffff) as u16);
+        put32(&mut end, size.min(MAX_32) as u32);
+        put32(&mut end, start.min(MAX_32) as u32);
+        put16(&mut end, 0);
+        self.write_all(&end)?;
+        Ok(self.out)
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        self.out.write_all(data)?;
+        self.offset += data.len() as u64;
+        Ok(())
+    }
+
+    // Without `sizes` they come in a data descriptor.
+    fn write_local(
+        &mut self,
+        header: &EntryHeader,
+        method: u16,
+        flags: u16,
+        sizes: Option<&Sizes>,
+        zip64: bool,
+    ) -> io::Result<()> {
+        let (date, time) = dos_date_time(header.modified);
+        let mut extra = vec![];
+        if zip64 {
+            put16(&mut extra, ZIP64_EXTRA);
+            put16(&mut extra, 16);
+            put64(&mut extra, sizes.map_or(0, |s| s.size));
+            put64(&mut extra, sizes.map_or(0, |s| s.compressed));
+        }
+        put_timestamp(&mut extra, header.modified);
+        let field = |value: u64| if zip64 { MAX_32 as u32 } else { value as u32 };
+
+        let mut local = Vec::with_capacity(30 + header.name.len() + extra.len());
+        put32(&mut local, LOCAL_HEADER);
+        put16(&mut local, version_needed(method, zip64));
+        put16(&mut local, flags | FLAG_UTF8);
+        put16(&mut local, method);
+        put16(&mut local, time);
+        put16(&mut local, date);
+        put32(&mut local, sizes.map_or(0, |s| s.crc));
+        put32(&mut local, sizes.map_or(0, |s| field(s.compressed)));
+        put32(&mut local, sizes.map_or(0, |s| field(s.size)));
+        put16(&mut local, header.name.len() as u16);
+        put16(&mut local, extra.len() as u16);
+        local.extend_from_slice(header.name.as_bytes());
+        local.extend_from_slice(&extra);
+        self.write_all(&local)
+    }
+
+    fn add_central(
+        &mut self,
+        header: &EntryHeader,
+        method: u16,
+        flags: u16,
+        offset: u64,
+        sizes: &Sizes,
+    ) {
+        let (date, time) = dos_date_time(header.modified);
+        // Only what doesn't fit goes into the zip64 field.
+        let mut zip64 = vec![];
+        for value in [sizes.size, sizes.compressed, offset] {
+            if value >= MAX_32 {
+                put64(&mut zip64, value);
+            }
+        }
+        let mut extra = vec![];
+        if !zip64.is_empty() {
+            put16(&mut extra, ZIP64_EXTRA);
+            put16(&mut extra, zip64.len() as u16);
+            extra.extend_from_slice(&zip64);
+        }
+        put_timestamp(&mut extra, header.modified);
+        let (file_type, dos) = match header.kind {
+            ZipKind::File => (S_IFREG, 0),
+            ZipKind::Dir => (S_IFDIR, DOS_DIR),
+            ZipKind::Symlink => (S_IFLNK, 0),
+        };
+
+        let c = &mut self.central;
+        put32(c, CENTRAL_HEADER);
+        put16(c, MADE_BY);
+        put16(c, version_needed(method, !zip64.is_empty()));
+        put16(c, flags | FLAG_UTF8);
+        put16(c, method);
+        put16(c, time);
+        put16(c, date);
+        put32(c, sizes.crc);
+        put32(c, sizes.compressed.min(MAX_32) as u32);
+        put32(c, sizes.size.min(MAX_32) as u32);
+        put16(c, header.name.len() as u16);
+        put16(c, extra.len() as u16);
+        // Comment, disk and internal attributes.
+        put16(c, 0);
+        put16(c, 0);
+        put16(c, 0);
+        put32(c, (file_type | header.mode & 0o7777) << 16 | dos);
+        put32(c, offset.min(MAX_32) as u32);
+        c.extend_from_slice(header.name.as_bytes());
+        c.extend_from_slice(&extra);
+        self.entries += 1;
+    }
+}
+
+fn version_needed(method: u16, zip64: bool) -> u16 {
+    match method {
+        ZSTD => 63,
+        _ if zip64 => 45,
+        _ => 20,
+    }
+}
+
+// An entry as the central directory lists it.
+pub(crate) struct ZipEntry {
+    // Separated by `/`, also when written on Windows with `\`.
+    pub(crate) name: String,
+    pub(crate) kind: ZipKind,
+    // Permission bits; for entries not made on unix, defaults after their DOS attributes.
+    pub(crate) mode: u32,
+    pub(crate) modified: Option<SystemTime>,
+    pub(crate) size: u64,
+    method: u16,
+    flags: u16,
+    crc: u32,
+    compressed_size: u64,
+    header_offset: u64,
+}
+
+impl ZipEntry {
+    // Where the entry's local header, followed by its data, is in the archive.
+    pub(crate) fn offset(&self) -> u64 {
+        self.header_offset
+    }
+
+    // The data of the entry, checked against its size and checksum when read to the end.
+    pub(crate) fn reader<'a, R: Read + Seek + 'a>(
+        &self,
+        mut archive: R,
+    ) -> io::Result<Box<dyn Read + 'a>> {
+        if self.flags & FLAG_ENCRYPTED != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "encrypted entries aren't supported",
+            ));
+        }
+        archive.seek(SeekFrom::Start(self.header_offset))?;
+        let mut local = [0; 30];
+        archive.read_exact(&mut local)?;
+        if le32(&local[0..]) != LOCAL_HEADER {
+            return Err(invalid("bad local header"));
+        }
+        let skip = i64::from(le16(&local[26..])) + i64::from(le16(&local[28..]));
+        archive.seek(SeekFrom::Current(skip))?;
+        let data = archive.take(self.compressed_size);
+        let inner: Box<dyn Read + 'a> = match self.method {
+            STORED => Box::new(data),
+            DEFLATED => Box::new(DeflateDecoder::new(data)),
+            ZSTD => Box::new(zstd::Decoder::new(data)?),
+            method => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("compression method {} isn't supported", method),
+                ))
+            }
+        };
+        Ok(Box::new(Verified {
+            inner,
+            crc: crc32fast::Hasher::new(),
+            expected_crc: self.crc,
+            left: self.size,
+        }))
+    }
+}
+
+// Entries of the archive, in the order of the central directory.
+pub(crate) fn read_central_directory<R: Read + Seek>(reader: &mut R) -> io::Result<Vec<ZipEntry>> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    // The end record is 22 bytes, with a comment of up to 64 KiB.
+    let tail_len = len.min(22 + 0xffff);
+    if tail_len < 22 {
+        return Err(invalid("not a zip archive"));
+    }
+    let tail_start = len - tail_len;
+    reader.seek(SeekFrom::Start(tail_start))?;
+    let mut tail = vec![0; tail_len as usize];
+    reader.read_exact(&mut tail)?;
+    let end = (0..=tail.len() - 22)
+        .rev()
+        .find(|&i| le32(&tail[i..]) == END_OF_CENTRAL_DIR)
+        .ok_or_else(|| invalid("not a zip archive"))?;
+    let record = &tail[end..];
+    let mut count = u64::from(le16(&record[10..]));
+    let mut size = u64::from(le32(&record[12..]));
+    let mut start = u64::from(le32(&record[16..]));
+    if end >= 20 && le32(&tail[end - 20..]) == ZIP64_LOCATOR {
+        reader.seek(SeekFrom::Start(le64(&tail[end - 12..])))?;
+        let mut record = [0; 56];
+        reader.read_exact(&mut record)?;
+        if le32(&record[0..]) != ZIP64_END_OF_CENTRAL_DIR {
+            return Err(invalid("bad zip64 end of central directory"));
+        }
+        count = le64(&record[32..]);
+        size = le64(&record[40..]);
+        start = le64(&record[48..]);
+    }
+    if start.checked_add(size).is_none_or(|end| end > len) {
+        return Err(invalid("central directory out of the archive"));
+    }
+    reader.seek(SeekFrom::Start(start))?;
+    let mut central = vec![0; size as usize];
+    reader.read_exact(&mut central)?;
+
+    let mut entries = Vec::with_capacity(count.min(size / 46) as usize);
+    let mut rest = &central[..];
+    while entries.len() < count as usize {
+        if rest.len() < 46 || le32(rest) != CENTRAL_HEADER {
+            return Err(invalid("bad central directory"));
+        }
+        let name_len = usize::from(le16(&rest[28..]));
+        let extra_len = usize::from(le16(&rest[30..]));
+        let comment_len = usize::from(le16(&rest[32..]));
+        let total = 46 + name_len + extra_len + comment_len;
+        if rest.len() < total {
+            return Err(invalid("bad central directory"));
+        }
+        entries.push(parse_central(&rest[..total], name_len, extra_len));
+        rest = &rest[total..];
+    }
+    Ok(entries)
+}
+
+fn parse_central(header: &[u8], name_len: usize, extra_len: usize) -> ZipEntry {
+    let made_on = (le16(&header[4..]) >> 8) as u8;
+    let flags = le16(&header[8..]);
+    let raw_name = &header[46..46 + name_len];
+    let extra = &header[46 + name_len..46 + name_len + extra_len];
+    let external = le32(&header[38..]);
+    let mut size = u64::from(le32(&header[24..]));
+    let mut compressed_size = u64::from(le32(&header[20..]));
+    let mut header_offset = u64::from(le32(&header[42..]));
+    let mut modified = dos_to_system_time(le16(&header[14..]), le16(&header[12..]));
+
+    let mut fields = extra;
+    while fields.len() >= 4 {
+        let (id, len) = (le16(fields), usize::from(le16(&fields[2..])));
+        let data = &fields[4..(4 + len).min(fields.len())];
+        match id {
+            ZIP64_EXTRA => {
+                let mut values = data.chunks_exact(8).map(le64);
+                for value in [&mut size, &mut compressed_size, &mut header_offset] {
+                    if *value == MAX_32 {
+                        *value = values.next().unwrap_or(MAX_32);
+                    }
+                }
+            }
+            TIMESTAMP_EXTRA if data.len() >= 5 && data[0] & 1 != 0 => {
+                modified = Some(UNIX_EPOCH + Duration::from_secs(u64::from(le32(&data[1..]))));
+            }
+            _ => {}
+        }
+        fields = &fields[(4 + len).min(fields.len())..];
+    }
+
+    let name = if flags & FLAG_UTF8 != 0 || raw_name.is_ascii() {
+        String::from_utf8_lossy(raw_name).into_owned()
+    } else {
+        raw_name.iter().map(|&b| cp437(b)).collect()
+    };
+    let unix = made_on == UNIX || made_on == OS_X;
+    let name = if unix { name } else { name.replace('\\', "/") };
+    let unix_mode = if unix { external >> 16 } else { 0 };
+    let kind = match unix_mode & S_IFMT {
+        S_IFLNK => ZipKind::Symlink,
+        S_IFDIR => ZipKind::Dir,
+        _ if name.ends_with('/') || external & DOS_DIR != 0 => ZipKind::Dir,
+        _ => ZipKind::File,
+    };
+    let mode = match kind {
+        _ if unix_mode != 0 => unix_mode & 0o7777,
+        ZipKind::Dir => 0o755,
+        _ if external & DOS_READ_ONLY != 0 => 0o444,
+        _ => 0o644,
+    };
+    ZipEntry {
+        name,
+        kind,
+        mode,
+        modified,
+        size,
+        method: le16(&header[10..]),
+        flags,
+        crc: le32(&header[16..]),
+        compressed_size,
+        header_offset,
+    }
+}
+
+// Entry data as it is read, failing at the end if it doesn't match what the archive says.
+struct Verified<'a> {
+    inner: Box<dyn Read + 'a>,
+    crc: crc32fast::Hasher,
+    expected_crc: u32,
+    left: u64,
+}
+
+impl Read for Verified<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n as u64 > self.left {
+            return Err(invalid("entry is longer than its size"));
+        }
+        self.left -= n as u64;
+        self.crc.update(&buf[..n]);
+        if n == 0 && !buf.is_empty() {
+            if self.left > 0 {
+                return Err(invalid("entry is shorter than its size"));
+            }
+            if self.crc.clone().finalize() != self.expected_crc {
+                return Err(invalid("entry checksum mismatch"));
+            }
+        }
+        Ok(n)
+    }
+}
+
+struct Checksummed<'a> {
+    inner: &'a mut dyn Read,
+    crc: crc32fast::Hasher,
+    count: u64,
+}
+
+impl Read for Checksummed<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.crc.update(&buf[..n]);
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+struct Counted<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for Counted<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// DOS date and time, in UTC for lack of a time zone, within the years 1980 to 2107 they hold.
+fn dos_date_time(time: Option<SystemTime>) -> (u16, u16) {
+    let secs = time
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs()) as i64;
+    let (year, month, day) = civil_from_days(secs / 86400);
+    let rest = secs % 86400;
+    match year {
+        ..1980 => (1 << 5 | 1, 0),
+        2108.. => (127 << 9 | 12 << 5 | 31, 23 << 11 | 59 << 5 | 29),
+        _ => (
+            ((year - 1980) << 9 | i64::from(month) << 5 | i64::from(day)) as u16,
+            ((rest / 3600) << 11 | (rest % 3600 / 60) << 5 | (rest % 60 / 2)) as u16,
+        ),
+    }
+}
+
+fn dos_to_system_time(date: u16, time: u16) -> Option<SystemTime> {
+    let (year, month, day) = (1980 + i64::from(date >> 9), (date >> 5) & 0xf, date & 0x1f);
+    if !(1..=12).contains(&month) || day == 0 {
+        return None;
+    }
+    let days = days_from_civil(year, u32::from(month), u32::from(day));
+    let secs = days * 86400
+        + i64::from(time >> 11) * 3600
+        + i64::from((time >> 5) & 0x3f) * 60
+        + i64::from(time & 0x1f) * 2;
+    Some(UNIX_EPOCH + Duration::from_secs(secs as u64))
+}
+
+// Seconds as Info-ZIP tools read them, signed 32 bits.
+fn put_timestamp(extra: &mut Vec<u8>, time: Option<SystemTime>) {
+    let secs = time.and_then(|t| t.duration_since(UNIX_EPOCH).ok());
+    if let Some(secs) = secs.filter(|s| s.as_secs() <= i32::MAX as u64) {
+        put16(extra, TIMESTAMP_EXTRA);
+        put16(extra, 5);
+        extra.push(1);
+        put32(extra, secs.as_secs() as u32);
+    }
+}
+
+// Names without the UTF-8 flag are in the DOS code page, as Windows writes them.
+fn cp437(byte: u8) -> char {
+    const HIGH: &str = "ÇüéâäàåçêëèïîìÄÅÉæÆôöòûùÿÖÜ¢£¥₧ƒáíóúñÑªº¿⌐¬½¼¡«»░▒▓│┤╡╢╖╕╣║╗╝╜╛┐\
+                        └┴┬├─┼╞╟╚╔╩╦╠═╬╧╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀αßΓπΣσµτΦΘΩδ∞φε∩≡±≥≤⌠⌡÷≈°∙·√ⁿ²■\u{a0}";
+    match byte {
+        0..=0x7f => char::from(byte),
+        _ => HIGH.chars().nth(usize::from(byte - 0x80)).unwrap(),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn put16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn le16(bytes: &[u8]) -> u16 {
+    u16::from_le_bytes([bytes[0], bytes[1]])
+}
+
+fn le32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[..4].try_into().unwrap())
+}
+
+fn le64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes[..8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    fn header(name: &str, kind: ZipKind, mode: u32) -> EntryHeader {
+        EntryHeader {
+            name: String::from(name),
+            kind,
+            mode,
+            modified: Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+        }
+    }
+
+    #[test]
+    fn zip_round_trip_test() {
+        let text = b"zip zip zip zip zip zip zip zip zip zip".repeat(100);
+        let mut writer = ZipWriter::new(vec![]);
+        let dir = header("dir/", ZipKind::Dir, 0o750);
+        writer.add(&dir, &Compressed::new(b"", ZipCompression::Store).unwrap()).unwrap();
+        for (name, compression) in [("dir/d", ZipCompression::Deflate), ("z", ZipCompression::Zstd)]
+        {
+            let data = Compressed::new(&text, compression).unwrap();
+            assert!(data.data.len() < text.len());
+            writer.add(&header(name, ZipKind::File, 0o640), &data).unwrap();
+        }
+        let link = Compressed::new(b"dir/d", ZipCompression::Deflate).unwrap();
+        writer.add(&header("link", ZipKind::Symlink, 0o777), &link).unwrap();
+        let big = header("big", ZipKind::File, 0o600);
+        writer.add_streamed(&big, ZipCompression::Zstd, &mut &text[..], 0).unwrap();
+        let archive = writer.finish().unwrap();
+
+        // Read by another implementation.
+        let mut other = zip::ZipArchive::new(Cursor::new(&archive)).unwrap();
+        assert_eq!(other.len(), 5);
+        let mut data = vec![];
+        other.by_name("big").unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, text);
+        assert_eq!(other.by_name("dir/d").unwrap().unix_mode(), Some(0o100640));
+
+        let mut reader = Cursor::new(&archive);
+        let entries = read_central_directory(&mut reader).unwrap();
+        let kinds: Vec<_> = entries.iter().map(|e| (e.name.as_str(), e.kind, e.mode)).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ("dir/", ZipKind::Dir, 0o750),
+                ("dir/d", ZipKind::File, 0o640),
+                ("z", ZipKind::File, 0o640),
+                ("link", ZipKind::Symlink, 0o777),
+                ("big", ZipKind::File, 0o600),
+            ]
+        );
+        for entry in &entries[1..] {
+            let mut data = vec![];
+            entry.reader(&mut reader).unwrap().read_to_end(&mut data).unwrap();
+            let expected = if entry.kind == ZipKind::Symlink { &b"dir/d"[..] } else { &text };
+            assert_eq!(data, expected);
+            assert_eq!(entry.modified, dir.modified);
+        }
+    }
+
+    #[test]
+    fn zip64_test() {
+        let mut writer = ZipWriter::new(vec![]);
+        let empty = Compressed::new(b"", ZipCompression::Deflate).unwrap();
+        for i in 0..70000 {
+            writer.add(&header(&i.to_string(), ZipKind::File, 0o644), &empty).unwrap();
+        }
+        let archive = writer.finish().unwrap();
+        let entries = read_central_directory(&mut Cursor::new(&archive)).unwrap();
+        assert_eq!(entries.len(), 70000);
+        assert_eq!(entries[69999].name, "69999");
+        assert_eq!(zip::ZipArchive::new(Cursor::new(&archive)).unwrap().len(), 70000);
+    }
+
+    #[test]
+    fn windows_entry_test() {
+        // Made on DOS, read-only, with a CP437 name and `\` separators.
+        let name = b"dir\\caf\x82.txt";
+        let mut header = vec![];
+        put32(&mut header, CENTRAL_HEADER);
+        put16(&mut header, 20);
+        header.resize(28, 0);
+        put16(&mut header, name.len() as u16);
+        header.resize(38, 0);
+        put32(&mut header, DOS_READ_ONLY);
+        put32(&mut header, 0);
+        header.extend_from_slice(name);
+        let entry = parse_central(&header, name.len(), 0);
+        assert_eq!(entry.name, "dir/café.txt");
+        assert_eq!((entry.kind, entry.mode), (ZipKind::File, 0o444));
+    }
+
+    #[test]
+    fn dos_date_time_test() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let (date, dos_time) = dos_date_time(Some(time));
+        assert_eq!(dos_to_system_time(date, dos_time), Some(time));
+        assert_eq!(dos_date_time(None), (1 << 5 | 1, 0));
+        assert_eq!(cp437(0xff), '\u{a0}');
+    }
+}
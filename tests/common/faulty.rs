@@ -0,0 +1,275 @@
+// A filesystem failing chosen operations on demand, for tests of how the copy copes.
+
+use r_fast_copy::{FileSystem, Metadata, ReadDir, ReadFile, Space, WriteFile, Xattrs};
+use std::ffi::OsStr;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+// Operations a fault can hit. `Read`, `Write` and `Sync` are single calls on an open file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    ReadDir,
+    Stat,
+    Open,
+    Read,
+    Create,
+    Write,
+    Sync,
+    SyncDir,
+    SyncFs,
+}
+
+// One injected fault: calls of `op` on matching paths are delayed, fail or panic. Which calls is
+// counted per fault, so with `nth` exactly one call fails however threads interleave.
+pub struct Fault {
+    op: Op,
+    path: Option<PathBuf>,
+    nth: Option<usize>,
+    errno: Option<i32>,
+    panics: bool,
+    delay: Duration,
+    calls: AtomicUsize,
+}
+
+impl Fault {
+    pub fn new(op: Op) -> Self {
+        Self {
+            op,
+            path: None,
+            nth: None,
+            errno: None,
+            panics: false,
+            delay: Duration::ZERO,
+            calls: AtomicUsize::new(0),
+        }
+    }
+
+    // Only paths ending with `path`.
+    pub fn on(mut self, path: &str) -> Self {
+        self.path = Some(PathBuf::from(path));
+        self
+    }
+
+    // Only the `n`th matching call, counting from 1.
+    pub fn nth(mut self, n: usize) -> Self {
+        self.nth = Some(n);
+        self
+    }
+
+    // Fail with this OS error, e.g. `libc::EIO`.
+    pub fn error(mut self, errno: i32) -> Self {
+        self.errno = Some(errno);
+        self
+    }
+
+    // Panic instead, like a buggy backend would.
+    pub fn panics(mut self) -> Self {
+        self.panics = true;
+        self
+    }
+
+    // Sleep this long before the call.
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    fn hit(&self, op: Op, path: &Path) -> io::Result<()> {
+        if op != self.op || self.path.as_ref().is_some_and(|p| !path.ends_with(p)) {
+            return Ok(());
+        }
+        let n = self.calls.fetch_add(1, Ordering::SeqCst) + 1;
+        if self.nth.is_some_and(|nth| nth != n) {
+            return Ok(());
+        }
+        thread::sleep(self.delay);
+        if self.panics {
+            panic!("injected panic on {:?}", path);
+        }
+        match self.errno {
+            Some(errno) => Err(io::Error::from_raw_os_error(errno)),
+            None => Ok(()),
+        }
+    }
+
+    // How many calls matched so far.
+    pub fn calls(&self) -> usize {
+        self.calls.load(Ordering::SeqCst)
+    }
+}
+
+#[derive(Default)]
+pub struct Faults(Vec<Arc<Fault>>);
+
+impl Faults {
+    fn check(&self, op: Op, path: &Path) -> io::Result<()> {
+        self.0.iter().try_for_each(|fault| fault.hit(op, path))
+    }
+}
+
+pub struct FaultyFs {
+    inner: Arc<dyn FileSystem>,
+    faults: Arc<Faults>,
+}
+
+impl FaultyFs {
+    pub fn new(inner: Arc<dyn FileSystem>, faults: Vec<Arc<Fault>>) -> Self {
+        Self {
+            inner,
+            faults: Arc::new(Faults(faults)),
+        }
+    }
+}
+
+struct FaultyReader<R> {
+    inner: R,
+    path: PathBuf,
+    faults: Arc<Faults>,
+}
+
+impl<R: Read> Read for FaultyReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.faults.check(Op::Read, &self.path)?;
+        self.inner.read(buf)
+    }
+}
+
+impl<R: Seek> Seek for FaultyReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+struct FaultyWriter {
+    inner: Box<dyn WriteFile>,
+    path: PathBuf,
+    faults: Arc<Faults>,
+}
+
+impl Write for FaultyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.faults.check(Op::Write, &self.path)?;
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl WriteFile for FaultyWriter {
+    fn sync_all(&mut self) -> io::Result<()> {
+        self.faults.check(Op::Sync, &self.path)?;
+        self.inner.sync_all()
+    }
+}
+
+impl FileSystem for FaultyFs {
+    fn read_dir(&self, path: &Path) -> io::Result<ReadDir> {
+        self.faults.check(Op::ReadDir, path)?;
+        self.inner.read_dir(path)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        self.faults.check(Op::Stat, path)?;
+        self.inner.metadata(path)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        self.faults.check(Op::Stat, path)?;
+        self.inner.symlink_metadata(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        self.inner.canonicalize(path)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        self.faults.check(Op::Open, path)?;
+        Ok(Box::new(FaultyReader {
+            inner: self.inner.open(path)?,
+            path: path.to_path_buf(),
+            faults: self.faults.clone(),
+        }))
+    }
+
+    fn open_seekable(&self, path: &Path) -> io::Result<Box<dyn ReadFile>> {
+        self.faults.check(Op::Open, path)?;
+        Ok(Box::new(FaultyReader {
+            inner: self.inner.open_seekable(path)?,
+            path: path.to_path_buf(),
+            faults: self.faults.clone(),
+        }))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WriteFile>> {
+        self.faults.check(Op::Create, path)?;
+        Ok(Box::new(FaultyWriter {
+            inner: self.inner.create(path)?,
+            path: path.to_path_buf(),
+            faults: self.faults.clone(),
+        }))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.inner.create_dir_all(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove_dir(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.inner.rename(from, to)
+    }
+
+    fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()> {
+        self.inner.hard_link(original, link)
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        self.inner.read_link(path)
+    }
+
+    fn symlink(&self, target: &Path, link: &Path) -> io::Result<()> {
+        self.inner.symlink(target, link)
+    }
+
+    fn set_permissions(&self, path: &Path, mode: u32) -> io::Result<()> {
+        self.inner.set_permissions(path, mode)
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        self.faults.check(Op::SyncDir, path)?;
+        self.inner.sync_dir(path)
+    }
+
+    fn sync_fs(&self, path: &Path) -> io::Result<()> {
+        self.faults.check(Op::SyncFs, path)?;
+        self.inner.sync_fs(path)
+    }
+
+    fn set_modified(&self, path: &Path, modified: SystemTime) -> io::Result<()> {
+        self.inner.set_modified(path, modified)
+    }
+
+    fn xattrs(&self, path: &Path) -> io::Result<Xattrs> {
+        self.inner.xattrs(path)
+    }
+
+    fn set_xattr(&self, path: &Path, name: &OsStr, value: &[u8]) -> io::Result<()> {
+        self.inner.set_xattr(path, name, value)
+    }
+
+    fn available(&self, path: &Path) -> io::Result<Space> {
+        self.inner.available(path)
+    }
+}
@@ -0,0 +1,45 @@
+// Helpers shared by the integration tests, each of which uses some of them.
+#![allow(dead_code)]
+
+pub mod faulty;
+
+use r_fast_copy::{CopyBuilder, FileSystem, MemoryFs};
+use std::path::Path;
+use std::sync::Arc;
+
+// `builder` running on a pool of `threads`, or on the calling thread for 0.
+pub fn with_threads(builder: CopyBuilder, threads: usize) -> CopyBuilder {
+    if threads > 0 {
+        builder.set_threads_number(threads)
+    } else {
+        builder
+    }
+}
+
+// A `MemoryFs` filled entry by entry; parent directories are made as needed.
+pub struct MemoryTree(Arc<MemoryFs>);
+
+impl MemoryTree {
+    pub fn new() -> Self {
+        Self(Arc::new(MemoryFs::new()))
+    }
+
+    pub fn file(self, path: &str, data: &[u8]) -> Self {
+        self.0.write_file(Path::new(path), data).unwrap();
+        self
+    }
+
+    pub fn symlink(self, target: &str, link: &str) -> Self {
+        self.0.symlink(Path::new(target), Path::new(link)).unwrap();
+        self
+    }
+
+    pub fn hard_link(self, original: &str, link: &str) -> Self {
+        self.0.hard_link(Path::new(original), Path::new(link)).unwrap();
+        self
+    }
+
+    pub fn build(self) -> Arc<MemoryFs> {
+        self.0
+    }
+}
@@ -0,0 +1,352 @@
+mod common;
+
+use common::with_threads;
+use r_fast_copy::{
+    Compression, ConflictPolicy, CopyBuilder, CopyObserver, CopyReport, Copyer, EncryptionKey,
+    Error, EventWriter, Filter,
+};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// A fresh directory for one test, holding `src` with a small tree:
+// `a.txt`, `b.log`, `sub/c.txt`, `sub/deeper/d.txt`.
+fn fixture(name: &str) -> PathBuf {
+    let base = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = fs::remove_dir_all(&base);
+    let src = base.join("src");
+    fs::create_dir_all(src.join("sub/deeper")).unwrap();
+    fs::write(src.join("a.txt"), "a").unwrap();
+    fs::write(src.join("b.log"), "bb").unwrap();
+    fs::write(src.join("sub/c.txt"), "ccc").unwrap();
+    fs::write(src.join("sub/deeper/d.txt"), "dddd").unwrap();
+    base
+}
+
+fn builder(from: &Path, to: &Path, threads: usize) -> CopyBuilder {
+    let builder = Copyer::builder()
+        .add_from(from.to_str().unwrap())
+        .set_to(to.to_str().unwrap())
+        .set_quiet(true);
+    with_threads(builder, threads)
+}
+
+fn copy(from: &Path, to: &Path, threads: usize) -> Result<CopyReport, Error> {
+    builder(from, to, threads).build()?.run()
+}
+
+#[test]
+fn copies_tree_test() {
+    let base = fixture("copies_tree_test");
+    for threads in [0, 4] {
+        let out = base.join(format!("out_{}", threads));
+        let report = copy(&base.join("src"), &out, threads).unwrap();
+        assert_eq!((report.files, report.bytes, report.dirs), (4, 10, 2));
+        assert_eq!(report.errors, 0);
+        assert!(!report.cancelled && !report.dry_run);
+        assert_eq!(fs::read_to_string(out.join("sub/deeper/d.txt")).unwrap(), "dddd");
+    }
+}
+
+#[test]
+fn filter_and_conflict_test() {
+    let base = fixture("filter_and_conflict_test");
+    let (src, out) = (base.join("src"), base.join("out"));
+    let mut filter = Filter::new();
+    filter.exclude("*.log").unwrap();
+    let report = Copyer::builder()
+        .add_from(&format!("{}/", src.display()))
+        .set_to(out.to_str().unwrap())
+        .set_filter(filter)
+        .set_quiet(true)
+        .build()
+        .unwrap()
+        .run()
+        .unwrap();
+    assert_eq!((report.files, report.skipped), (3, 1));
+    assert!(out.join("a.txt").exists() && !out.join("b.log").exists());
+
+    fs::write(out.join("a.txt"), "changed").unwrap();
+    let report = Copyer::builder()
+        .add_from(&format!("{}/", src.display()))
+        .set_to(out.to_str().unwrap())
+        .set_conflict_policy(ConflictPolicy::Skip)
+        .set_threads_number(2)
+        .set_quiet(true)
+        .build()
+        .unwrap()
+        .run()
+        .unwrap();
+    assert_eq!((report.files, report.kept), (1, 3));
+    assert_eq!(fs::read_to_string(out.join("a.txt")).unwrap(), "changed");
+}
+
+#[test]
+fn dry_run_and_move_test() {
+    let base = fixture("dry_run_and_move_test");
+    let (src, out) = (base.join("src"), base.join("out"));
+    let builder = Copyer::builder()
+        .add_from(src.to_str().unwrap())
+        .set_to(out.to_str().unwrap())
+        .set_move(true)
+        .set_quiet(true);
+    let report = builder.clone().set_dry_run(true).build().unwrap().run().unwrap();
+    assert!(report.dry_run);
+    assert_eq!(report.moved, 1);
+    assert!(src.exists() && !out.exists());
+
+    let report = builder.build().unwrap().run().unwrap();
+    assert_eq!(report.moved, 1);
+    assert!(!src.exists());
+    assert_eq!(fs::read_to_string(out.join("sub/c.txt")).unwrap(), "ccc");
+}
+
+#[test]
+fn build_error_test() {
+    let base = fixture("build_error_test");
+    let src = base.join("src");
+    let no_to = Copyer::builder().add_from(src.to_str().unwrap()).build();
+    assert!(matches!(no_to, Err(Error::Config(_))));
+    let inside = copy(&src, &src.join("sub/inside"), 0);
+    assert!(matches!(inside, Err(Error::Config(_))));
+}
+
+#[test]
+fn compress_test() {
+    let base = fixture("compress_test");
+    let src = base.join("src");
+    for compression in [Compression::Zstd, Compression::Lz4] {
+        let packed = base.join(format!("packed_{}", compression));
+        let report = Copyer::builder()
+            .add_from(&format!("{}/", src.display()))
+            .set_to(packed.to_str().unwrap())
+            .set_compress(compression)
+            .set_verify(true)
+            .set_threads_number(4)
+            .set_quiet(true)
+            .build()
+            .unwrap()
+            .run()
+            .unwrap();
+        assert_eq!((report.files, report.bytes, report.errors), (4, 10, 0));
+        let d = packed.join(format!("sub/deeper/d.txt.{}", compression.extension()));
+        assert!(fs::metadata(&d).unwrap().len() > 4);
+        if compression == Compression::Zstd {
+            assert_eq!(zstd::decode_all(fs::File::open(&d).unwrap()).unwrap(), b"dddd");
+        }
+
+        // Back, with a file that was never compressed among them.
+        fs::write(packed.join("plain.txt"), "plain").unwrap();
+        let restored = base.join(format!("restored_{}", compression));
+        let report = Copyer::builder()
+            .add_from(&format!("{}/", packed.display()))
+            .set_to(restored.to_str().unwrap())
+            .set_decompress(true)
+            .set_verify(true)
+            .set_quiet(true)
+            .build()
+            .unwrap()
+            .run()
+            .unwrap();
+        assert_eq!((report.files, report.bytes, report.errors), (5, 15, 0));
+        assert_eq!(fs::read_to_string(restored.join("sub/deeper/d.txt")).unwrap(), "dddd");
+        assert_eq!(fs::read_to_string(restored.join("plain.txt")).unwrap(), "plain");
+    }
+
+    let both = Copyer::builder()
+        .add_from(src.to_str().unwrap())
+        .set_to(base.join("out").to_str().unwrap())
+        .set_compress(Compression::Lz4)
+        .set_decompress(true)
+        .build();
+    assert!(matches!(both, Err(Error::Config(_))));
+}
+
+// Every name below `dir`, and the data of the files.
+fn tree(dir: &Path, names: &mut Vec<String>, data: &mut Vec<Vec<u8>>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        names.push(path.file_name().unwrap().to_str().unwrap().to_owned());
+        if path.is_dir() {
+            tree(&path, names, data);
+        } else {
+            data.push(fs::read(&path).unwrap());
+        }
+    }
+}
+
+#[test]
+fn encrypt_test() {
+    let base = fixture("encrypt_test");
+    let src = base.join("src");
+    let key = EncryptionKey::from_passphrase("offsite");
+    for encrypt_names in [false, true] {
+        let sealed = base.join(format!("sealed_{}", encrypt_names));
+        let report = Copyer::builder()
+            .add_from(src.to_str().unwrap())
+            .set_to(&format!("{}/", sealed.display()))
+            .set_encrypt(key.clone())
+            .set_encrypt_names(encrypt_names)
+            .set_verify(true)
+            .set_threads_number(4)
+            .set_quiet(true)
+            .build()
+            .unwrap()
+            .run()
+            .unwrap();
+        assert_eq!((report.files, report.bytes, report.errors), (4, 10, 0));
+        let (mut names, mut data) = (vec![], vec![]);
+        tree(&sealed, &mut names, &mut data);
+        assert!(data.iter().all(|d| d.len() > 4 && !d.windows(4).any(|w| w == b"dddd")));
+        if encrypt_names {
+            // With the salt of the names at the top.
+            assert_eq!((names.len(), data.len()), (8, 5));
+            assert!(sealed.join(".rfc-salt").is_file());
+            let clear = ["src", "sub", "a.txt", "enc"];
+            assert!(names.iter().all(|n| !clear.iter().any(|c| n.contains(c))));
+
+            // A later run finds what this one encrypted, under the same names.
+            let again = Copyer::builder()
+                .add_from(src.to_str().unwrap())
+                .set_to(&format!("{}/", sealed.display()))
+                .set_encrypt(key.clone())
+                .set_encrypt_names(true)
+                .set_conflict_policy(ConflictPolicy::Skip)
+                .set_quiet(true)
+                .build()
+                .unwrap()
+                .run()
+                .unwrap();
+            assert_eq!((again.files, again.kept), (0, 4));
+        } else {
+            assert_eq!((names.len(), data.len()), (7, 4));
+            assert!(sealed.join("src/sub/deeper/d.txt.enc").is_file());
+        }
+
+        // Back, all four files only with the right key.
+        let decrypt = |key: EncryptionKey, to: &Path| {
+            Copyer::builder()
+                .add_from(&format!("{}/", sealed.display()))
+                .set_to(to.to_str().unwrap())
+                .set_decrypt(key)
+                .set_encrypt_names(encrypt_names)
+                .set_verify(true)
+                .set_threads_number(4)
+                .set_quiet(true)
+                .build()
+                .unwrap()
+                .run()
+                .unwrap()
+        };
+        let restored = base.join(format!("restored_{}", encrypt_names));
+        let report = decrypt(key.clone(), &restored);
+        assert_eq!((report.files, report.bytes, report.errors), (4, 10, 0));
+        assert_eq!(fs::read_to_string(restored.join("src/sub/deeper/d.txt")).unwrap(), "dddd");
+        assert_eq!(fs::read_to_string(restored.join("src/b.log")).unwrap(), "bb");
+        let wrong = base.join(format!("wrong_{}", encrypt_names));
+        let report = decrypt(EncryptionKey::from_passphrase("guessed"), &wrong);
+        assert!(report.files == 0 && report.errors > 0);
+        assert!(!wrong.join("src/a.txt").exists());
+    }
+
+    let out = base.join("out");
+    let builder = Copyer::builder()
+        .add_from(src.to_str().unwrap())
+        .set_to(out.to_str().unwrap());
+    let compressed = builder.clone().set_encrypt(key).set_compress(Compression::Zstd).build();
+    assert!(matches!(compressed, Err(Error::Config(_))));
+    let names_only = builder.set_encrypt_names(true).build();
+    assert!(matches!(names_only, Err(Error::Config(_))));
+}
+
+struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn events_test() {
+    let base = fixture("events_test");
+    let buf = Arc::new(Mutex::new(vec![]));
+    Copyer::builder()
+        .add_from(base.join("src").to_str().unwrap())
+        .set_to(base.join("out").to_str().unwrap())
+        .set_event_writer(EventWriter::new(Box::new(SharedBuf(buf.clone()))))
+        .set_threads_number(2)
+        .set_quiet(true)
+        .build()
+        .unwrap()
+        .run()
+        .unwrap();
+    let out = String::from_utf8(buf.lock().unwrap().clone()).unwrap();
+    let lines: Vec<&str> = out.lines().collect();
+    assert!(lines[0].starts_with(r#"{"event":"run_started""#));
+    assert!(lines.last().unwrap().starts_with(r#"{"event":"run_finished","files":4,"#));
+    let copied = lines.iter().filter(|l| l.contains(r#""event":"file_copied""#));
+    assert_eq!(copied.count(), 4);
+}
+
+// Records the hooks called, as `hook path`.
+#[derive(Default)]
+struct Recorder(Mutex<Vec<String>>);
+
+impl Recorder {
+    fn push(&self, hook: &str, path: &Path) {
+        self.0.lock().unwrap().push(format!("{} {}", hook, path.display()));
+    }
+
+    fn position(&self, hook: &str, path: &Path) -> usize {
+        let line = format!("{} {}", hook, path.display());
+        let lines = self.0.lock().unwrap();
+        lines.iter().position(|l| *l == line).unwrap()
+    }
+}
+
+impl CopyObserver for Recorder {
+    fn on_dir_start(&self, path: &Path) {
+        self.push("start", path);
+    }
+
+    fn on_file_done(&self, path: &Path, bytes: u64, _elapsed: Duration) {
+        assert_eq!(fs::metadata(path).unwrap().len(), bytes);
+        self.push("file", path);
+    }
+
+    fn on_dir_complete(&self, path: &Path) {
+        self.push("complete", path);
+    }
+}
+
+#[test]
+fn observer_test() {
+    let base = fixture("observer_test");
+    for threads in [0, 4] {
+        let out = base.join(format!("out_{}", threads));
+        let recorder = Arc::new(Recorder::default());
+        builder(&base.join("src"), &out, threads)
+            .add_observer(recorder.clone())
+            .build()
+            .unwrap()
+            .run()
+            .unwrap();
+
+        assert_eq!(recorder.0.lock().unwrap().len(), 3 + 4 + 3);
+        // Hooks get absolute paths.
+        let (sub, deeper) = (out.join("sub"), out.join("sub/deeper"));
+        let at = |hook, path: &Path| recorder.position(hook, &fs::canonicalize(path).unwrap());
+        assert!(at("start", &sub) < at("file", &sub.join("c.txt")));
+        assert!(at("file", &deeper.join("d.txt")) < at("complete", &deeper));
+        // Completion bubbles up: a directory is complete after everything below it.
+        assert!(at("complete", &deeper) < at("complete", &sub));
+        assert!(at("complete", &sub) < at("complete", &out));
+    }
+}
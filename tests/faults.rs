@@ -0,0 +1,244 @@
+mod common;
+
+use common::faulty::{Fault, FaultyFs, Op};
+use common::{with_threads, MemoryTree};
+use r_fast_copy::{
+    AttrFilter, CancelToken, ConflictPolicy, CopyBuilder, CopyReport, Copyer, Error, FileSystem,
+    MemoryFs, SyncPolicy,
+};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+
+const DIRS: usize = 8;
+const FILES: usize = 10;
+
+// `/src/d0` to `/src/d7`, each holding `f0.txt` to `f9.txt`, seen through `faults`.
+fn faulty_fixture(faults: Vec<Arc<Fault>>) -> (Arc<MemoryFs>, Arc<FaultyFs>) {
+    let mut tree = MemoryTree::new();
+    for d in 0..DIRS {
+        for f in 0..FILES {
+            let path = format!("/src/d{}/f{}.txt", d, f);
+            tree = tree.file(&path, path.as_bytes());
+        }
+    }
+    let memory = tree.build();
+    let faulty = Arc::new(FaultyFs::new(memory.clone(), faults));
+    (memory, faulty)
+}
+
+fn builder(fs: Arc<FaultyFs>, threads: usize) -> CopyBuilder {
+    let builder = Copyer::builder()
+        .add_from("/src/")
+        .set_to("/out")
+        .set_file_system(fs)
+        .set_quiet(true);
+    with_threads(builder, threads)
+}
+
+// Run the copy, failing the test instead of hanging if it never returns.
+fn run_within(builder: CopyBuilder) -> Result<CopyReport, Error> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = tx.send(builder.build().and_then(|copyer| copyer.run()));
+    });
+    rx.recv_timeout(Duration::from_secs(20))
+        .expect("the copy hung")
+}
+
+// Every file below `dir`, temporary ones included.
+fn files(fs: &MemoryFs, dir: &Path) -> Vec<PathBuf> {
+    let mut files = vec![];
+    for entry in fs.read_dir(dir).unwrap() {
+        let entry = entry.unwrap();
+        let path = dir.join(&entry.name);
+        if entry.file_type.is_dir() {
+            files.extend(self::files(fs, &path));
+        } else {
+            files.push(path);
+        }
+    }
+    files
+}
+
+fn assert_no_temp_files(fs: &MemoryFs) {
+    let temp = files(fs, Path::new("/out"))
+        .into_iter()
+        .find(|p| p.to_string_lossy().ends_with(".rfc-tmp"));
+    assert_eq!(temp, None);
+}
+
+#[test]
+fn read_dir_denied_test() {
+    for threads in [0, 4] {
+        let fault = Arc::new(Fault::new(Op::ReadDir).on("src/d3").error(libc::EACCES));
+        let (memory, fs) = faulty_fixture(vec![fault.clone()]);
+        let report = run_within(builder(fs, threads)).unwrap();
+        assert_eq!(fault.calls(), 1);
+        assert_eq!(report.errors, 1);
+        assert_eq!((report.files, report.dirs), (70, 8));
+        assert!(memory.exists(Path::new("/out/d3")));
+        assert_eq!(files(&memory, Path::new("/out")).len(), 70);
+    }
+}
+
+#[test]
+fn nth_read_fails_test() {
+    for threads in [0, 4] {
+        let fault = Arc::new(Fault::new(Op::Read).nth(25).error(libc::EIO));
+        let (memory, fs) = faulty_fixture(vec![fault.clone()]);
+        let report = run_within(builder(fs, threads)).unwrap();
+        assert!(fault.calls() > 25);
+        assert_eq!((report.files, report.errors), (79, 1));
+        assert!(!report.cancelled);
+        assert_no_temp_files(&memory);
+    }
+}
+
+#[test]
+fn panic_in_task_test() {
+    // A single worker has to outlive the panic to copy the rest.
+    for threads in [1, 4] {
+        let fault = Arc::new(Fault::new(Op::ReadDir).on("src/d3").panics());
+        let (memory, fs) = faulty_fixture(vec![fault]);
+        let report = run_within(builder(fs, threads)).unwrap();
+        assert_eq!((report.files, report.errors), (70, 1));
+        assert_eq!(files(&memory, Path::new("/out")).len(), 70);
+    }
+}
+
+#[test]
+fn storage_full_test() {
+    let fault = Arc::new(Fault::new(Op::Write).nth(10).error(libc::ENOSPC));
+    let (memory, fs) = faulty_fixture(vec![fault]);
+    let cancel = CancelToken::new();
+    let copy = builder(fs, 4).set_cancel_token(cancel.clone());
+    match run_within(copy) {
+        Err(Error::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::StorageFull),
+        other => panic!("expected running out of space, got {:?}", other),
+    }
+    // The rest of the run was called off.
+    assert!(cancel.is_cancelled());
+    assert!(files(&memory, Path::new("/out")).len() < DIRS * FILES);
+    assert_no_temp_files(&memory);
+}
+
+#[test]
+fn sync_fails_test() {
+    // A file that can't be flushed fails on its own, and isn't left behind.
+    let fault = Arc::new(Fault::new(Op::Sync).nth(5).error(libc::EIO));
+    let (memory, fs) = faulty_fixture(vec![fault.clone()]);
+    let report = run_within(builder(fs, 4).set_sync_policy(SyncPolicy::File)).unwrap();
+    assert_eq!(fault.calls(), 80);
+    assert_eq!((report.files, report.errors), (79, 1));
+    assert_no_temp_files(&memory);
+
+    // Nothing is known to be on disk if the destination, or its filesystem, can't be synced.
+    let syncs = [(Op::SyncDir, SyncPolicy::Dir), (Op::SyncFs, SyncPolicy::End)];
+    for ((op, sync), threads) in syncs.into_iter().flat_map(|s| [(s, 0), (s, 4)]) {
+        let fault = Arc::new(Fault::new(op).on("/out").error(libc::EIO));
+        let (memory, fs) = faulty_fixture(vec![fault.clone()]);
+        match run_within(builder(fs, threads).set_sync_policy(sync)) {
+            Err(Error::Io(e)) => assert!(e.to_string().starts_with("/out: "), "{}", e),
+            other => panic!("expected the {:?} to fail, got {:?}", op, other),
+        }
+        assert_eq!(fault.calls(), 1);
+        assert_eq!(files(&memory, Path::new("/out")).len(), DIRS * FILES);
+    }
+}
+
+#[test]
+fn slow_io_test() {
+    let slow = || Arc::new(Fault::new(Op::Read).delay(Duration::from_millis(2)));
+    let (memory, fs) = faulty_fixture(vec![slow()]);
+    let report = run_within(builder(fs, 4)).unwrap();
+    assert_eq!((report.files, report.errors), (80, 0));
+    assert_eq!(
+        memory.read_file(Path::new("/out/d7/f9.txt")).unwrap(),
+        b"/src/d7/f9.txt"
+    );
+
+    // Cancelled while reads are slow, the run still winds down.
+    let very_slow = Arc::new(Fault::new(Op::Read).delay(Duration::from_millis(20)));
+    let (memory, fs) = faulty_fixture(vec![very_slow]);
+    let cancel = CancelToken::new();
+    let copy = builder(fs, 4).set_cancel_token(cancel.clone());
+    let canceller = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        cancel.cancel();
+    });
+    let report = run_within(copy).unwrap();
+    canceller.join().unwrap();
+    assert!(report.cancelled);
+    assert!(report.files < (DIRS * FILES) as u64);
+    assert_no_temp_files(&memory);
+}
+
+#[test]
+fn stat_once_test() {
+    for threads in [0, 4] {
+        let stat = Arc::new(Fault::new(Op::Stat).on("src/d0/f1.txt"));
+        let (memory, fs) = faulty_fixture(vec![stat.clone()]);
+        let older = Path::new("/out/d0/f1.txt");
+        memory.write_file(older, b"old").unwrap();
+        memory.set_modified(older, UNIX_EPOCH).unwrap();
+        // Filtered by size, copied over an older file and moved to another filesystem, the
+        // source still is stat'ed once.
+        let copy = builder(fs.clone(), threads)
+            .set_file_system(memory.clone())
+            .set_source_file_system(fs)
+            .set_attr_filter(AttrFilter::new().set_min_size(1))
+            .set_conflict_policy(ConflictPolicy::Newer)
+            .set_move(true);
+        let report = run_within(copy).unwrap();
+        assert_eq!((report.files, report.errors), (80, 0));
+        assert_eq!(memory.read_file(older).unwrap(), b"/src/d0/f1.txt");
+        assert_eq!(stat.calls(), 1, "with {} threads", threads);
+    }
+}
+
+#[test]
+fn tar_storage_full_test() {
+    let fault = Arc::new(Fault::new(Op::Write).on(".out.tar.rfc-tmp").error(libc::ENOSPC));
+    let (memory, fs) = faulty_fixture(vec![fault.clone()]);
+    let tar = Copyer::builder()
+        .add_from("/src/")
+        .set_to_tar("/out.tar")
+        .set_file_system(fs)
+        .set_threads_number(4)
+        .set_quiet(true);
+    match run_within(tar) {
+        Err(Error::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::StorageFull),
+        other => panic!("expected running out of space, got {:?}", other),
+    }
+    assert!(fault.calls() > 0);
+    // Neither a broken archive nor its temporary file is left.
+    assert!(!memory.exists(Path::new("/out.tar")));
+    assert!(!memory.exists(Path::new("/.out.tar.rfc-tmp")));
+}
+
+#[test]
+fn failed_entry_exit_test() {
+    let base = Path::new(env!("CARGO_TARGET_TMPDIR")).join("failed_entry_exit_test");
+    let _ = fs::remove_dir_all(&base);
+    let src = base.join("src");
+    fs::create_dir_all(&src).unwrap();
+    fs::write(src.join("a.txt"), "a").unwrap();
+    std::os::unix::fs::symlink("nowhere", src.join("broken")).unwrap();
+    // The other file is copied, but the run fails however it runs.
+    for (i, args) in [&["-t", "4"][..], &["-s"], &["--output", "json"]].iter().enumerate() {
+        let out = base.join(format!("out{}", i));
+        let run = Command::new(env!("CARGO_BIN_EXE_r-fast-copy"))
+            .arg(&src)
+            .arg(&out)
+            .args(*args)
+            .output()
+            .unwrap();
+        assert_eq!(run.status.code(), Some(1), "{:?}", args);
+        assert_eq!(fs::read(out.join("a.txt")).unwrap(), b"a");
+    }
+}
@@ -0,0 +1,224 @@
+mod common;
+
+use common::{with_threads, MemoryTree};
+use r_fast_copy::{BackupMode, ConflictPolicy, Copyer, FileSystem, Filter, MemoryFs, SyncPolicy};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+fn exclude(pattern: &str) -> Filter {
+    let mut filter = Filter::new();
+    filter.exclude(pattern).unwrap();
+    filter
+}
+
+// `/src` with `a.txt`, `b.log`, `sub/c.txt`, `sub/deeper/d.txt` and `link` pointing to `a.txt`.
+fn memory_fixture() -> Arc<MemoryFs> {
+    MemoryTree::new()
+        .file("/src/a.txt", b"a")
+        .file("/src/b.log", b"bb")
+        .file("/src/sub/c.txt", b"ccc")
+        .file("/src/sub/deeper/d.txt", b"dddd")
+        .symlink("a.txt", "/src/link")
+        .build()
+}
+
+#[test]
+fn memory_copy_test() {
+    let fs = memory_fixture();
+    for threads in [0, 4] {
+        let out = format!("/out_{}", threads);
+        let builder = Copyer::builder()
+            .add_from("/src/")
+            .set_to(&out)
+            .set_file_system(fs.clone())
+            .set_verify(true)
+            .set_quiet(true);
+        let report = with_threads(builder, threads).build().unwrap().run().unwrap();
+        assert_eq!((report.files, report.bytes, report.dirs), (5, 11, 2));
+        assert_eq!(report.errors, 0);
+        let out = PathBuf::from(out);
+        assert_eq!(fs.read_file(&out.join("sub/deeper/d.txt")).unwrap(), b"dddd");
+        // Copies follow symlinks.
+        let link = fs.symlink_metadata(&out.join("link")).unwrap();
+        assert!(link.file_type.is_file());
+    }
+}
+
+#[test]
+fn memory_conflict_test() {
+    let fs = memory_fixture();
+    fs.write_file(Path::new("/out/a.txt"), b"old").unwrap();
+    fs.write_file(Path::new("/out/b.log"), b"old").unwrap();
+    let report = Copyer::builder()
+        .add_from("/src/")
+        .set_to("/out")
+        .set_file_system(fs.clone())
+        .set_filter(exclude("sub"))
+        .set_conflict_policy(ConflictPolicy::Rename)
+        .set_quiet(true)
+        .build()
+        .unwrap()
+        .run()
+        .unwrap();
+    assert_eq!(report.renamed, 2);
+    assert_eq!(fs.read_file(Path::new("/out/a.txt")).unwrap(), b"old");
+    assert_eq!(fs.read_file(Path::new("/out/a (1).txt")).unwrap(), b"a");
+    assert!(!fs.exists(Path::new("/out/sub")));
+
+    let report = Copyer::builder()
+        .add_from("/src/a.txt")
+        .set_to("/out")
+        .set_file_system(fs.clone())
+        .set_backup_mode(BackupMode::Numbered)
+        .set_quiet(true)
+        .build()
+        .unwrap()
+        .run()
+        .unwrap();
+    assert_eq!((report.overwritten, report.backups), (1, 1));
+    assert_eq!(fs.read_file(Path::new("/out/a.txt.~1~")).unwrap(), b"old");
+}
+
+#[test]
+fn memory_move_test() {
+    let fs = memory_fixture();
+    fs.create_dir_all(Path::new("/out")).unwrap();
+    // The filter rules out renaming the whole source, entries are moved one by one.
+    let report = Copyer::builder()
+        .add_from("/src")
+        .set_to("/out")
+        .set_file_system(fs.clone())
+        .set_filter(exclude("*.log"))
+        .set_move(true)
+        .set_quiet(true)
+        .build()
+        .unwrap()
+        .run()
+        .unwrap();
+    assert_eq!((report.files, report.moved), (4, 0));
+    assert_eq!(fs.read_file(Path::new("/out/src/sub/c.txt")).unwrap(), b"ccc");
+    // Moves keep symlinks.
+    let link = fs.symlink_metadata(Path::new("/out/src/link")).unwrap();
+    assert!(link.file_type.is_symlink());
+    assert!(!fs.exists(Path::new("/src/sub")));
+    assert!(fs.exists(Path::new("/src/b.log")));
+
+    let report = Copyer::builder()
+        .add_from("/src")
+        .set_to("/moved")
+        .set_file_system(fs.clone())
+        .set_move(true)
+        .set_quiet(true)
+        .build()
+        .unwrap()
+        .run()
+        .unwrap();
+    assert_eq!(report.moved, 1);
+    assert!(!fs.exists(Path::new("/src")));
+    assert_eq!(fs.read_file(Path::new("/moved/b.log")).unwrap(), b"bb");
+}
+
+#[test]
+fn memory_gitignore_test() {
+    let fs = memory_fixture();
+    fs.write_file(Path::new("/src/.gitignore"), b"*.log\n").unwrap();
+    fs.write_file(Path::new("/src/sub/.ignore"), b"deeper/\n").unwrap();
+    for threads in [0, 4] {
+        let out = format!("/out_{}", threads);
+        let builder = Copyer::builder()
+            .add_from("/src/")
+            .set_to(&out)
+            .set_file_system(fs.clone())
+            .set_respect_gitignore(true)
+            .set_quiet(true);
+        let report = with_threads(builder, threads).build().unwrap().run().unwrap();
+        // The ignore files are in the filesystem given, not on the local disk.
+        assert_eq!((report.files, report.skipped), (5, 2));
+        let out = PathBuf::from(out);
+        assert!(fs.exists(&out.join("sub/c.txt")));
+        assert!(!fs.exists(&out.join("b.log")));
+        assert!(!fs.exists(&out.join("sub/deeper")));
+    }
+}
+
+#[test]
+fn memory_gitignore_subdir_test() {
+    let mut tree = MemoryTree::new()
+        .file("/repo/.git/info/exclude", b"*.tmp\n")
+        .file("/repo/.gitignore", b"*.log\nbuild/\n")
+        .file("/repo/sub/.gitignore", b"!keep.log\n");
+    for file in ["a.txt", "b.log", "keep.log", "c.tmp", "build/x.o", "deep/build/y.o"] {
+        tree = tree.file(&format!("/repo/sub/{}", file), b"x");
+    }
+    let fs = tree.build();
+    // Copying part of a repository still applies the rules above it.
+    let report = Copyer::builder()
+        .add_from("/repo/sub/")
+        .set_to("/out")
+        .set_file_system(fs.clone())
+        .set_respect_gitignore(true)
+        .set_quiet(true)
+        .build()
+        .unwrap()
+        .run()
+        .unwrap();
+    assert_eq!((report.files, report.skipped), (3, 4));
+    assert!(fs.exists(Path::new("/out/a.txt")));
+    assert!(fs.exists(Path::new("/out/keep.log")));
+    assert!(!fs.exists(Path::new("/out/b.log")));
+    assert!(!fs.exists(Path::new("/out/c.tmp")));
+    assert!(!fs.exists(Path::new("/out/build")));
+    assert!(!fs.exists(Path::new("/out/deep/build")));
+}
+
+#[test]
+fn memory_source_test() {
+    let out = Path::new(env!("CARGO_TARGET_TMPDIR")).join("memory_source_test");
+    let _ = fs::remove_dir_all(&out);
+    let report = Copyer::builder()
+        .add_from("/src/")
+        .set_to(out.to_str().unwrap())
+        .set_source_file_system(memory_fixture())
+        .set_move(true)
+        .set_quiet(true)
+        .build()
+        .unwrap()
+        .run()
+        .unwrap();
+    // Between two filesystems a move copies.
+    assert_eq!((report.files, report.moved), (5, 0));
+    assert_eq!(fs::read_to_string(out.join("sub/c.txt")).unwrap(), "ccc");
+}
+
+#[test]
+fn move_between_filesystems_test() {
+    let out = Path::new(env!("CARGO_TARGET_TMPDIR")).join("move_between_filesystems_test");
+    let run = Path::new("/src/run.sh");
+    let old = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+    for sync in [SyncPolicy::None, SyncPolicy::File] {
+        let _ = fs::remove_dir_all(&out);
+        let source = MemoryTree::new().file("/src/run.sh", b"#!/bin/sh\n").build();
+        source.set_permissions(run, 0o755).unwrap();
+        source.set_modified(run, old).unwrap();
+        let report = Copyer::builder()
+            .add_from("/src/")
+            .set_to(out.to_str().unwrap())
+            .set_source_file_system(source.clone())
+            .set_move(true)
+            .set_sync_policy(sync)
+            .set_quiet(true)
+            .build()
+            .unwrap()
+            .run()
+            .unwrap();
+        assert_eq!((report.files, report.errors), (1, 0));
+        assert!(!source.exists(run));
+        // The copy is all that is left, so it keeps the mode and time of the source.
+        let moved = fs::metadata(out.join("run.sh")).unwrap();
+        assert_eq!(moved.permissions().mode() & 0o7777, 0o755);
+        assert_eq!(moved.modified().unwrap(), old);
+    }
+}
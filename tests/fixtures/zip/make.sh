@@ -0,0 +1,47 @@
+#!/bin/sh
+# Regenerates the zip archives of other tools that tests/zip.rs extracts. Needs Info-ZIP's zip,
+# libarchive's bsdtar and python3.
+set -e
+cd "$(dirname "$0")"
+work=$(mktemp -d)
+trap 'rm -rf "$work"' EXIT
+mkdir -p "$work/tree/sub"
+python3 -c "open('$work/tree/hello.txt', 'w').write('hello zip\n' * 200)"
+: > "$work/tree/sub/empty"
+printf '#!/bin/sh\n' > "$work/tree/sub/run.sh"
+chmod 750 "$work/tree/sub/run.sh"
+ln -s hello.txt "$work/tree/link"
+(cd "$work" && touch -h -d '2020-02-03 04:05:06 UTC' tree/* tree/sub/* tree/sub tree)
+here=$(pwd)
+rm -f ./*.zip
+cd "$work"
+# Info-ZIP: plain, forced ZIP64, and streamed from a pipe, which gives a ZIP64 entry named `-`
+# with a data descriptor.
+zip -qry "$here/infozip.zip" tree
+zip -qry -fz "$here/infozip-zip64.zip" tree
+cat tree/hello.txt | zip -q - - | cat > "$here/infozip-stream.zip"
+# libarchive: data descriptors on deflated files, with and without ZIP64.
+bsdtar --format zip -cf "$here/libarchive.zip" tree
+bsdtar --format zip --options zip:zip64 -cf "$here/libarchive-zip64.zip" tree
+# Python, written to a pipe: ZIP64 data descriptors. Symlinks are left out.
+TZ=UTC python3 - "$here/python-zip64-dd.zip" <<'PY'
+import io, os, sys, zipfile
+
+class Pipe(io.RawIOBase):
+    def __init__(self, f):
+        self.f = f
+    def writable(self):
+        return True
+    def write(self, b):
+        return self.f.write(b)
+
+with open(sys.argv[1], 'wb') as raw, zipfile.ZipFile(Pipe(raw), 'w') as archive:
+    for path in ['tree', 'tree/hello.txt', 'tree/sub', 'tree/sub/empty', 'tree/sub/run.sh']:
+        info = zipfile.ZipInfo.from_file(path)
+        if info.is_dir():
+            archive.write(path)
+            continue
+        info.compress_type = zipfile.ZIP_DEFLATED
+        with archive.open(info, 'w', force_zip64=True) as entry, open(path, 'rb') as f:
+            entry.write(f.read())
+PY
@@ -0,0 +1,56 @@
+use futures::executor::block_on;
+use futures::StreamExt;
+use r_fast_copy::{Copyer, Event};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+fn fixture(name: &str) -> PathBuf {
+    let base = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = fs::remove_dir_all(&base);
+    fs::create_dir_all(base.join("src/sub")).unwrap();
+    for i in 0..20 {
+        fs::write(base.join(format!("src/sub/f{}", i)), "data").unwrap();
+    }
+    base
+}
+
+#[test]
+fn run_async_test() {
+    let base = fixture("run_async_test");
+    let copyer = Copyer::builder()
+        .add_from(base.join("src").to_str().unwrap())
+        .set_to(base.join("out").to_str().unwrap())
+        .set_threads_number(4)
+        .set_quiet(true)
+        .build()
+        .unwrap();
+    let events = copyer.event_stream();
+    // A second stream gets nothing.
+    let late = copyer.event_stream();
+    let task = copyer.run_async();
+
+    let (events, report) = block_on(async { futures::join!(events.collect::<Vec<_>>(), task) });
+    let report = report.unwrap();
+    assert_eq!(report.files, 20);
+    assert!(matches!(events.first(), Some(Event::RunStarted { .. })));
+    assert!(matches!(events.last(), Some(Event::RunFinished { files: 20, .. })));
+    let copied = events.iter().filter(|e| matches!(e, Event::FileCopied { .. }));
+    assert_eq!(copied.count(), 20);
+    assert_eq!(block_on(late.count()), 0);
+}
+
+#[test]
+fn run_async_error_test() {
+    let base = fixture("run_async_error_test");
+    fs::create_dir_all(base.join("out/src/sub")).unwrap();
+    fs::write(base.join("out/src/sub/f0"), "old").unwrap();
+    let task = Copyer::builder()
+        .add_from(base.join("src").to_str().unwrap())
+        .set_to(base.join("out").to_str().unwrap())
+        .set_conflict_policy(r_fast_copy::ConflictPolicy::Fail)
+        .set_quiet(true)
+        .build()
+        .unwrap()
+        .run_async();
+    assert!(block_on(task).is_err());
+}
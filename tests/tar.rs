@@ -0,0 +1,299 @@
+mod common;
+
+use common::{with_threads, MemoryTree};
+use r_fast_copy::{Copyer, Error, FileSystem, Filter, MemoryFs};
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+// `/src` with a file carrying an xattr, a symlink, a hard link and a path too long for ustar.
+fn tar_fixture() -> (Arc<MemoryFs>, String) {
+    let long = format!("sub/{}/{}.txt", "d".repeat(120), "f".repeat(150));
+    let fs = MemoryTree::new()
+        .file("/src/a.txt", b"a")
+        .file("/src/sub/b.txt", b"bb")
+        .symlink("a.txt", "/src/link")
+        .hard_link("/src/a.txt", "/src/sub/hard.txt")
+        .file(&format!("/src/{}", long), b"deep")
+        .build();
+    fs.set_xattr(Path::new("/src/a.txt"), OsStr::new("user.note"), b"kept").unwrap();
+    (fs, long)
+}
+
+// Path, type, link target and data of every entry.
+fn entries(archive: &[u8]) -> Vec<(String, tar::EntryType, Option<String>, Vec<u8>)> {
+    let mut archive = tar::Archive::new(archive);
+    archive
+        .entries()
+        .unwrap()
+        .map(|entry| {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_string_lossy().into_owned();
+            let link = entry
+                .link_name()
+                .unwrap()
+                .map(|l| l.to_string_lossy().into_owned());
+            let entry_type = entry.header().entry_type();
+            let mut data = vec![];
+            entry.read_to_end(&mut data).unwrap();
+            (path, entry_type, link, data)
+        })
+        .collect()
+}
+
+#[test]
+fn to_tar_test() {
+    let (fs, long) = tar_fixture();
+    let mut archives = vec![];
+    for threads in [0, 4] {
+        let builder = Copyer::builder()
+            .add_from("/src")
+            .set_to_tar("/out/src.tar")
+            .set_file_system(fs.clone())
+            .set_quiet(true);
+        let report = with_threads(builder, threads).build().unwrap().run().unwrap();
+        assert_eq!((report.files, report.dirs, report.errors), (5, 3, 0));
+        archives.push(fs.read_file(Path::new("/out/src.tar")).unwrap());
+    }
+    // Read on the pool or not, entries come in the same order.
+    assert_eq!(archives[0], archives[1]);
+
+    let entries = entries(&archives[0]);
+    let names: Vec<&str> = entries.iter().map(|e| e.0.as_str()).collect();
+    let long_dir = format!("src/{}/", Path::new(&long).parent().unwrap().display());
+    let long = format!("src/{}", long);
+    assert_eq!(
+        names,
+        vec![
+            "src/",
+            "src/a.txt",
+            "src/link",
+            "src/sub/",
+            "src/sub/b.txt",
+            &long_dir,
+            &long,
+            "src/sub/hard.txt",
+        ]
+    );
+    use tar::EntryType::*;
+    let kinds: Vec<_> = entries.iter().map(|e| (e.1, e.2.as_deref())).collect();
+    assert_eq!(kinds[2], (Symlink, Some("a.txt")));
+    assert_eq!(kinds[7], (Link, Some("src/a.txt")));
+    assert_eq!(entries[6].3, b"deep");
+
+    let mut archive = tar::Archive::new(&archives[0][..]);
+    let mut first = archive.entries().unwrap().nth(1).unwrap().unwrap();
+    let pax: Vec<_> = first
+        .pax_extensions()
+        .unwrap()
+        .unwrap()
+        .map(|e| e.unwrap())
+        .map(|e| (e.key().unwrap().to_string(), e.value_bytes().to_vec()))
+        .collect();
+    assert_eq!(pax, vec![(String::from("SCHILY.xattr.user.note"), b"kept".to_vec())]);
+    assert!(!fs.exists(Path::new("/out/.src.tar.rfc-tmp")));
+}
+
+#[test]
+fn to_tar_contents_test() {
+    let (fs, _) = tar_fixture();
+    let mut filter = Filter::new();
+    filter.exclude("sub").unwrap();
+    // The archive is written among the sources, it isn't archived itself.
+    let report = Copyer::builder()
+        .add_from("/src/")
+        .set_to_tar("/src/self.tar")
+        .set_file_system(fs.clone())
+        .set_filter(filter)
+        .set_threads_number(2)
+        .set_quiet(true)
+        .build()
+        .unwrap()
+        .run()
+        .unwrap();
+    assert_eq!((report.files, report.skipped), (2, 2));
+    let archive = fs.read_file(Path::new("/src/self.tar")).unwrap();
+    let names: Vec<String> = entries(&archive).into_iter().map(|e| e.0).collect();
+    assert_eq!(names, vec!["a.txt", "link"]);
+
+    let moving = Copyer::builder()
+        .add_from("/src")
+        .set_to_tar("/out.tar")
+        .set_file_system(fs)
+        .set_move(true)
+        .build();
+    assert!(matches!(moving, Err(Error::Config(_))));
+}
+
+#[test]
+fn from_tar_test() {
+    let (fs, long) = tar_fixture();
+    let modified = UNIX_EPOCH + Duration::from_secs(1_000_000);
+    fs.set_modified(Path::new("/src/a.txt"), modified).unwrap();
+    fs.set_permissions(Path::new("/src/sub/b.txt"), 0o600).unwrap();
+    Copyer::builder()
+        .add_from("/src")
+        .set_to_tar("/src.tar")
+        .set_file_system(fs.clone())
+        .set_quiet(true)
+        .build()
+        .unwrap()
+        .run()
+        .unwrap();
+
+    for threads in [0, 4] {
+        let to = format!("/restored{}", threads);
+        let builder = Copyer::builder()
+            .set_from_tar("/src.tar")
+            .set_to(&to)
+            .set_file_system(fs.clone())
+            .set_quiet(true);
+        let report = with_threads(builder, threads).build().unwrap().run().unwrap();
+        assert_eq!((report.files, report.dirs, report.errors), (5, 3, 0));
+
+        let root = Path::new(&to).join("src");
+        assert_eq!(fs.read_file(&root.join("sub/b.txt")).unwrap(), b"bb");
+        assert_eq!(fs.read_file(&root.join(&long)).unwrap(), b"deep");
+        assert_eq!(fs.read_link(&root.join("link")).unwrap(), Path::new("a.txt"));
+        let a = fs.symlink_metadata(&root.join("a.txt")).unwrap();
+        let hard = fs.symlink_metadata(&root.join("sub/hard.txt")).unwrap();
+        assert_eq!((a.ino, a.nlink), (hard.ino, 2));
+        assert_eq!(a.modified, Some(modified));
+        let xattrs = fs.xattrs(&root.join("a.txt")).unwrap();
+        assert_eq!(xattrs, vec![(OsString::from("user.note"), b"kept".to_vec())]);
+        let b = fs.symlink_metadata(&root.join("sub/b.txt")).unwrap();
+        assert_eq!(b.mode & 0o777, 0o600);
+        assert!(!fs.exists(&root.join(".a.txt.rfc-tmp")));
+    }
+
+    let with_sources = Copyer::builder()
+        .add_from("/src")
+        .set_from_tar("/src.tar")
+        .set_to("/restored")
+        .set_file_system(fs)
+        .build();
+    assert!(matches!(with_sources, Err(Error::Config(_))));
+}
+
+#[test]
+fn from_tar_special_bits_test() {
+    let mut builder = tar::Builder::new(vec![]);
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Directory);
+    header.set_mode(0o1777);
+    header.set_size(0);
+    builder.append_data(&mut header, "shared", &b""[..]).unwrap();
+    let mut header = tar::Header::new_gnu();
+    header.set_mode(0o4755);
+    header.set_size(4);
+    builder.append_data(&mut header, "shared/su", &b"evil"[..]).unwrap();
+    let fs = MemoryTree::new()
+        .file("/special.tar", &builder.into_inner().unwrap())
+        .build();
+
+    // Only kept when asked for.
+    for (keep, dir_mode, file_mode) in [(false, 0o777, 0o755), (true, 0o1777, 0o4755)] {
+        let to = format!("/restored_{}", keep);
+        let report = Copyer::builder()
+            .set_from_tar("/special.tar")
+            .set_to(&to)
+            .set_file_system(fs.clone())
+            .set_keep_special_bits(keep)
+            .set_quiet(true)
+            .build()
+            .unwrap()
+            .run()
+            .unwrap();
+        assert_eq!((report.files, report.errors), (1, 0));
+        let dir = fs.symlink_metadata(&Path::new(&to).join("shared")).unwrap();
+        let file = fs.symlink_metadata(&Path::new(&to).join("shared/su")).unwrap();
+        assert_eq!((dir.mode & 0o7777, file.mode & 0o7777), (dir_mode, file_mode));
+    }
+}
+
+#[test]
+fn from_tar_duplicate_test() {
+    // As `tar -r` leaves it: one path many times, big enough once to be written while read.
+    let mut versions: Vec<Vec<u8>> =
+        (0..40u8).map(|i| vec![b'a' + i % 26; 1000 + i as usize]).collect();
+    versions.insert(20, vec![b'B'; 2 * 1024 * 1024]);
+    versions.push(b"last".to_vec());
+    let mut builder = tar::Builder::new(vec![]);
+    for data in &versions {
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(0o644);
+        header.set_size(data.len() as u64);
+        builder.append_data(&mut header, "dup.txt", &data[..]).unwrap();
+    }
+    let fs = MemoryTree::new().file("/dup.tar", &builder.into_inner().unwrap()).build();
+
+    for threads in [0, 4] {
+        let to = format!("/restored{}", threads);
+        let builder = Copyer::builder()
+            .set_from_tar("/dup.tar")
+            .set_to(&to)
+            .set_file_system(fs.clone())
+            .set_quiet(true);
+        let report = with_threads(builder, threads).build().unwrap().run().unwrap();
+        assert_eq!((report.files, report.overwritten, report.errors), (42, 41, 0));
+        // The last one wins, whole.
+        assert_eq!(fs.read_file(&Path::new(&to).join("dup.txt")).unwrap(), b"last");
+        assert!(!fs.exists(&Path::new(&to).join(".dup.txt.rfc-tmp")));
+    }
+}
+
+// A tar entry named `path`, which the tar builder would refuse to write.
+fn raw_entry(builder: &mut tar::Builder<Vec<u8>>, path: &str, kind: tar::EntryType, link: &str) {
+    let data = if kind == tar::EntryType::Regular { &b"evil"[..] } else { &b""[..] };
+    let mut header = tar::Header::new_old();
+    header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+    header.as_old_mut().linkname[..link.len()].copy_from_slice(link.as_bytes());
+    header.set_entry_type(kind);
+    header.set_mode(0o644);
+    header.set_size(data.len() as u64);
+    header.set_cksum();
+    builder.append(&header, data).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn from_tar_outside_test() {
+    use tar::EntryType::*;
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("from_tar_outside");
+    let _ = fs::remove_dir_all(&dir);
+    let (dest, outside) = (dir.join("dest"), dir.join("outside"));
+    fs::create_dir_all(&outside).unwrap();
+    fs::create_dir_all(&dest).unwrap();
+    std::os::unix::fs::symlink(&outside, dest.join("pre")).unwrap();
+
+    let mut builder = tar::Builder::new(vec![]);
+    raw_entry(&mut builder, "../evil.txt", Regular, "");
+    raw_entry(&mut builder, &format!("{}/abs.txt", outside.display()), Regular, "");
+    raw_entry(&mut builder, "pre/through.txt", Regular, "");
+    raw_entry(&mut builder, "link", Symlink, outside.to_str().unwrap());
+    raw_entry(&mut builder, "link/later.txt", Regular, "");
+    raw_entry(&mut builder, "hard", Link, "../../outside/x");
+    raw_entry(&mut builder, "ok.txt", Regular, "");
+    let archive = dir.join("evil.tar");
+    fs::write(&archive, builder.into_inner().unwrap()).unwrap();
+
+    let report = Copyer::builder()
+        .set_from_tar(archive.to_str().unwrap())
+        .set_to(dest.to_str().unwrap())
+        .set_threads_number(2)
+        .set_quiet(true)
+        .build()
+        .unwrap()
+        .run()
+        .unwrap();
+    assert_eq!(fs::read_dir(&outside).unwrap().count(), 0);
+    assert!(!dir.join("evil.txt").exists());
+    // `link/later.txt` went into a real `link` directory, which the symlink then can't replace.
+    assert_eq!(fs::read(dest.join("link/later.txt")).unwrap(), b"evil");
+    assert!(fs::symlink_metadata(dest.join("link")).unwrap().is_dir());
+    assert_eq!(fs::read(dest.join("ok.txt")).unwrap(), b"evil");
+    assert_eq!((report.files, report.skipped, report.errors), (2, 3, 2));
+}
@@ -0,0 +1,208 @@
+mod common;
+
+use common::{with_threads, MemoryTree};
+use r_fast_copy::{CopyReport, Copyer, Error, FileSystem, MemoryFs, ZipCompression};
+use std::io::{Cursor, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+use zip::write::SimpleFileOptions;
+
+// `/src` with a symlink, a hard link and a file too big to be read ahead.
+fn zip_fixture() -> (Arc<MemoryFs>, Vec<u8>) {
+    let big = b"0123456789abcdef".repeat(64 * 1024);
+    let fs = MemoryTree::new()
+        .file("/src/a.txt", &b"a".repeat(1000))
+        .file("/src/sub/b.txt", b"bb")
+        .file("/src/sub/big", &big)
+        .symlink("a.txt", "/src/link")
+        .hard_link("/src/a.txt", "/src/sub/hard.txt")
+        .build();
+    (fs, big)
+}
+
+fn to_zip(fs: &Arc<MemoryFs>, archive: &str, compression: ZipCompression, threads: usize) {
+    let builder = Copyer::builder()
+        .add_from("/src")
+        .set_to_zip(archive)
+        .set_zip_compression(compression)
+        .set_file_system(fs.clone())
+        .set_quiet(true);
+    let report = with_threads(builder, threads).build().unwrap().run().unwrap();
+    assert_eq!((report.files, report.dirs, report.errors), (5, 2, 0));
+}
+
+#[test]
+fn to_zip_test() {
+    let (fs, big) = zip_fixture();
+    for threads in [0, 4] {
+        to_zip(&fs, "/out/src.zip", ZipCompression::Zstd, threads);
+        let archive = fs.read_file(Path::new("/out/src.zip")).unwrap();
+        assert!(archive.len() < big.len() / 10);
+
+        // Read by another implementation.
+        let mut zip = zip::ZipArchive::new(Cursor::new(archive)).unwrap();
+        let names: Vec<&str> = zip.file_names().collect();
+        assert_eq!(names.len(), 7);
+        let mut data = vec![];
+        zip.by_name("src/sub/big").unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, big);
+        let a = zip.by_name("src/a.txt").unwrap();
+        assert_eq!(a.compression(), zip::CompressionMethod::Zstd);
+        drop(a);
+        // Hard links are copies.
+        let mut hard = zip.by_name("src/sub/hard.txt").unwrap();
+        let mut data = vec![];
+        hard.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"a".repeat(1000));
+        drop(hard);
+        let mut link = zip.by_name("src/link").unwrap();
+        assert!(link.is_symlink());
+        let mut target = String::new();
+        link.read_to_string(&mut target).unwrap();
+        assert_eq!(target, "a.txt");
+        drop(link);
+        assert!(zip.by_name("src/sub/").unwrap().is_dir());
+    }
+    assert!(!fs.exists(Path::new("/out/.src.zip.rfc-tmp")));
+
+    let both = Copyer::builder()
+        .add_from("/src")
+        .set_to_zip("/out.zip")
+        .set_from_tar("/in.tar")
+        .set_file_system(fs)
+        .build();
+    assert!(matches!(both, Err(Error::Config(_))));
+}
+
+#[test]
+fn from_zip_test() {
+    let (fs, big) = zip_fixture();
+    let modified = UNIX_EPOCH + Duration::from_secs(1_000_000);
+    fs.set_modified(Path::new("/src/a.txt"), modified).unwrap();
+    fs.set_permissions(Path::new("/src/sub/b.txt"), 0o600).unwrap();
+    to_zip(&fs, "/src.zip", ZipCompression::Deflate, 2);
+
+    for threads in [0, 4] {
+        let to = format!("/restored{}", threads);
+        let builder = Copyer::builder()
+            .set_from_zip("/src.zip")
+            .set_to(&to)
+            .set_file_system(fs.clone())
+            .set_quiet(true);
+        let report = with_threads(builder, threads).build().unwrap().run().unwrap();
+        assert_eq!((report.files, report.dirs, report.errors), (5, 2, 0));
+
+        let root = Path::new(&to).join("src");
+        assert_eq!(fs.read_file(&root.join("sub/b.txt")).unwrap(), b"bb");
+        assert_eq!(fs.read_file(&root.join("sub/big")).unwrap(), big);
+        assert_eq!(fs.read_file(&root.join("sub/hard.txt")).unwrap(), b"a".repeat(1000));
+        assert_eq!(fs.read_link(&root.join("link")).unwrap(), Path::new("a.txt"));
+        let a = fs.symlink_metadata(&root.join("a.txt")).unwrap();
+        assert_eq!((a.nlink, a.modified), (1, Some(modified)));
+        let b = fs.symlink_metadata(&root.join("sub/b.txt")).unwrap();
+        assert_eq!(b.mode & 0o777, 0o600);
+    }
+
+    let stdin = Copyer::builder()
+        .set_from_zip("-")
+        .set_to("/restored")
+        .set_file_system(fs)
+        .build();
+    assert!(matches!(stdin, Err(Error::Config(_))));
+}
+
+#[test]
+fn from_zip_foreign_test() {
+    // As another tool would write it, with entries that lead out of the destination.
+    let mut writer = zip::ZipWriter::new(Cursor::new(vec![]));
+    let options = SimpleFileOptions::default();
+    for (name, data) in [
+        ("docs/readme.txt", &b"read me"[..]),
+        ("../evil.txt", b"evil"),
+        ("docs/../../evil.txt", b"evil"),
+        ("empty", b""),
+    ] {
+        writer.start_file(name, options).unwrap();
+        writer.write_all(data).unwrap();
+    }
+    writer.add_directory("docs/more/", options).unwrap();
+    let archive = writer.finish().unwrap().into_inner();
+
+    let fs = MemoryTree::new().file("/in.zip", &archive).build();
+    let report = Copyer::builder()
+        .set_from_zip("/in.zip")
+        .set_to("/out/dest")
+        .set_file_system(fs.clone())
+        .set_threads_number(2)
+        .set_quiet(true)
+        .build()
+        .unwrap()
+        .run()
+        .unwrap();
+    assert_eq!((report.files, report.skipped, report.errors), (2, 2, 0));
+    assert_eq!(fs.read_file(Path::new("/out/dest/docs/readme.txt")).unwrap(), b"read me");
+    assert!(fs.is_dir(Path::new("/out/dest/docs/more")));
+    assert!(!fs.exists(Path::new("/out/evil.txt")));
+
+    // Not a zip archive.
+    let report = Copyer::builder()
+        .set_from_zip("/out/dest/docs/readme.txt")
+        .set_to("/out/other")
+        .set_file_system(fs)
+        .set_quiet(true)
+        .build()
+        .unwrap()
+        .run();
+    assert!(matches!(report, Err(Error::Io(_))));
+}
+
+// Extract `archive` from `tests/fixtures/zip`, made by other tools with `make.sh`, into `/out`.
+fn extract_fixture(archive: &str) -> (Arc<MemoryFs>, CopyReport) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/zip").join(archive);
+    let fs = MemoryTree::new().file("/in.zip", &std::fs::read(path).unwrap()).build();
+    let report = Copyer::builder()
+        .set_from_zip("/in.zip")
+        .set_to("/out")
+        .set_file_system(fs.clone())
+        .set_threads_number(2)
+        .set_quiet(true)
+        .build()
+        .unwrap()
+        .run()
+        .unwrap();
+    (fs, report)
+}
+
+#[test]
+fn from_zip_other_tools_test() {
+    let hello = b"hello zip\n".repeat(200);
+    let modified = UNIX_EPOCH + Duration::from_secs(1_580_702_706);
+    // Plain, ZIP64 and data descriptor variants; Python's leaves out the symlink.
+    for (archive, links) in [
+        ("infozip.zip", 1),
+        ("infozip-zip64.zip", 1),
+        ("libarchive.zip", 1),
+        ("libarchive-zip64.zip", 1),
+        ("python-zip64-dd.zip", 0),
+    ] {
+        let (fs, report) = extract_fixture(archive);
+        assert_eq!((report.files, report.dirs, report.errors), (3 + links, 2, 0), "{}", archive);
+        let tree = Path::new("/out/tree");
+        assert_eq!(fs.read_file(&tree.join("hello.txt")).unwrap(), hello, "{}", archive);
+        assert_eq!(fs.read_file(&tree.join("sub/empty")).unwrap(), b"");
+        assert_eq!(fs.read_file(&tree.join("sub/run.sh")).unwrap(), b"#!/bin/sh\n");
+        let run = fs.symlink_metadata(&tree.join("sub/run.sh")).unwrap();
+        assert_eq!(run.mode & 0o7777, 0o750, "{}", archive);
+        let hello_metadata = fs.symlink_metadata(&tree.join("hello.txt")).unwrap();
+        assert_eq!(hello_metadata.modified, Some(modified), "{}", archive);
+        if links > 0 {
+            assert_eq!(fs.read_link(&tree.join("link")).unwrap(), Path::new("hello.txt"));
+        }
+    }
+
+    // Streamed by Info-ZIP from stdin, as `-`.
+    let (fs, report) = extract_fixture("infozip-stream.zip");
+    assert_eq!((report.files, report.errors), (1, 0));
+    assert_eq!(fs.read_file(Path::new("/out/-")).unwrap(), hello);
+}